@@ -0,0 +1,119 @@
+use crate::error::{Error, Result};
+
+pub const ATTRIBUTES_SIZE: usize = 25;
+
+const HAS_MODE: u8 = 1 << 0;
+const HAS_MTIME: u8 = 1 << 1;
+const HAS_OWNER: u8 = 1 << 2;
+
+/// Metadata of a file that the receiver applies once the file is
+/// complete, sent right after the `Header` when both sides support
+/// `CAP_ATTRIBUTES`. Only the attributes that are set are applied.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Attributes {
+    /// Unix permission bits.
+    pub mode: Option<u32>,
+    /// Modification time, in seconds and nanoseconds since the Unix
+    /// epoch.
+    pub mtime: Option<(i64, u32)>,
+    /// User and group IDs of the owner.
+    pub owner: Option<(u32, u32)>,
+}
+
+impl Attributes {
+    pub fn is_empty(&self) -> bool {
+        self == &Attributes::default()
+    }
+
+    pub fn encode(&self) -> [u8; ATTRIBUTES_SIZE] {
+        let mut buf = [0u8; ATTRIBUTES_SIZE];
+
+        if let Some(mode) = self.mode {
+            buf[0] |= HAS_MODE;
+            buf[1..5].copy_from_slice(&mode.to_be_bytes());
+        }
+        if let Some((secs, nanos)) = self.mtime {
+            buf[0] |= HAS_MTIME;
+            buf[5..13].copy_from_slice(&secs.to_be_bytes());
+            buf[13..17].copy_from_slice(&nanos.to_be_bytes());
+        }
+        if let Some((uid, gid)) = self.owner {
+            buf[0] |= HAS_OWNER;
+            buf[17..21].copy_from_slice(&uid.to_be_bytes());
+            buf[21..25].copy_from_slice(&gid.to_be_bytes());
+        }
+
+        buf
+    }
+
+    pub fn decode(buf: &[u8; ATTRIBUTES_SIZE]) -> Result<Attributes> {
+        let flags = buf[0];
+        if flags & !(HAS_MODE | HAS_MTIME | HAS_OWNER) != 0 {
+            return Err(Error::Invalid(format!(
+                "unknown file attributes: {:#x}",
+                flags
+            )));
+        }
+
+        let u32_at = |start: usize| {
+            u32::from_be_bytes([buf[start], buf[start + 1], buf[start + 2], buf[start + 3]])
+        };
+
+        let mut secs_buf = [0u8; 8];
+        secs_buf.copy_from_slice(&buf[5..13]);
+        let secs = i64::from_be_bytes(secs_buf);
+        let nanos = u32_at(13);
+
+        if flags & HAS_MTIME != 0 && nanos >= 1_000_000_000 {
+            return Err(Error::Invalid(format!(
+                "invalid modification time: {}.{}",
+                secs, nanos
+            )));
+        }
+
+        Ok(Attributes {
+            mode: Some(u32_at(1)).filter(|_| flags & HAS_MODE != 0),
+            mtime: Some((secs, nanos)).filter(|_| flags & HAS_MTIME != 0),
+            owner: Some((u32_at(17), u32_at(21))).filter(|_| flags & HAS_OWNER != 0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attributes_round_trip() {
+        let attributes = vec![
+            Attributes::default(),
+            Attributes {
+                mode: Some(0o640),
+                mtime: Some((-86400, 999_999_999)),
+                owner: Some((1000, 100)),
+            },
+            Attributes {
+                mtime: Some((1_600_000_000, 0)),
+                ..Attributes::default()
+            },
+        ];
+
+        for attributes in attributes {
+            assert_eq!(
+                Attributes::decode(&attributes.encode()).unwrap(),
+                attributes
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_attributes() {
+        let mut buf = [0u8; ATTRIBUTES_SIZE];
+        buf[0] = 1 << 7;
+        assert!(Attributes::decode(&buf).is_err());
+
+        buf[0] = HAS_MTIME;
+        buf[13..17].copy_from_slice(&1_000_000_000u32.to_be_bytes());
+        assert!(Attributes::decode(&buf).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use crate::error::{Error, Result};
+use crate::MAX_CHUNK_SIZE;
+
+pub const CHUNK_HEADER_SIZE: usize = 16;
+pub const CHUNK_CHECKSUM_SIZE: usize = 4;
+
+/// Size of the header of the chunks when a `Compression` was agreed on,
+/// which also carries the size of the compressed data.
+pub const COMPRESSED_CHUNK_HEADER_SIZE: usize = 20;
+
+/// Header that precedes the data of a chunk. The header has a checksum
+/// of its own, so that a corrupted length does not make the receiver
+/// lose track of where the chunks start.
+///
+/// The offset and the length are those of the data in the file, even
+/// when the data is sent compressed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkHeader {
+    pub offset: u64,
+    pub length: u32,
+    /// Size of the data sent when it is compressed.
+    pub compressed_length: Option<u32>,
+}
+
+impl ChunkHeader {
+    pub fn new(offset: u64, length: usize) -> ChunkHeader {
+        ChunkHeader {
+            offset,
+            length: length as u32,
+            compressed_length: None,
+        }
+    }
+
+    pub fn with_compressed_length(mut self, compressed_length: usize) -> ChunkHeader {
+        self.compressed_length = Some(compressed_length as u32);
+        self
+    }
+
+    /// Number of bytes of data that follow the header.
+    pub fn data_size(&self) -> usize {
+        self.compressed_length.unwrap_or(self.length) as usize
+    }
+
+    pub fn encode(&self) -> [u8; CHUNK_HEADER_SIZE] {
+        let mut header = [0u8; CHUNK_HEADER_SIZE];
+        header[..8].copy_from_slice(&self.offset.to_be_bytes());
+        header[8..12].copy_from_slice(&self.length.to_be_bytes());
+        let header_checksum = crc32fast::hash(&header[..12]);
+        header[12..].copy_from_slice(&header_checksum.to_be_bytes());
+        header
+    }
+
+    /// Decodes the header of a chunk of a file of `file_size` bytes. An
+    /// error is returned if the header is corrupted, since the stream
+    /// can not be parsed any further.
+    pub fn decode(header: &[u8; CHUNK_HEADER_SIZE], file_size: u64) -> Result<ChunkHeader> {
+        let mut u64_buf = [0u8; 8];
+        u64_buf.copy_from_slice(&header[..8]);
+        let offset = u64::from_be_bytes(u64_buf);
+        let length = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+        let header_checksum = u32::from_be_bytes([header[12], header[13], header[14], header[15]]);
+
+        if crc32fast::hash(&header[..12]) != header_checksum {
+            return Err(Error::Invalid("corrupted chunk header".to_string()));
+        }
+
+        let chunk = ChunkHeader::new(offset, length as usize);
+        chunk.validate(file_size)?;
+        Ok(chunk)
+    }
+
+    /// Encodes the header for a session that agreed on a `Compression`,
+    /// where a compressed length of zero means that the data is sent as
+    /// it is.
+    pub fn encode_compressed(&self) -> [u8; COMPRESSED_CHUNK_HEADER_SIZE] {
+        let mut header = [0u8; COMPRESSED_CHUNK_HEADER_SIZE];
+        header[..8].copy_from_slice(&self.offset.to_be_bytes());
+        header[8..12].copy_from_slice(&self.length.to_be_bytes());
+        header[12..16].copy_from_slice(&self.compressed_length.unwrap_or(0).to_be_bytes());
+        let header_checksum = crc32fast::hash(&header[..16]);
+        header[16..].copy_from_slice(&header_checksum.to_be_bytes());
+        header
+    }
+
+    /// Decodes a header encoded with `ChunkHeader::encode_compressed`.
+    pub fn decode_compressed(
+        header: &[u8; COMPRESSED_CHUNK_HEADER_SIZE],
+        file_size: u64,
+    ) -> Result<ChunkHeader> {
+        let mut u64_buf = [0u8; 8];
+        u64_buf.copy_from_slice(&header[..8]);
+        let offset = u64::from_be_bytes(u64_buf);
+        let length = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+        let compressed_length =
+            u32::from_be_bytes([header[12], header[13], header[14], header[15]]);
+        let header_checksum = u32::from_be_bytes([header[16], header[17], header[18], header[19]]);
+
+        if crc32fast::hash(&header[..16]) != header_checksum {
+            return Err(Error::Invalid("corrupted chunk header".to_string()));
+        }
+
+        let mut chunk = ChunkHeader::new(offset, length as usize);
+        if compressed_length != 0 {
+            // Data that does not get smaller is sent uncompressed.
+            if compressed_length >= length {
+                return Err(Error::Invalid(format!(
+                    "invalid compressed chunk (length={}, compressed_length={})",
+                    length, compressed_length
+                )));
+            }
+            chunk = chunk.with_compressed_length(compressed_length as usize);
+        }
+        chunk.validate(file_size)?;
+        Ok(chunk)
+    }
+
+    fn validate(&self, file_size: u64) -> Result<()> {
+        let ChunkHeader { offset, length, .. } = *self;
+
+        if length == 0
+            || length as usize > MAX_CHUNK_SIZE
+            || length as u64 > file_size
+            || offset > file_size - length as u64
+        {
+            return Err(Error::Invalid(format!(
+                "invalid chunk (offset={}, length={})",
+                offset, length
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Checksum that follows the data of a chunk.
+pub fn chunk_checksum(data: &[u8]) -> [u8; CHUNK_CHECKSUM_SIZE] {
+    crc32fast::hash(data).to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressed_chunk_header() {
+        let chunk = ChunkHeader::new(4096, 1000).with_compressed_length(300);
+        let header = chunk.encode_compressed();
+        assert_eq!(
+            ChunkHeader::decode_compressed(&header, 5096).unwrap(),
+            chunk
+        );
+        assert_eq!(chunk.data_size(), 300);
+
+        let chunk = ChunkHeader::new(4096, 1000);
+        let header = chunk.encode_compressed();
+        assert_eq!(
+            ChunkHeader::decode_compressed(&header, 5096).unwrap(),
+            chunk
+        );
+        assert_eq!(chunk.data_size(), 1000);
+
+        // Compressed data must be smaller than the data it expands to.
+        let header = ChunkHeader::new(0, 1000)
+            .with_compressed_length(1000)
+            .encode_compressed();
+        assert!(ChunkHeader::decode_compressed(&header, 5096).is_err());
+
+        let mut header = chunk.encode_compressed();
+        header[13] ^= 1;
+        assert!(ChunkHeader::decode_compressed(&header, 5096).is_err());
+    }
+}
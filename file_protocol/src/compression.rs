@@ -0,0 +1,232 @@
+//! Algorithms the data of the chunks can be compressed with. Each chunk
+//! is compressed on its own, into a Zstandard frame, an LZ4 block or a
+//! gzip member.
+
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::error::{Error, Result};
+use crate::hello::{CAP_GZIP, CAP_LZ4, CAP_ZSTD};
+
+/// Compression of the data of the chunks, agreed on in the handshake
+/// through the capability of the algorithm. The uploader offers the
+/// algorithm it was configured with, and the data is sent uncompressed
+/// to receivers that do not support it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Zstd,
+    Lz4,
+    Gzip,
+}
+
+impl Compression {
+    /// All the algorithms, from the most preferred.
+    pub const ALL: [Compression; 3] = [Compression::Zstd, Compression::Lz4, Compression::Gzip];
+
+    pub fn capability(self) -> u32 {
+        match self {
+            Compression::Zstd => CAP_ZSTD,
+            Compression::Lz4 => CAP_LZ4,
+            Compression::Gzip => CAP_GZIP,
+        }
+    }
+
+    /// Returns the most preferred algorithm among the `capabilities`
+    /// agreed on, if any.
+    pub fn negotiated(capabilities: u32) -> Option<Compression> {
+        Compression::ALL
+            .iter()
+            .copied()
+            .find(|compression| capabilities & compression.capability() != 0)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+            Compression::Gzip => "gzip",
+        }
+    }
+
+    /// Appends the compressed `data` to `out`.
+    pub fn compress(self, data: &[u8], out: &mut Vec<u8>) {
+        let result = match self {
+            Compression::Zstd => {
+                zstd::stream::copy_encode(data, &mut *out, zstd::DEFAULT_COMPRESSION_LEVEL)
+            }
+            Compression::Lz4 => {
+                out.extend_from_slice(&lz4_flex::block::compress(data));
+                Ok(())
+            }
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(&mut *out, flate2::Compression::default());
+                encoder.write_all(data).and_then(|()| encoder.finish().map(drop))
+            }
+        };
+
+        result.expect("compressing into memory does not fail");
+    }
+
+    /// Decompresses `data`, failing if it is corrupted or if it expands
+    /// to more than `max_size` bytes.
+    pub fn decompress(self, data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => {
+                zstd::bulk::decompress(data, max_size).map_err(|err| self.corrupted(err))
+            }
+            Compression::Lz4 => {
+                lz4_flex::block::decompress(data, max_size).map_err(|err| self.corrupted(err))
+            }
+            Compression::Gzip => {
+                let mut out = Vec::new();
+                GzDecoder::new(data)
+                    .take(max_size as u64 + 1)
+                    .read_to_end(&mut out)
+                    .map_err(|err| self.corrupted(err))?;
+
+                if out.len() > max_size {
+                    return Err(self.corrupted("expands past the size of the chunk"));
+                }
+
+                Ok(out)
+            }
+        }
+    }
+
+    /// Error for data that does not decompress.
+    fn corrupted(self, err: impl fmt::Display) -> Error {
+        Error::Invalid(format!("corrupted {} data: {}", self, err))
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Compression, String> {
+        Compression::ALL
+            .iter()
+            .copied()
+            .find(|compression| compression.name() == s)
+            .ok_or_else(|| format!("invalid compression algorithm: {}", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Data that compresses well, with both short and long repetitions.
+    fn sample_data() -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..2000u32 {
+            data.extend_from_slice(
+                format!(
+                    "2020-01-01 12:{:02}:{:02} INFO request {} served\n",
+                    i / 60 % 60,
+                    i % 60,
+                    i * 7919 % 1000
+                )
+                .as_bytes(),
+            );
+        }
+        data
+    }
+
+    /// Data that does not compress.
+    fn random_data(size: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let samples = vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"abcd".to_vec(),
+            vec![0u8; 100_000],
+            sample_data(),
+            random_data(70_000),
+        ];
+
+        for compression in &Compression::ALL {
+            for data in &samples {
+                let mut compressed = Vec::new();
+                compression.compress(data, &mut compressed);
+                let decompressed = compression.decompress(&compressed, data.len()).unwrap();
+                assert_eq!(&decompressed, data, "{} round trip", compression);
+            }
+
+            let mut compressed = Vec::new();
+            compression.compress(&sample_data(), &mut compressed);
+            assert!(
+                compressed.len() * 4 < sample_data().len(),
+                "{} ratio",
+                compression
+            );
+        }
+    }
+
+    #[test]
+    fn test_decompress_past_max_size() {
+        let data = sample_data();
+        for compression in &Compression::ALL {
+            let mut compressed = Vec::new();
+            compression.compress(&data, &mut compressed);
+            assert!(compression.decompress(&compressed, data.len() - 1).is_err());
+        }
+    }
+
+    #[test]
+    fn test_decompress_corrupted() {
+        let data = sample_data();
+        for compression in &Compression::ALL {
+            let mut compressed = Vec::new();
+            compression.compress(&data, &mut compressed);
+
+            // Either the corruption is detected or the output differs.
+            for position in (0..compressed.len()).step_by(97) {
+                let mut corrupted = compressed.clone();
+                corrupted[position] ^= 0x55;
+                if let Ok(decompressed) = compression.decompress(&corrupted, data.len()) {
+                    assert_ne!(decompressed, data);
+                }
+            }
+            assert!(compression
+                .decompress(&compressed[..compressed.len() / 2], data.len())
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_negotiated() {
+        assert_eq!(Compression::negotiated(0), None);
+        assert_eq!(
+            Compression::negotiated(CAP_LZ4 | CAP_GZIP),
+            Some(Compression::Lz4)
+        );
+        assert_eq!(
+            Compression::negotiated(CAP_ZSTD | CAP_LZ4 | CAP_GZIP),
+            Some(Compression::Zstd)
+        );
+        assert_eq!("gzip".parse::<Compression>().unwrap(), Compression::Gzip);
+        assert!("brotli".parse::<Compression>().is_err());
+    }
+}
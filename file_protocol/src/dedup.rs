@@ -0,0 +1,299 @@
+use fastcdc::v2020::FastCDC;
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+use crate::MAX_CHUNK_SIZE;
+
+/// Size of the hash the chunks are stored under, a SHA-256.
+pub const CHUNK_HASH_SIZE: usize = 32;
+
+/// Size of an encoded `StoredChunk`.
+pub const STORED_CHUNK_SIZE: usize = 48;
+
+/// Largest number of chunks a `ChunkQuery` can ask about.
+pub const MAX_QUERY_CHUNKS: usize = 1024;
+
+/// Sizes of the content-defined chunks. The largest chunks fit in a
+/// chunk of data, so that a stored chunk is handled like one.
+const MIN_SIZE: usize = 2 * 1024;
+const AVERAGE_SIZE: usize = 8 * 1024;
+const MAX_SIZE: usize = MAX_CHUNK_SIZE;
+
+/// Returns the size of the content-defined chunk at the start of `data`,
+/// as cut by FastCDC, so that a change in the data only moves the
+/// boundaries of the chunks around it.
+fn cut_point(data: &[u8]) -> usize {
+    FastCDC::new(data, MIN_SIZE as u32, AVERAGE_SIZE as u32, MAX_SIZE as u32)
+        .next()
+        .map_or(0, |chunk| chunk.length)
+}
+
+/// Splits data into content-defined chunks as it is read.
+#[derive(Default)]
+pub struct Chunker {
+    pending: Vec<u8>,
+}
+
+impl Chunker {
+    pub fn new() -> Chunker {
+        Chunker::default()
+    }
+
+    /// Adds the data read next.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+    }
+
+    /// Returns the next chunk, or `None` if more data must be read to
+    /// tell where it ends.
+    pub fn next_chunk(&mut self) -> Option<Vec<u8>> {
+        if self.pending.len() < MAX_SIZE {
+            return None;
+        }
+
+        let size = cut_point(&self.pending);
+        Some(self.pending.drain(..size).collect())
+    }
+
+    /// Returns the chunks left once all the data was read.
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        let size = cut_point(&self.pending);
+        Some(self.pending.drain(..size).collect())
+    }
+}
+
+/// Hash that identifies a chunk in the store of the receiver.
+pub fn chunk_hash(data: &[u8]) -> [u8; CHUNK_HASH_SIZE] {
+    let mut hash = [0u8; CHUNK_HASH_SIZE];
+    hash.copy_from_slice(&Sha256::digest(data));
+    hash
+}
+
+/// Asks the receiver which of the chunks with the given hashes are
+/// missing from its store, which it replies to with
+/// `Response::Missing`.
+///
+/// The query is encoded as the number of hashes on four bytes, the
+/// hashes and a checksum of the whole query.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkQuery {
+    pub hashes: Vec<[u8; CHUNK_HASH_SIZE]>,
+}
+
+impl ChunkQuery {
+    /// Size of the part of the query that determines its total size.
+    pub const PREFIX_SIZE: usize = 4;
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf =
+            Vec::with_capacity(ChunkQuery::PREFIX_SIZE + self.hashes.len() * CHUNK_HASH_SIZE + 4);
+        buf.extend_from_slice(&(self.hashes.len() as u32).to_be_bytes());
+        for hash in &self.hashes {
+            buf.extend_from_slice(hash);
+        }
+        let checksum = crc32fast::hash(&buf);
+        buf.extend_from_slice(&checksum.to_be_bytes());
+        buf
+    }
+
+    /// Returns the total size of the query that starts with `prefix`.
+    pub fn size(prefix: &[u8; ChunkQuery::PREFIX_SIZE]) -> Result<usize> {
+        let count = u32::from_be_bytes(*prefix) as usize;
+        if count == 0 || count > MAX_QUERY_CHUNKS {
+            return Err(Error::Invalid(format!(
+                "invalid number of chunks queried: {}",
+                count
+            )));
+        }
+
+        Ok(ChunkQuery::PREFIX_SIZE + count * CHUNK_HASH_SIZE + 4)
+    }
+
+    /// Decodes a whole query, whose size is given by `ChunkQuery::size`.
+    pub fn decode(buf: &[u8]) -> Result<ChunkQuery> {
+        if buf.len() < ChunkQuery::PREFIX_SIZE
+            || buf.len() != ChunkQuery::size(&[buf[0], buf[1], buf[2], buf[3]])?
+        {
+            return Err(Error::Invalid(format!(
+                "truncated chunk query ({} bytes)",
+                buf.len()
+            )));
+        }
+
+        let (query, checksum) = buf.split_at(buf.len() - 4);
+        if crc32fast::hash(query).to_be_bytes() != checksum {
+            return Err(Error::Invalid("corrupted chunk query".to_string()));
+        }
+
+        let hashes = query[ChunkQuery::PREFIX_SIZE..]
+            .chunks(CHUNK_HASH_SIZE)
+            .map(|hash| {
+                let mut buf = [0u8; CHUNK_HASH_SIZE];
+                buf.copy_from_slice(hash);
+                buf
+            })
+            .collect();
+
+        Ok(ChunkQuery { hashes })
+    }
+}
+
+/// Chunk of a deduplicated file whose data is a chunk the receiver
+/// holds in its store, which is stored at the given offset of the file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredChunk {
+    pub offset: u64,
+    pub length: u32,
+    pub hash: [u8; CHUNK_HASH_SIZE],
+}
+
+impl StoredChunk {
+    pub fn new(offset: u64, length: usize, hash: [u8; CHUNK_HASH_SIZE]) -> StoredChunk {
+        StoredChunk {
+            offset,
+            length: length as u32,
+            hash,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; STORED_CHUNK_SIZE] {
+        let mut buf = [0u8; STORED_CHUNK_SIZE];
+        buf[..8].copy_from_slice(&self.offset.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.length.to_be_bytes());
+        buf[12..44].copy_from_slice(&self.hash);
+        let checksum = crc32fast::hash(&buf[..44]);
+        buf[44..].copy_from_slice(&checksum.to_be_bytes());
+        buf
+    }
+
+    /// Decodes the reference to a stored chunk of a file of `file_size`
+    /// bytes. As for chunk headers, an error is returned if the
+    /// reference is corrupted.
+    pub fn decode(buf: &[u8; STORED_CHUNK_SIZE], file_size: u64) -> Result<StoredChunk> {
+        let mut u64_buf = [0u8; 8];
+        u64_buf.copy_from_slice(&buf[..8]);
+        let offset = u64::from_be_bytes(u64_buf);
+        let length = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]);
+        let checksum = u32::from_be_bytes([buf[44], buf[45], buf[46], buf[47]]);
+
+        if crc32fast::hash(&buf[..44]) != checksum {
+            return Err(Error::Invalid("corrupted stored chunk".to_string()));
+        }
+
+        if length == 0
+            || length as usize > MAX_CHUNK_SIZE
+            || offset > file_size
+            || length as u64 > file_size - offset
+        {
+            return Err(Error::Invalid(format!(
+                "invalid stored chunk (offset={}, length={})",
+                offset, length
+            )));
+        }
+
+        let mut hash = [0u8; CHUNK_HASH_SIZE];
+        hash.copy_from_slice(&buf[12..44]);
+
+        Ok(StoredChunk {
+            offset,
+            length,
+            hash,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_data(size: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn split(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::new();
+        let mut chunks = Vec::new();
+
+        for piece in data.chunks(1000) {
+            chunker.feed(piece);
+            while let Some(chunk) = chunker.next_chunk() {
+                chunks.push(chunk);
+            }
+        }
+        while let Some(chunk) = chunker.finish() {
+            chunks.push(chunk);
+        }
+
+        chunks
+    }
+
+    #[test]
+    fn test_content_defined_chunks() {
+        let data = random_data(1_000_000, 0x2545_f491_4f6c_dd1d);
+        let chunks = split(&data);
+
+        assert_eq!(chunks.concat(), data);
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|chunk| chunk.len() >= MIN_SIZE && chunk.len() <= MAX_SIZE));
+        let average = data.len() / chunks.len();
+        assert!(average > AVERAGE_SIZE / 2 && average < AVERAGE_SIZE * 2);
+
+        // Inserting data only changes the chunks around it.
+        let mut modified = data.clone();
+        modified.splice(500_000..500_000, random_data(100, 1));
+        let modified_chunks = split(&modified);
+        let unchanged = modified_chunks
+            .iter()
+            .filter(|chunk| chunks.contains(chunk))
+            .count();
+        assert!(unchanged >= chunks.len() - 3, "{} unchanged", unchanged);
+    }
+
+    #[test]
+    fn test_chunk_query_round_trip() {
+        let query = ChunkQuery {
+            hashes: vec![chunk_hash(b"abc"), chunk_hash(b"def")],
+        };
+        let buf = query.encode();
+
+        assert_eq!(
+            ChunkQuery::size(&[buf[0], buf[1], buf[2], buf[3]]).unwrap(),
+            buf.len()
+        );
+        assert_eq!(ChunkQuery::decode(&buf).unwrap(), query);
+
+        let mut corrupted = buf.clone();
+        corrupted[10] ^= 1;
+        assert!(ChunkQuery::decode(&corrupted).is_err());
+        assert!(ChunkQuery::decode(&buf[..buf.len() - 1]).is_err());
+        assert!(ChunkQuery::size(&0u32.to_be_bytes()).is_err());
+        assert!(ChunkQuery::size(&(MAX_QUERY_CHUNKS as u32 + 1).to_be_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_stored_chunk() {
+        let chunk = StoredChunk::new(4096, 1024, chunk_hash(b"abc"));
+        let buf = chunk.encode();
+        assert_eq!(StoredChunk::decode(&buf, 5120).unwrap(), chunk);
+
+        // The chunk must fit in the file.
+        assert!(StoredChunk::decode(&buf, 5119).is_err());
+
+        let mut buf = chunk.encode();
+        buf[20] ^= 1;
+        assert!(StoredChunk::decode(&buf, 5120).is_err());
+    }
+}
@@ -0,0 +1,301 @@
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+use crate::MAX_CHUNK_SIZE;
+
+/// Size of the strong checksum of a block, a truncated SHA-256.
+pub const STRONG_CHECKSUM_SIZE: usize = 16;
+
+/// Size of an encoded `BlockCopy`.
+pub const BLOCK_COPY_SIZE: usize = 16;
+
+/// Largest number of blocks `Signatures` can describe. Only the start
+/// of larger files is used as the basis of a delta.
+pub const MAX_SIGNATURES: usize = 1 << 20;
+
+const SIGNATURE_SIZE: usize = 4 + STRONG_CHECKSUM_SIZE;
+
+/// Checksum of a block that can be updated as the block slides over the
+/// data one byte at a time, as in rsync.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RollingChecksum {
+    a: u32,
+    b: u32,
+    length: u32,
+}
+
+impl RollingChecksum {
+    pub fn new(block: &[u8]) -> RollingChecksum {
+        let length = block.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+
+        for (i, &byte) in block.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((length - i as u32).wrapping_mul(byte as u32));
+        }
+
+        RollingChecksum { a, b, length }
+    }
+
+    /// Slides the block by one byte, dropping `removed` from its start
+    /// and appending `added` to its end.
+    pub fn roll(&mut self, removed: u8, added: u8) {
+        self.a = self
+            .a
+            .wrapping_sub(removed as u32)
+            .wrapping_add(added as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.length.wrapping_mul(removed as u32))
+            .wrapping_add(self.a);
+    }
+
+    pub fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Checksum that confirms a match of the rolling checksum of a block.
+pub fn strong_checksum(block: &[u8]) -> [u8; STRONG_CHECKSUM_SIZE] {
+    let mut checksum = [0u8; STRONG_CHECKSUM_SIZE];
+    checksum.copy_from_slice(&Sha256::digest(block)[..STRONG_CHECKSUM_SIZE]);
+    checksum
+}
+
+/// Checksums of a block of the copy of a file the receiver holds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockSignature {
+    pub rolling: u32,
+    pub strong: [u8; STRONG_CHECKSUM_SIZE],
+}
+
+impl BlockSignature {
+    pub fn new(block: &[u8]) -> BlockSignature {
+        BlockSignature {
+            rolling: RollingChecksum::new(block).value(),
+            strong: strong_checksum(block),
+        }
+    }
+}
+
+/// Signatures of the whole blocks of the copy of a file the receiver
+/// already holds, which the uploader looks for in the file to send only
+/// the data the receiver lacks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signatures {
+    pub block_size: u32,
+    pub blocks: Vec<BlockSignature>,
+}
+
+impl Signatures {
+    /// Size of the part of the signatures that determines their total
+    /// size.
+    const PREFIX_SIZE: usize = 8;
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.block_size.to_be_bytes());
+        buf.extend_from_slice(&(self.blocks.len() as u32).to_be_bytes());
+        for block in &self.blocks {
+            buf.extend_from_slice(&block.rolling.to_be_bytes());
+            buf.extend_from_slice(&block.strong);
+        }
+    }
+
+    /// Decodes the signatures at the start of `data`, returning them
+    /// along with their size, or `None` if they were only partially
+    /// received.
+    pub fn decode(data: &[u8]) -> Result<Option<(Signatures, usize)>> {
+        if data.len() < Signatures::PREFIX_SIZE {
+            return Ok(None);
+        }
+
+        let block_size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let count = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+
+        if block_size == 0 || block_size as usize > MAX_CHUNK_SIZE || count > MAX_SIGNATURES {
+            return Err(Error::Invalid(format!(
+                "invalid signatures (block_size={}, count={})",
+                block_size, count
+            )));
+        }
+
+        let size = Signatures::PREFIX_SIZE + count * SIGNATURE_SIZE;
+        if data.len() < size {
+            return Ok(None);
+        }
+
+        let blocks = data[Signatures::PREFIX_SIZE..size]
+            .chunks(SIGNATURE_SIZE)
+            .map(|signature| {
+                let mut strong = [0u8; STRONG_CHECKSUM_SIZE];
+                strong.copy_from_slice(&signature[4..]);
+                BlockSignature {
+                    rolling: u32::from_be_bytes([
+                        signature[0],
+                        signature[1],
+                        signature[2],
+                        signature[3],
+                    ]),
+                    strong,
+                }
+            })
+            .collect();
+
+        Ok(Some((Signatures { block_size, blocks }, size)))
+    }
+}
+
+/// Kind of the chunks of a file sent as a delta or deduplicated, sent
+/// before each of them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChunkKind {
+    /// A `ChunkHeader` followed by the data of the chunk.
+    Data,
+    /// A `BlockCopy`.
+    Copy,
+    /// A `ChunkQuery`, which is not a chunk of the file.
+    Query,
+    /// A `StoredChunk`.
+    Stored,
+}
+
+impl ChunkKind {
+    pub fn encode(self) -> u8 {
+        match self {
+            ChunkKind::Data => 0,
+            ChunkKind::Copy => 1,
+            ChunkKind::Query => 2,
+            ChunkKind::Stored => 3,
+        }
+    }
+
+    pub fn decode(byte: u8) -> Result<ChunkKind> {
+        match byte {
+            0 => Ok(ChunkKind::Data),
+            1 => Ok(ChunkKind::Copy),
+            2 => Ok(ChunkKind::Query),
+            3 => Ok(ChunkKind::Stored),
+            _ => Err(Error::Invalid(format!("unknown chunk kind: {}", byte))),
+        }
+    }
+}
+
+/// Chunk of a delta whose data is a block of the copy of the file the
+/// receiver holds, which is stored at the given offset of the file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockCopy {
+    pub offset: u64,
+    pub block: u32,
+}
+
+impl BlockCopy {
+    pub fn new(offset: u64, block: u32) -> BlockCopy {
+        BlockCopy { offset, block }
+    }
+
+    pub fn encode(&self) -> [u8; BLOCK_COPY_SIZE] {
+        let mut buf = [0u8; BLOCK_COPY_SIZE];
+        buf[..8].copy_from_slice(&self.offset.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.block.to_be_bytes());
+        let checksum = crc32fast::hash(&buf[..12]);
+        buf[12..].copy_from_slice(&checksum.to_be_bytes());
+        buf
+    }
+
+    /// Decodes the copy of a block of `block_size` bytes into a file of
+    /// `file_size` bytes. As for chunk headers, an error is returned if
+    /// the copy is corrupted.
+    pub fn decode(
+        buf: &[u8; BLOCK_COPY_SIZE],
+        block_size: usize,
+        file_size: u64,
+    ) -> Result<BlockCopy> {
+        let mut u64_buf = [0u8; 8];
+        u64_buf.copy_from_slice(&buf[..8]);
+        let offset = u64::from_be_bytes(u64_buf);
+        let block = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]);
+        let checksum = u32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]);
+
+        if crc32fast::hash(&buf[..12]) != checksum {
+            return Err(Error::Invalid("corrupted block copy".to_string()));
+        }
+
+        if block_size as u64 > file_size || offset > file_size - block_size as u64 {
+            return Err(Error::Invalid(format!(
+                "invalid block copy (offset={}, block={})",
+                offset, block
+            )));
+        }
+
+        Ok(BlockCopy { offset, block })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rolling_checksum() {
+        let data: Vec<u8> = (0..300u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let block_size = 64;
+
+        let mut checksum = RollingChecksum::new(&data[..block_size]);
+        for start in 1..=data.len() - block_size {
+            checksum.roll(data[start - 1], data[start + block_size - 1]);
+            assert_eq!(
+                checksum.value(),
+                RollingChecksum::new(&data[start..start + block_size]).value()
+            );
+        }
+    }
+
+    #[test]
+    fn test_signatures_round_trip() {
+        let signatures = Signatures {
+            block_size: 1024,
+            blocks: vec![BlockSignature::new(b"abc"), BlockSignature::new(b"def")],
+        };
+        let mut buf = Vec::new();
+        signatures.encode(&mut buf);
+
+        assert_eq!(Signatures::decode(&buf[..buf.len() - 1]).unwrap(), None);
+        assert_eq!(
+            Signatures::decode(&buf).unwrap(),
+            Some((signatures, buf.len()))
+        );
+
+        let mut buf = Vec::new();
+        Signatures {
+            block_size: MAX_CHUNK_SIZE as u32 + 1,
+            blocks: Vec::new(),
+        }
+        .encode(&mut buf);
+        assert!(Signatures::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn test_block_copy() {
+        let copy = BlockCopy::new(4096, 3);
+        let buf = copy.encode();
+        assert_eq!(BlockCopy::decode(&buf, 1024, 5120).unwrap(), copy);
+
+        // The block must fit in the file.
+        assert!(BlockCopy::decode(&buf, 1024, 5119).is_err());
+
+        let mut buf = copy.encode();
+        buf[9] ^= 1;
+        assert!(BlockCopy::decode(&buf, 1024, 5120).is_err());
+
+        for kind in &[
+            ChunkKind::Data,
+            ChunkKind::Copy,
+            ChunkKind::Query,
+            ChunkKind::Stored,
+        ] {
+            assert_eq!(ChunkKind::decode(kind.encode()).unwrap(), *kind);
+        }
+        assert!(ChunkKind::decode(4).is_err());
+    }
+}
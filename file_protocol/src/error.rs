@@ -0,0 +1,24 @@
+use std::error;
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The message does not follow the protocol.
+    Invalid(String),
+    /// The peer does not support a common version of the protocol or a
+    /// required capability.
+    Incompatible(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Invalid(msg) => write!(f, "invalid message: {}", msg),
+            Error::Incompatible(msg) => write!(f, "incompatible protocol: {}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {}
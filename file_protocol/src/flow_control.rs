@@ -0,0 +1,149 @@
+use std::cmp;
+
+use crate::error::{Error, Result};
+use crate::MAX_CHUNK_SIZE;
+
+pub const FLOW_CONTROL_SIZE: usize = 8;
+
+/// Number of bytes the uploader sends without them being acknowledged,
+/// unless configured otherwise.
+pub const DEFAULT_WINDOW: u32 = 8 * 1024 * 1024;
+
+/// Number of bytes after which the receiver acknowledges the data it
+/// received, unless agreed otherwise.
+pub const DEFAULT_ACK_INTERVAL: u32 = 1024 * 1024;
+
+/// Smallest interval between acknowledgements, so that there is at most
+/// one for each chunk.
+pub const MIN_ACK_INTERVAL: u32 = MAX_CHUNK_SIZE as u32;
+
+/// Limits on the data in flight, sent by the uploader after the `Hello`
+/// when both sides support `CAP_WINDOW`. The uploader never has more
+/// than `window` bytes sent but not acknowledged, and asks to be
+/// acknowledged at least every `ack_interval` bytes, which the receiver
+/// replies to with the interval it agrees on. The window holds at least
+/// two intervals, so that the uploader never waits for an
+/// acknowledgement the receiver is not about to send.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlowControl {
+    pub window: u32,
+    pub ack_interval: u32,
+}
+
+impl Default for FlowControl {
+    fn default() -> FlowControl {
+        FlowControl::new(DEFAULT_WINDOW, DEFAULT_ACK_INTERVAL)
+    }
+}
+
+impl FlowControl {
+    /// Flow control with the given limits, raised as needed to be valid.
+    pub fn new(window: u32, ack_interval: u32) -> FlowControl {
+        let ack_interval = ack_interval.clamp(MIN_ACK_INTERVAL, u32::MAX / 2);
+        FlowControl {
+            window: cmp::max(window, 2 * ack_interval),
+            ack_interval,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FLOW_CONTROL_SIZE] {
+        let mut buf = [0u8; FLOW_CONTROL_SIZE];
+        buf[..4].copy_from_slice(&self.window.to_be_bytes());
+        buf[4..].copy_from_slice(&self.ack_interval.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8; FLOW_CONTROL_SIZE]) -> Result<FlowControl> {
+        let window = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let ack_interval = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        let flow_control = FlowControl {
+            window,
+            ack_interval,
+        };
+
+        if FlowControl::new(window, ack_interval) != flow_control {
+            return Err(Error::Invalid(format!(
+                "invalid flow control (window={}, ack_interval={})",
+                window, ack_interval
+            )));
+        }
+
+        Ok(flow_control)
+    }
+
+    /// Agrees on the flow control of the session, acknowledging the data
+    /// at least every `max_ack_interval` bytes even if the uploader asked
+    /// for less frequent acknowledgements.
+    pub fn agree(&self, max_ack_interval: u32) -> FlowControl {
+        FlowControl {
+            window: self.window,
+            ack_interval: cmp::min(
+                self.ack_interval,
+                cmp::max(max_ack_interval, MIN_ACK_INTERVAL),
+            ),
+        }
+    }
+
+    /// Validates the interval between acknowledgements the receiver
+    /// agreed on in reply, returning the flow control of the session.
+    pub fn validate_agreement(&self, ack_interval: u32) -> Result<FlowControl> {
+        if ack_interval < MIN_ACK_INTERVAL || ack_interval > self.ack_interval {
+            return Err(Error::Invalid(format!(
+                "invalid interval between acknowledgements: {}",
+                ack_interval
+            )));
+        }
+
+        Ok(FlowControl {
+            window: self.window,
+            ack_interval,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flow_control_round_trip() {
+        let flow_control = FlowControl::default();
+        assert_eq!(
+            FlowControl::decode(&flow_control.encode()).unwrap(),
+            flow_control
+        );
+
+        // The window must hold two intervals.
+        let invalid = FlowControl {
+            window: DEFAULT_ACK_INTERVAL,
+            ack_interval: DEFAULT_ACK_INTERVAL,
+        };
+        assert!(FlowControl::decode(&invalid.encode()).is_err());
+        assert_eq!(
+            FlowControl::new(0, 0),
+            FlowControl::new(2 * MIN_ACK_INTERVAL, MIN_ACK_INTERVAL)
+        );
+    }
+
+    #[test]
+    fn test_agree_on_ack_interval() {
+        let flow_control = FlowControl::new(DEFAULT_WINDOW, DEFAULT_ACK_INTERVAL);
+
+        let agreed = flow_control.agree(DEFAULT_ACK_INTERVAL / 4);
+        assert_eq!(agreed.ack_interval, DEFAULT_ACK_INTERVAL / 4);
+        assert_eq!(agreed.window, DEFAULT_WINDOW);
+        assert_eq!(flow_control.agree(u32::MAX), flow_control);
+        assert_eq!(flow_control.agree(0).ack_interval, MIN_ACK_INTERVAL);
+
+        assert_eq!(
+            flow_control
+                .validate_agreement(agreed.ack_interval)
+                .unwrap(),
+            agreed
+        );
+        assert!(flow_control
+            .validate_agreement(DEFAULT_ACK_INTERVAL + 1)
+            .is_err());
+        assert!(flow_control.validate_agreement(0).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use crate::error::{Error, Result};
+
+/// Largest file name a header can carry, in bytes.
+pub const MAX_FILE_NAME_SIZE: usize = 4096;
+
+/// Name and size of the file being uploaded, sent after the hello. The
+/// name is made of raw bytes, as file names on Unix need not be valid
+/// UTF-8.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub file_name: Vec<u8>,
+    pub file_size: u64,
+}
+
+impl Header {
+    /// Size of the part of the header that determines its total size.
+    pub const PREFIX_SIZE: usize = 2;
+
+    pub fn new(file_name: impl Into<Vec<u8>>, file_size: u64) -> Header {
+        Header {
+            file_name: file_name.into(),
+            file_size,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        check_file_name_size(self.file_name.len())?;
+
+        let mut buf = Vec::with_capacity(Header::PREFIX_SIZE + self.file_name.len() + 8);
+        buf.extend_from_slice(&(self.file_name.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&self.file_name);
+        buf.extend_from_slice(&self.file_size.to_be_bytes());
+        Ok(buf)
+    }
+
+    /// Returns the total size of the header that starts with `prefix`.
+    pub fn size(prefix: &[u8; Header::PREFIX_SIZE]) -> Result<usize> {
+        let file_name_size = u16::from_be_bytes(*prefix) as usize;
+        check_file_name_size(file_name_size)?;
+
+        Ok(Header::PREFIX_SIZE + file_name_size + 8)
+    }
+
+    /// Decodes a whole header, whose size is given by `Header::size`.
+    pub fn decode(buf: &[u8]) -> Result<Header> {
+        if buf.len() < Header::PREFIX_SIZE || buf.len() != Header::size(&[buf[0], buf[1]])? {
+            return Err(Error::Invalid(format!(
+                "truncated header ({} bytes)",
+                buf.len()
+            )));
+        }
+
+        let name_end = buf.len() - 8;
+        let mut size_buf = [0u8; 8];
+        size_buf.copy_from_slice(&buf[name_end..]);
+
+        Ok(Header {
+            file_name: buf[Header::PREFIX_SIZE..name_end].to_vec(),
+            file_size: u64::from_be_bytes(size_buf),
+        })
+    }
+}
+
+/// Kind of the file a header is for, sent right after the header when
+/// both sides support `CAP_LINKS`. The data of a link is its target.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileKind {
+    File,
+    Link,
+}
+
+impl FileKind {
+    pub fn encode(self) -> u8 {
+        match self {
+            FileKind::File => 0,
+            FileKind::Link => 1,
+        }
+    }
+
+    pub fn decode(byte: u8) -> Result<FileKind> {
+        match byte {
+            0 => Ok(FileKind::File),
+            1 => Ok(FileKind::Link),
+            _ => Err(Error::Invalid(format!("unknown file kind: {}", byte))),
+        }
+    }
+}
+
+fn check_file_name_size(file_name_size: usize) -> Result<()> {
+    if file_name_size > MAX_FILE_NAME_SIZE {
+        return Err(Error::Invalid(format!(
+            "file name too long: {} bytes (the limit is {})",
+            file_name_size, MAX_FILE_NAME_SIZE
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_round_trip() {
+        let header = Header::new(vec![b'a', 0xff, 0xfe, b'/', 0x80], 1 << 33);
+        let buf = header.encode().unwrap();
+
+        let size = Header::size(&[buf[0], buf[1]]).unwrap();
+        assert_eq!(size, buf.len());
+        assert_eq!(Header::decode(&buf).unwrap(), header);
+    }
+
+    #[test]
+    fn test_longest_file_name() {
+        let header = Header::new(vec![b'a'; MAX_FILE_NAME_SIZE], 0);
+        assert_eq!(Header::decode(&header.encode().unwrap()).unwrap(), header);
+    }
+
+    #[test]
+    fn test_file_kind() {
+        for kind in &[FileKind::File, FileKind::Link] {
+            assert_eq!(FileKind::decode(kind.encode()).unwrap(), *kind);
+        }
+        assert!(FileKind::decode(2).is_err());
+    }
+
+    #[test]
+    fn test_file_name_too_long() {
+        assert!(Header::new(vec![b'a'; MAX_FILE_NAME_SIZE + 1], 0)
+            .encode()
+            .is_err());
+        assert!(Header::size(&(MAX_FILE_NAME_SIZE as u16 + 1).to_be_bytes()).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use std::cmp;
+
+use crate::error::{Error, Result};
+
+pub const HELLO_SIZE: usize = 12;
+
+/// Bytes the hello starts with, so that the receiver can tell uploaders
+/// apart from other clients.
+const MAGIC: &[u8; 4] = b"FUPL";
+
+/// Versions of the protocol implemented by this crate.
+pub const MIN_VERSION: u16 = 2;
+pub const MAX_VERSION: u16 = 2;
+
+/// The uploader can answer the challenge of a receiver that requires a
+/// shared secret.
+pub const CAP_AUTH: u32 = 1 << 0;
+
+/// The uploader can send several files over a session, named after their
+/// path relative to the directory being uploaded.
+pub const CAP_MULTI_FILE: u32 = 1 << 1;
+
+/// The uploader sends the `Attributes` of each file after its header.
+pub const CAP_ATTRIBUTES: u32 = 1 << 2;
+
+/// The uploader sends the `FileKind` of each file after its header, so
+/// that symbolic links are recreated as links.
+pub const CAP_LINKS: u32 = 1 << 3;
+
+/// The uploader sends the `ExtendedAttributes` of each file after its
+/// `Attributes`.
+pub const CAP_XATTRS: u32 = 1 << 4;
+
+/// The chunks may be compressed with LZ4.
+pub const CAP_LZ4: u32 = 1 << 5;
+
+/// The chunks may be compressed with gzip.
+pub const CAP_GZIP: u32 = 1 << 6;
+
+/// The chunks may be compressed with Zstandard.
+pub const CAP_ZSTD: u32 = 1 << 7;
+
+/// The files the receiver already holds a copy of may be sent as a
+/// delta against that copy.
+pub const CAP_DELTA: u32 = 1 << 8;
+
+/// The files may be deduplicated against the chunks the receiver holds
+/// in its store.
+pub const CAP_DEDUP: u32 = 1 << 9;
+
+/// Large files may be split into ranges uploaded over several
+/// connections in parallel.
+pub const CAP_STRIPES: u32 = 1 << 10;
+
+/// The uploader sends its `FlowControl` after the hello, and bounds the
+/// data it sends without being acknowledged.
+pub const CAP_WINDOW: u32 = 1 << 11;
+
+const CAPABILITY_NAMES: &[(u32, &str)] = &[
+    (CAP_AUTH, "auth"),
+    (CAP_MULTI_FILE, "multi-file"),
+    (CAP_ATTRIBUTES, "attributes"),
+    (CAP_LINKS, "links"),
+    (CAP_XATTRS, "xattrs"),
+    (CAP_LZ4, "lz4"),
+    (CAP_GZIP, "gzip"),
+    (CAP_ZSTD, "zstd"),
+    (CAP_DELTA, "delta"),
+    (CAP_DEDUP, "dedup"),
+    (CAP_STRIPES, "stripes"),
+    (CAP_WINDOW, "window"),
+];
+
+/// First message sent by the uploader, advertising the versions of the
+/// protocol and the capabilities it supports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hello {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub capabilities: u32,
+}
+
+impl Hello {
+    /// Hello advertising the versions implemented by this crate.
+    pub fn new(capabilities: u32) -> Hello {
+        Hello {
+            min_version: MIN_VERSION,
+            max_version: MAX_VERSION,
+            capabilities,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; HELLO_SIZE] {
+        let mut buf = [0u8; HELLO_SIZE];
+        buf[..4].copy_from_slice(MAGIC);
+        buf[4..6].copy_from_slice(&self.min_version.to_be_bytes());
+        buf[6..8].copy_from_slice(&self.max_version.to_be_bytes());
+        buf[8..].copy_from_slice(&self.capabilities.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8; HELLO_SIZE]) -> Result<Hello> {
+        if &buf[..4] != MAGIC {
+            return Err(Error::Incompatible(
+                "not a file upload client (invalid magic bytes)".to_string(),
+            ));
+        }
+
+        Ok(Hello {
+            min_version: u16::from_be_bytes([buf[4], buf[5]]),
+            max_version: u16::from_be_bytes([buf[6], buf[7]]),
+            capabilities: u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]),
+        })
+    }
+
+    /// Agrees on the highest version of the protocol supported by both
+    /// sides and on the capabilities that both sides support, failing
+    /// if the uploader lacks some of the `required` capabilities.
+    pub fn negotiate(&self, supported: u32, required: u32) -> Result<(u16, u32)> {
+        let version = cmp::min(self.max_version, MAX_VERSION);
+
+        if version < self.min_version || version < MIN_VERSION {
+            return Err(Error::Incompatible(format!(
+                "no common protocol version (uploader supports {}-{}, receiver supports {}-{})",
+                self.min_version, self.max_version, MIN_VERSION, MAX_VERSION
+            )));
+        }
+
+        let missing = required & !self.capabilities;
+        if missing != 0 {
+            return Err(Error::Incompatible(format!(
+                "the uploader does not support: {}",
+                capability_names(missing)
+            )));
+        }
+
+        Ok((version, self.capabilities & supported))
+    }
+
+    /// Validates the version and the capabilities the receiver agreed on
+    /// in reply to this hello, returning the capabilities.
+    pub fn validate_agreement(&self, version: u16, capabilities: u32) -> Result<u32> {
+        if version < self.min_version || version > self.max_version {
+            return Err(Error::Invalid(format!(
+                "unsupported protocol version: {}",
+                version
+            )));
+        }
+
+        let not_offered = capabilities & !self.capabilities;
+        if not_offered != 0 {
+            return Err(Error::Invalid(format!(
+                "capabilities not offered were agreed on: {:#x}",
+                not_offered
+            )));
+        }
+
+        Ok(capabilities)
+    }
+}
+
+/// Returns the names of the capabilities in `capabilities`.
+pub fn capability_names(capabilities: u32) -> String {
+    CAPABILITY_NAMES
+        .iter()
+        .filter(|(capability, _)| capabilities & capability != 0)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,103 @@
+//! Messages exchanged between the file uploader and the file receiver.
+//!
+//! A session starts with the uploader sending a `Hello`, to which the
+//! receiver replies with `Response::Hello` or `Response::Error`. When
+//! both sides support `CAP_WINDOW`, the uploader then sends its
+//! `FlowControl`, which the receiver replies to with
+//! `Response::AckInterval`. The uploader then sends a `Header`,
+//! followed by the `FileKind` of the file when both sides support
+//! `CAP_LINKS`, its `Attributes` when they support `CAP_ATTRIBUTES` and
+//! its `ExtendedAttributes` when they support `CAP_XATTRS`. It then
+//! answers the `Response::Challenge` of receivers that require a shared
+//! secret with the HMAC-SHA256 of the nonce, and waits for
+//! `Response::Accept`. The file is then sent as a sequence of chunks,
+//! each made of a `ChunkHeader`, the data and its `chunk_checksum`, and
+//! finally the SHA-256 digest of the whole file, which the receiver
+//! replies to with `Response::Verification`.
+//!
+//! When both sides support `CAP_MULTI_FILE`, the uploader may then send
+//! the `Header` of another file, for which the challenge is skipped, or
+//! end the session by closing the connection.
+//!
+//! When both sides support the capability of a `Compression`, each chunk
+//! starts with a header encoded with `ChunkHeader::encode_compressed`
+//! instead, and its data may be compressed. The offsets and lengths of
+//! the chunks and the offsets the receiver replies with remain those of
+//! the uncompressed file.
+//!
+//! When both sides support `CAP_DELTA` and the receiver already holds a
+//! copy of the file, it replies to the header with
+//! `Response::Signatures` before accepting the file. Each chunk of the
+//! file then starts with its `ChunkKind`, and is either a chunk of data
+//! or a `BlockCopy` of a block of the copy with a matching signature.
+//!
+//! When both sides support `CAP_DEDUP` and the file is not sent as a
+//! delta, the uploader splits the file into content-defined chunks and
+//! sends each chunk prefixed with its `ChunkKind` as well. Before the
+//! chunks, it sends a `ChunkQuery` with their hashes, which the
+//! receiver replies to with `Response::Missing`. The chunks held in the
+//! store of the receiver are then sent as a `StoredChunk` and the others
+//! as chunks of data.
+//!
+//! When both sides support `CAP_STRIPES`, the `Stripe` of the file is
+//! sent last among the messages that follow its header, and large files
+//! are split into ranges that are uploaded over several sessions in
+//! parallel. The offsets remain those of the whole file, while the
+//! digest sent after the last chunk of a range covers only the range.
+//! The receiver verifies each range on its own, and stores the file once
+//! all of its ranges are verified.
+
+mod attributes;
+mod chunk;
+mod compression;
+mod dedup;
+mod delta;
+mod error;
+mod flow_control;
+mod header;
+mod hello;
+mod response;
+mod secret;
+mod stripe;
+mod xattrs;
+
+pub use crate::attributes::{Attributes, ATTRIBUTES_SIZE};
+pub use crate::chunk::{
+    chunk_checksum, ChunkHeader, CHUNK_CHECKSUM_SIZE, CHUNK_HEADER_SIZE,
+    COMPRESSED_CHUNK_HEADER_SIZE,
+};
+pub use crate::compression::Compression;
+pub use crate::dedup::{
+    chunk_hash, ChunkQuery, Chunker, StoredChunk, CHUNK_HASH_SIZE, MAX_QUERY_CHUNKS,
+    STORED_CHUNK_SIZE,
+};
+pub use crate::delta::{
+    strong_checksum, BlockCopy, BlockSignature, ChunkKind, RollingChecksum, Signatures,
+    BLOCK_COPY_SIZE, MAX_SIGNATURES, STRONG_CHECKSUM_SIZE,
+};
+pub use crate::error::{Error, Result};
+pub use crate::flow_control::{
+    FlowControl, DEFAULT_ACK_INTERVAL, DEFAULT_WINDOW, FLOW_CONTROL_SIZE, MIN_ACK_INTERVAL,
+};
+pub use crate::header::{FileKind, Header, MAX_FILE_NAME_SIZE};
+pub use crate::hello::{
+    capability_names, Hello, CAP_ATTRIBUTES, CAP_AUTH, CAP_DEDUP, CAP_DELTA, CAP_GZIP, CAP_LINKS,
+    CAP_LZ4, CAP_MULTI_FILE, CAP_STRIPES, CAP_WINDOW, CAP_XATTRS, CAP_ZSTD, HELLO_SIZE,
+    MAX_VERSION, MIN_VERSION,
+};
+pub use crate::response::{Response, ResponseDecoder, VERIFICATION_FAILED, VERIFICATION_OK};
+pub use crate::secret::{read_secret, MIN_SECRET_SIZE, SECRET_VAR};
+pub use crate::stripe::{Stripe, MAX_STRIPES, STRIPE_SIZE};
+pub use crate::xattrs::{ExtendedAttributes, MAX_XATTRS_SIZE};
+
+/// Size of the nonce sent in `Response::Challenge`.
+pub const NONCE_SIZE: usize = 32;
+
+/// Size of the HMAC-SHA256 the uploader answers a challenge with.
+pub const MAC_SIZE: usize = 32;
+
+/// Size of the SHA-256 digest sent after the last chunk.
+pub const DIGEST_SIZE: usize = 32;
+
+/// Largest amount of data a chunk can carry.
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024;
@@ -0,0 +1,258 @@
+use std::cmp;
+
+use crate::dedup::MAX_QUERY_CHUNKS;
+use crate::delta::Signatures;
+use crate::error::{Error, Result};
+use crate::NONCE_SIZE;
+
+const ACK: u8 = 0;
+const NACK: u8 = 1;
+const VERIFICATION: u8 = 2;
+const ACCEPT: u8 = 3;
+const CHALLENGE: u8 = 4;
+const ERROR: u8 = 5;
+const HELLO: u8 = 6;
+const SIGNATURES: u8 = 7;
+const MISSING: u8 = 8;
+const ACK_INTERVAL: u8 = 9;
+
+pub const VERIFICATION_OK: u8 = 0;
+pub const VERIFICATION_FAILED: u8 = 1;
+
+/// Messages sent by the receiver.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    /// All the data up to the given offset was stored.
+    Ack(u64),
+    /// The chunk at the given offset and with the given length was
+    /// corrupted and must be sent again.
+    Nack(u64, u32),
+    /// Result of comparing the digest of the file received with the one
+    /// of the original.
+    Verification(u8),
+    /// The upload is accepted and must resume from the given offset.
+    Accept(u64),
+    /// Nonce the uploader must sign with the shared secret.
+    Challenge([u8; NONCE_SIZE]),
+    /// The upload is rejected for the given reason.
+    Error(String),
+    /// Version of the protocol and capabilities agreed on.
+    Hello(u16, u32),
+    /// Signatures of the copy of the file the receiver already holds,
+    /// which the file is sent as a delta against.
+    Signatures(Signatures),
+    /// Indexes of the chunks of a `ChunkQuery` that are missing from the
+    /// store of the receiver.
+    Missing(Vec<u32>),
+    /// Number of bytes after which the data received is acknowledged,
+    /// as agreed on in reply to the `FlowControl` of the uploader.
+    AckInterval(u32),
+}
+
+impl Response {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        match *self {
+            Response::Ack(offset) => {
+                buf.push(ACK);
+                buf.extend_from_slice(&offset.to_be_bytes());
+            }
+            Response::Nack(offset, length) => {
+                buf.push(NACK);
+                buf.extend_from_slice(&offset.to_be_bytes());
+                buf.extend_from_slice(&length.to_be_bytes());
+            }
+            Response::Verification(result) => {
+                buf.push(VERIFICATION);
+                buf.push(result);
+            }
+            Response::Accept(offset) => {
+                buf.push(ACCEPT);
+                buf.extend_from_slice(&offset.to_be_bytes());
+            }
+            Response::Challenge(nonce) => {
+                buf.push(CHALLENGE);
+                buf.extend_from_slice(&nonce);
+            }
+            Response::Error(ref message) => {
+                let message = &message.as_bytes()[..cmp::min(message.len(), u16::MAX as usize)];
+                buf.push(ERROR);
+                buf.extend_from_slice(&(message.len() as u16).to_be_bytes());
+                buf.extend_from_slice(message);
+            }
+            Response::Hello(version, capabilities) => {
+                buf.push(HELLO);
+                buf.extend_from_slice(&version.to_be_bytes());
+                buf.extend_from_slice(&capabilities.to_be_bytes());
+            }
+            Response::Signatures(ref signatures) => {
+                buf.push(SIGNATURES);
+                signatures.encode(&mut buf);
+            }
+            Response::Missing(ref indexes) => {
+                buf.push(MISSING);
+                buf.extend_from_slice(&(indexes.len() as u32).to_be_bytes());
+                for index in indexes {
+                    buf.extend_from_slice(&index.to_be_bytes());
+                }
+            }
+            Response::AckInterval(ack_interval) => {
+                buf.push(ACK_INTERVAL);
+                buf.extend_from_slice(&ack_interval.to_be_bytes());
+            }
+        }
+
+        buf
+    }
+
+    /// Decodes the response at the start of `data`, returning it along
+    /// with its size, or `None` if it was only partially received.
+    pub fn decode(data: &[u8]) -> Result<Option<(Response, usize)>> {
+        let tag = match data.first() {
+            Some(&tag) => tag,
+            None => return Ok(None),
+        };
+
+        let decoded = match tag {
+            ACK if data.len() >= 9 => (Response::Ack(read_u64(&data[1..])), 9),
+            NACK if data.len() >= 13 => (
+                Response::Nack(read_u64(&data[1..]), read_u32(&data[9..])),
+                13,
+            ),
+            VERIFICATION if data.len() >= 2 => (Response::Verification(data[1]), 2),
+            ACCEPT if data.len() >= 9 => (Response::Accept(read_u64(&data[1..])), 9),
+            CHALLENGE if data.len() > NONCE_SIZE => {
+                let mut nonce = [0u8; NONCE_SIZE];
+                nonce.copy_from_slice(&data[1..=NONCE_SIZE]);
+                (Response::Challenge(nonce), 1 + NONCE_SIZE)
+            }
+            ERROR if data.len() >= 3 => {
+                let end = 3 + read_u16(&data[1..]) as usize;
+                if data.len() < end {
+                    return Ok(None);
+                }
+                let message = String::from_utf8_lossy(&data[3..end]).into_owned();
+                (Response::Error(message), end)
+            }
+            HELLO if data.len() >= 7 => (
+                Response::Hello(read_u16(&data[1..]), read_u32(&data[3..])),
+                7,
+            ),
+            SIGNATURES => match Signatures::decode(&data[1..])? {
+                Some((signatures, size)) => (Response::Signatures(signatures), 1 + size),
+                None => return Ok(None),
+            },
+            MISSING if data.len() >= 5 => {
+                let count = read_u32(&data[1..]) as usize;
+                if count > MAX_QUERY_CHUNKS {
+                    return Err(Error::Invalid(format!(
+                        "invalid number of missing chunks: {}",
+                        count
+                    )));
+                }
+
+                let end = 5 + count * 4;
+                if data.len() < end {
+                    return Ok(None);
+                }
+                let indexes = data[5..end].chunks(4).map(read_u32).collect();
+                (Response::Missing(indexes), end)
+            }
+            ACK_INTERVAL if data.len() >= 5 => (Response::AckInterval(read_u32(&data[1..])), 5),
+            ACK | NACK | VERIFICATION | ACCEPT | CHALLENGE | ERROR | HELLO | MISSING
+            | ACK_INTERVAL => return Ok(None),
+            tag => return Err(Error::Invalid(format!("invalid response type: {}", tag))),
+        };
+
+        Ok(Some(decoded))
+    }
+}
+
+/// Decodes the responses sent by the receiver, keeping the bytes of
+/// the responses that were only partially received.
+#[derive(Default)]
+pub struct ResponseDecoder {
+    buf: Vec<u8>,
+}
+
+impl ResponseDecoder {
+    pub fn new() -> ResponseDecoder {
+        ResponseDecoder { buf: Vec::new() }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns the responses that were completely received so far.
+    pub fn decode(&mut self) -> Result<Vec<Response>> {
+        let mut responses = Vec::new();
+        let mut pos = 0;
+
+        while let Some((response, size)) = Response::decode(&self.buf[pos..])? {
+            responses.push(response);
+            pos += size;
+        }
+
+        self.buf.drain(..pos);
+
+        Ok(responses)
+    }
+}
+
+fn read_u16(buf: &[u8]) -> u16 {
+    u16::from_be_bytes([buf[0], buf[1]])
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    let mut u32_buf = [0u8; 4];
+    u32_buf.copy_from_slice(&buf[..4]);
+    u32::from_be_bytes(u32_buf)
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut u64_buf = [0u8; 8];
+    u64_buf.copy_from_slice(&buf[..8]);
+    u64::from_be_bytes(u64_buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delta::BlockSignature;
+
+    #[test]
+    fn test_responses_round_trip() {
+        let responses = vec![
+            Response::Ack(1 << 40),
+            Response::Nack(7, 65536),
+            Response::Verification(VERIFICATION_FAILED),
+            Response::Accept(42),
+            Response::Challenge([9; NONCE_SIZE]),
+            Response::Error("file too large".to_string()),
+            Response::Hello(1, 3),
+            Response::Signatures(Signatures {
+                block_size: 2048,
+                blocks: vec![BlockSignature::new(b"block")],
+            }),
+            Response::Missing(vec![0, 3, 1023]),
+            Response::Missing(Vec::new()),
+            Response::AckInterval(65536),
+        ];
+
+        let mut decoder = ResponseDecoder::new();
+        let mut decoded = Vec::new();
+        for byte in responses.iter().flat_map(Response::encode) {
+            decoder.feed(&[byte]);
+            decoded.extend(decoder.decode().unwrap());
+        }
+
+        assert_eq!(decoded, responses);
+    }
+
+    #[test]
+    fn test_invalid_response_type() {
+        assert!(Response::decode(&[0xff]).is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Environment variable the shared secret is read from when no file is
+/// given.
+pub const SECRET_VAR: &str = "FILE_UPLOAD_SECRET";
+
+/// Smallest size of a shared secret, so that it is not trivial to guess.
+pub const MIN_SECRET_SIZE: usize = 16;
+
+/// Reads the shared secret from `path`, or else from the
+/// `FILE_UPLOAD_SECRET` environment variable. Trailing line breaks are
+/// removed, and secrets shorter than `MIN_SECRET_SIZE` are rejected.
+pub fn read_secret(path: Option<&Path>) -> io::Result<Option<Vec<u8>>> {
+    let secret = match path {
+        Some(path) => fs::read(path)?,
+        None => match env::var_os(SECRET_VAR) {
+            Some(secret) => secret.into_string().map(String::into_bytes).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "the secret is not valid UTF-8")
+            })?,
+            None => return Ok(None),
+        },
+    };
+
+    parse_secret(secret).map(Some)
+}
+
+fn parse_secret(mut secret: Vec<u8>) -> io::Result<Vec<u8>> {
+    while matches!(secret.last(), Some(b'\n') | Some(b'\r')) {
+        secret.pop();
+    }
+
+    if secret.len() < MIN_SECRET_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the secret must be at least {} bytes long", MIN_SECRET_SIZE),
+        ));
+    }
+
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_secret() {
+        assert_eq!(
+            parse_secret(b"0123456789abcdef\r\n".to_vec()).unwrap(),
+            b"0123456789abcdef"
+        );
+        assert!(parse_secret(b"".to_vec()).is_err());
+        assert!(parse_secret(b"\n".to_vec()).is_err());
+        assert!(parse_secret(b"0123456789abcde\n".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use crate::error::{Error, Result};
+
+pub const STRIPE_SIZE: usize = 8;
+
+/// Largest number of ranges a file can be split into.
+pub const MAX_STRIPES: u32 = 64;
+
+/// Range of a file that is uploaded over its own connection, sent after
+/// the other messages that follow the `Header` when both sides support
+/// `CAP_STRIPES`. The file is split into `count` ranges of about the
+/// same size, of which this is the one at `index`. Files that are not
+/// striped are made of a single range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stripe {
+    pub index: u32,
+    pub count: u32,
+}
+
+impl Stripe {
+    pub fn new(index: u32, count: u32) -> Stripe {
+        Stripe { index, count }
+    }
+
+    /// Single range that covers the whole file.
+    pub fn whole() -> Stripe {
+        Stripe::new(0, 1)
+    }
+
+    pub fn is_whole(&self) -> bool {
+        self.count == 1
+    }
+
+    /// Returns the offsets the range starts and ends at in a file of
+    /// `file_size` bytes.
+    pub fn range(&self, file_size: u64) -> (u64, u64) {
+        let bound = |index: u32| (file_size as u128 * index as u128 / self.count as u128) as u64;
+        (bound(self.index), bound(self.index + 1))
+    }
+
+    pub fn encode(&self) -> [u8; STRIPE_SIZE] {
+        let mut buf = [0u8; STRIPE_SIZE];
+        buf[..4].copy_from_slice(&self.index.to_be_bytes());
+        buf[4..].copy_from_slice(&self.count.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8; STRIPE_SIZE]) -> Result<Stripe> {
+        let index = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let count = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+
+        if count == 0 || count > MAX_STRIPES || index >= count {
+            return Err(Error::Invalid(format!(
+                "invalid stripe: {} of {}",
+                index, count
+            )));
+        }
+
+        Ok(Stripe { index, count })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stripe_ranges() {
+        let ranges: Vec<_> = (0..3)
+            .map(|index| Stripe::new(index, 3).range(10))
+            .collect();
+        assert_eq!(ranges, vec![(0, 3), (3, 6), (6, 10)]);
+        assert_eq!(Stripe::whole().range(10), (0, 10));
+        assert_eq!(Stripe::new(1, 2).range(u64::MAX), (u64::MAX / 2, u64::MAX));
+    }
+
+    #[test]
+    fn test_stripe_round_trip() {
+        let stripe = Stripe::new(2, 4);
+        assert_eq!(Stripe::decode(&stripe.encode()).unwrap(), stripe);
+
+        assert!(Stripe::decode(&Stripe::new(4, 4).encode()).is_err());
+        assert!(Stripe::decode(&Stripe::new(0, 0).encode()).is_err());
+        assert!(Stripe::decode(&Stripe::new(0, MAX_STRIPES + 1).encode()).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use crate::error::{Error, Result};
+
+/// Largest amount of extended attributes a file can carry, in bytes.
+pub const MAX_XATTRS_SIZE: usize = 1024 * 1024;
+
+/// Extended attributes of a file, sent after its `Attributes` when both
+/// sides support `CAP_XATTRS`. They include the POSIX ACLs of the file,
+/// which Linux stores as the `system.posix_acl_access` and
+/// `system.posix_acl_default` attributes.
+///
+/// Each attribute is encoded as the size of its name on one byte, the
+/// name, the size of its value on four bytes and the value, after the
+/// total size of the attributes on four bytes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExtendedAttributes {
+    /// Names and values of the attributes.
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl ExtendedAttributes {
+    /// Size of the part of the message that determines its total size.
+    pub const PREFIX_SIZE: usize = 4;
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; ExtendedAttributes::PREFIX_SIZE];
+
+        for (name, value) in &self.entries {
+            if name.is_empty() || name.len() > u8::MAX as usize {
+                return Err(Error::Invalid(format!(
+                    "invalid extended attribute name size: {} bytes",
+                    name.len()
+                )));
+            }
+
+            buf.push(name.len() as u8);
+            buf.extend_from_slice(name);
+            buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            buf.extend_from_slice(value);
+        }
+
+        let size = buf.len() - ExtendedAttributes::PREFIX_SIZE;
+        check_size(size)?;
+        buf[..ExtendedAttributes::PREFIX_SIZE].copy_from_slice(&(size as u32).to_be_bytes());
+
+        Ok(buf)
+    }
+
+    /// Returns the total size of the message that starts with `prefix`.
+    pub fn size(prefix: &[u8; ExtendedAttributes::PREFIX_SIZE]) -> Result<usize> {
+        let size = u32::from_be_bytes(*prefix) as usize;
+        check_size(size)?;
+
+        Ok(ExtendedAttributes::PREFIX_SIZE + size)
+    }
+
+    /// Decodes a whole message, whose size is given by
+    /// `ExtendedAttributes::size`.
+    pub fn decode(buf: &[u8]) -> Result<ExtendedAttributes> {
+        let truncated = || {
+            Error::Invalid(format!(
+                "truncated extended attributes ({} bytes)",
+                buf.len()
+            ))
+        };
+
+        if buf.len() < ExtendedAttributes::PREFIX_SIZE
+            || buf.len() != ExtendedAttributes::size(&[buf[0], buf[1], buf[2], buf[3]])?
+        {
+            return Err(truncated());
+        }
+
+        let mut entries = Vec::new();
+        let mut rest = &buf[ExtendedAttributes::PREFIX_SIZE..];
+
+        while let Some((&name_size, tail)) = rest.split_first() {
+            let name_size = name_size as usize;
+            if name_size == 0 {
+                return Err(Error::Invalid("empty extended attribute name".to_string()));
+            }
+            if tail.len() < name_size + 4 {
+                return Err(truncated());
+            }
+
+            let (name, tail) = tail.split_at(name_size);
+            let (value_size, tail) = tail.split_at(4);
+            let value_size =
+                u32::from_be_bytes([value_size[0], value_size[1], value_size[2], value_size[3]])
+                    as usize;
+            if tail.len() < value_size {
+                return Err(truncated());
+            }
+
+            let (value, tail) = tail.split_at(value_size);
+            entries.push((name.to_vec(), value.to_vec()));
+            rest = tail;
+        }
+
+        Ok(ExtendedAttributes { entries })
+    }
+}
+
+fn check_size(size: usize) -> Result<()> {
+    if size > MAX_XATTRS_SIZE {
+        return Err(Error::Invalid(format!(
+            "extended attributes too large: {} bytes (the limit is {})",
+            size, MAX_XATTRS_SIZE
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xattrs_round_trip() {
+        let xattrs = ExtendedAttributes {
+            entries: vec![
+                (b"user.comment".to_vec(), b"hello".to_vec()),
+                (b"system.posix_acl_access".to_vec(), vec![2, 0, 0, 0, 1, 0]),
+                (b"user.empty".to_vec(), Vec::new()),
+            ],
+        };
+        let buf = xattrs.encode().unwrap();
+
+        let size = ExtendedAttributes::size(&[buf[0], buf[1], buf[2], buf[3]]).unwrap();
+        assert_eq!(size, buf.len());
+        assert_eq!(ExtendedAttributes::decode(&buf).unwrap(), xattrs);
+        assert_eq!(
+            ExtendedAttributes::decode(&ExtendedAttributes::default().encode().unwrap()).unwrap(),
+            ExtendedAttributes::default()
+        );
+    }
+
+    #[test]
+    fn test_invalid_xattrs() {
+        let xattrs = ExtendedAttributes {
+            entries: vec![(b"user.comment".to_vec(), b"hello".to_vec())],
+        };
+        let mut buf = xattrs.encode().unwrap();
+
+        // A value that ends past the end of the message.
+        let last = buf.len() - 6;
+        buf[last] = 6;
+        assert!(ExtendedAttributes::decode(&buf).is_err());
+
+        assert!(ExtendedAttributes::decode(&[0, 0, 0, 2, 0, 0]).is_err());
+        assert!(ExtendedAttributes::size(&(MAX_XATTRS_SIZE as u32 + 1).to_be_bytes()).is_err());
+        assert!(ExtendedAttributes {
+            entries: vec![(Vec::new(), Vec::new())],
+        }
+        .encode()
+        .is_err());
+    }
+}
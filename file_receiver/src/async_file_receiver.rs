@@ -0,0 +1,707 @@
+use std::cmp;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use file_protocol::{
+    Attributes, BlockCopy, ChunkHeader, ChunkKind, ChunkQuery, Compression, ExtendedAttributes,
+    FileKind, FlowControl, Header, Hello, Response, StoredChunk, Stripe, BLOCK_COPY_SIZE, CAP_AUTH,
+    CAP_DEDUP, CAP_DELTA, CAP_MULTI_FILE, CAP_WINDOW, CHUNK_CHECKSUM_SIZE,
+    COMPRESSED_CHUNK_HEADER_SIZE, DEFAULT_ACK_INTERVAL, DIGEST_SIZE, FLOW_CONTROL_SIZE, HELLO_SIZE,
+    MAC_SIZE, MAX_CHUNK_SIZE, STORED_CHUNK_SIZE, VERIFICATION_FAILED, VERIFICATION_OK,
+};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+
+use crate::auth;
+use crate::basis::Basis;
+use crate::chunk_store::ChunkStore;
+use crate::clients::Client;
+use crate::durability::Durability;
+use crate::error::{Error, Result};
+use crate::file_guard::{FileGuard, FilesInProgress};
+use crate::file_receiver::{
+    self, Command, DEFAULT_MAX_CONNECTIONS, DEFAULT_TIMEOUT, POLLING_TIME, SUPPORTED_CAPABILITIES,
+};
+use crate::naming::NamingPolicy;
+use crate::part_file::PartFile;
+use crate::progress::Progress;
+use crate::protocol::{self, ChunkFormat, FileMetadataDecoder};
+use crate::timeout::TimeoutStream;
+use crate::tls::TlsConfig;
+
+/// Stream the file is received through, which is encrypted when TLS is
+/// enabled.
+trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+/// Asynchronous counterpart of `FileReceiver`, to be used from within a
+/// tokio runtime. Each upload is handled by its own task.
+pub struct AsyncFileReceiver {
+    port: u16,
+    max_connections: usize,
+    config: Config,
+    command: watch::Sender<Command>,
+}
+
+/// Configuration the tasks that handle the connections are started with.
+#[derive(Clone)]
+struct Config {
+    tls: Option<TlsConfig>,
+    secret: Option<Vec<u8>>,
+    output_dir: PathBuf,
+    naming: NamingPolicy,
+    durability: Durability,
+    ack_interval: u32,
+    timeout: Duration,
+    chunk_store: Option<ChunkStore>,
+}
+
+/// State shared with the tasks that handle the connections.
+struct Shared {
+    config: Config,
+    command: watch::Receiver<Command>,
+    files_in_progress: FilesInProgress,
+}
+
+impl AsyncFileReceiver {
+    pub fn new(port: u16) -> AsyncFileReceiver {
+        AsyncFileReceiver {
+            port,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            config: Config {
+                tls: None,
+                secret: None,
+                output_dir: PathBuf::new(),
+                naming: NamingPolicy::default(),
+                durability: Durability::default(),
+                ack_interval: DEFAULT_ACK_INTERVAL,
+                timeout: DEFAULT_TIMEOUT,
+                chunk_store: None,
+            },
+            command: watch::channel(Command::Stop).0,
+        }
+    }
+
+    /// Sets the maximum number of uploads handled in parallel. Further
+    /// connections wait in the listen backlog until a transfer finishes.
+    pub fn with_max_connections(mut self, max_connections: usize) -> AsyncFileReceiver {
+        self.max_connections = cmp::max(max_connections, 1);
+        self
+    }
+
+    /// Receives over TLS only, authenticating with the certificate in
+    /// `tls`.
+    pub fn with_tls(mut self, tls: TlsConfig) -> AsyncFileReceiver {
+        self.config.tls = Some(tls);
+        self
+    }
+
+    /// Only accepts the uploaders that prove they know `secret`.
+    pub fn with_secret(mut self, secret: Vec<u8>) -> AsyncFileReceiver {
+        self.config.secret = Some(secret);
+        self
+    }
+
+    /// Stores the received files inside `output_dir` instead of the
+    /// working directory.
+    pub fn with_output_dir(mut self, output_dir: impl Into<PathBuf>) -> AsyncFileReceiver {
+        self.config.output_dir = output_dir.into();
+        self
+    }
+
+    /// Sets the names the received files are stored under.
+    pub fn with_naming(mut self, naming: NamingPolicy) -> AsyncFileReceiver {
+        self.config.naming = naming;
+        self
+    }
+
+    /// Sets how often the received data is synced to disk.
+    pub fn with_durability(mut self, durability: Durability) -> AsyncFileReceiver {
+        self.config.durability = durability;
+        self
+    }
+
+    /// Acknowledges the data received at least every `ack_interval`
+    /// bytes.
+    pub fn with_ack_interval(mut self, ack_interval: u32) -> AsyncFileReceiver {
+        self.config.ack_interval = ack_interval;
+        self
+    }
+
+    /// Drops the connections that stay idle for longer than `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> AsyncFileReceiver {
+        self.config.timeout = timeout;
+        self
+    }
+
+    /// Keeps the content-defined chunks of the files received in a store
+    /// inside `dir`.
+    pub fn with_chunk_store(mut self, dir: impl Into<PathBuf>) -> AsyncFileReceiver {
+        self.config.chunk_store = Some(ChunkStore::new(dir));
+        self
+    }
+
+    /// Accepts upload requests until `stop` or `stop_now` is called.
+    /// Returns after all the transfers in progress have finished. Only
+    /// failing to listen is fatal, as in `FileReceiver::start`.
+    pub async fn start(&self) -> Result<()> {
+        println!("Listening for file upload requests in port: {}", self.port);
+
+        let listener = TcpListener::bind(("127.0.0.1", self.port))
+            .await
+            .map_err(Error::Listen)?;
+        self.command.send_replace(Command::Run);
+
+        let shared = Arc::new(Shared {
+            config: self.config.clone(),
+            command: self.command.subscribe(),
+            files_in_progress: FilesInProgress::default(),
+        });
+        let mut command = self.command.subscribe();
+        let connections = Arc::new(Semaphore::new(self.max_connections));
+        let mut tasks = JoinSet::new();
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = accept(&listener, &connections) => accepted,
+                _ = command.wait_for(|command| *command != Command::Run) => break,
+            };
+
+            let (stream, permit) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    eprintln!("Failed to accept connection: {}", err);
+                    tokio::time::sleep(POLLING_TIME).await;
+                    continue;
+                }
+            };
+
+            let shared = shared.clone();
+            tasks.spawn(async move {
+                if let Err(err) = shared.handle_connection(stream).await {
+                    eprintln!("Dropping connection: {}", err);
+                }
+                drop(permit);
+            });
+
+            while tasks.try_join_next().is_some() {}
+        }
+
+        drop(listener);
+        while tasks.join_next().await.is_some() {}
+
+        Ok(())
+    }
+
+    /// Stops accepting new connections, letting the transfers in
+    /// progress run until completion.
+    pub fn stop(&self) {
+        self.command.send_replace(Command::Stop);
+    }
+
+    /// Stops accepting new connections and interrupts all the transfers
+    /// in progress. These can be resumed later on by the uploaders.
+    pub fn stop_now(&self) {
+        self.command.send_replace(Command::StopNow);
+    }
+}
+
+async fn accept(
+    listener: &TcpListener,
+    connections: &Arc<Semaphore>,
+) -> Result<(TcpStream, OwnedSemaphorePermit)> {
+    let permit = connections
+        .clone()
+        .acquire_owned()
+        .await
+        .expect("semaphore is never closed");
+    let (stream, _) = listener.accept().await?;
+    Ok((stream, permit))
+}
+
+impl Shared {
+    async fn handle_connection(&self, stream: TcpStream) -> Result<()> {
+        let result = self.receive_files(stream).await;
+
+        // Interrupting a transfer causes all sorts of I/O errors.
+        match result {
+            Err(_) if *self.command.borrow() == Command::StopNow => Err(Error::Cancelled),
+            _ => result,
+        }
+    }
+
+    /// Runs `future` until completion, unless `stop_now` is called first.
+    async fn interruptible<T>(&self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        let mut command = self.command.clone();
+
+        tokio::select! {
+            result = future => result,
+            _ = command.wait_for(|command| *command == Command::StopNow) => Err(Error::Cancelled),
+        }
+    }
+
+    /// Asynchronous counterpart of `FileReceiver::receive_files`.
+    async fn receive_files(&self, stream: TcpStream) -> Result<()> {
+        println!("Handling new request from: {}", stream.peer_addr()?);
+        let stream = TimeoutStream::new(stream, self.config.timeout);
+
+        let (mut stream, client): (Box<dyn Stream>, _) = match &self.config.tls {
+            Some(tls) => {
+                let acceptor = TlsAcceptor::from(tls.server_config());
+                let stream = self
+                    .interruptible(async { Ok(acceptor.accept(stream).await?) })
+                    .await?;
+                let client = tls.identify(stream.get_ref().1.peer_certificates())?;
+                (Box::new(stream), client)
+            }
+            None => (Box::new(stream), Client::anonymous()),
+        };
+
+        if !client.is_anonymous() {
+            println!("Client authenticated as: {}", client.name());
+        }
+
+        let negotiated = self.interruptible(self.negotiate(&mut stream)).await;
+        let (capabilities, flow_control) = reject_on_error(&mut stream, negotiated).await?;
+        let mut authenticated = false;
+
+        loop {
+            let header = match self.interruptible(read_header(&mut stream)).await {
+                Ok(Some(header)) => header,
+                Ok(None) => return Ok(()),
+                Err(err) => return reject_on_error(&mut stream, Err(err)).await,
+            };
+
+            self.receive_file(
+                &mut stream,
+                &client,
+                capabilities,
+                flow_control,
+                header,
+                &mut authenticated,
+            )
+            .await?;
+
+            if capabilities & CAP_MULTI_FILE == 0 || *self.command.borrow() != Command::Run {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn receive_file(
+        &self,
+        stream: &mut Box<dyn Stream>,
+        client: &Client,
+        capabilities: u32,
+        flow_control: Option<FlowControl>,
+        header: Header,
+        authenticated: &mut bool,
+    ) -> Result<()> {
+        let admitted = self
+            .interruptible(self.admit(stream, client, capabilities, header, authenticated))
+            .await;
+        let (part, file_path, _guard) = reject_on_error(stream, admitted).await?;
+        let file_size = part.file_size();
+        let (start, _) = part.range();
+
+        let (mut file, offset) = part.open_async().await?;
+
+        let mut basis = None;
+        if capabilities & CAP_DELTA != 0 && !part.is_link() && !part.is_striped() {
+            basis = Basis::<File>::open(&file_path).await?;
+        }
+        if let Some(basis) = &mut basis {
+            let signatures = self.interruptible(basis.signatures()).await?;
+            send_response(stream, Response::Signatures(signatures)).await?;
+        }
+
+        send_response(stream, Response::Accept(offset)).await?;
+
+        println!(
+            "Receiving file: {} (size={}, offset={})",
+            file_path.to_string_lossy(),
+            file_size,
+            offset
+        );
+
+        // The digest must cover the whole file, or the whole range of a
+        // striped file, including the data stored by a previous
+        // connection.
+        let mut hasher = Sha256::new();
+        hash_file_range(&mut file, start, offset, &mut hasher).await?;
+
+        let mut progress = Progress::new(&part, offset, flow_control, self.config.durability);
+
+        let client_store = self
+            .config
+            .chunk_store
+            .as_ref()
+            .map(|store| store.for_client(client));
+        let store = match &client_store {
+            Some(store)
+                if capabilities & CAP_DEDUP != 0
+                    && basis.is_none()
+                    && !part.is_link()
+                    && !part.is_striped() =>
+            {
+                Some(store)
+            }
+            _ => None,
+        };
+
+        let mut format = ChunkFormat {
+            compression: Compression::negotiated(capabilities),
+            basis,
+            store,
+        };
+        let result = self
+            .interruptible(receive_chunks(
+                stream,
+                &part,
+                &mut file,
+                &mut format,
+                &mut progress,
+                &mut hasher,
+                self.config.durability,
+            ))
+            .await;
+        if result.is_err() && progress.must_discard_incomplete() {
+            file.set_len(progress.contiguous_bytes()).await?;
+        }
+        result?;
+
+        file.flush().await?;
+
+        let digest = hasher.finalize();
+        let complete = self
+            .interruptible(self.verify(stream, client, &digest, &part, &file, &file_path))
+            .await?;
+
+        if let (Some(store), true, false) = (&client_store, complete, part.is_link()) {
+            if let Err(err) = store.insert_file_async(&file, self.config.durability).await {
+                eprintln!("WARNING: failed to store the chunks of the file: {}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Asynchronous counterpart of `FileReceiver::negotiate`.
+    async fn negotiate(&self, stream: &mut Box<dyn Stream>) -> Result<(u32, Option<FlowControl>)> {
+        let mut hello_buf = [0u8; HELLO_SIZE];
+        stream.read_exact(&mut hello_buf).await?;
+
+        let required = self.required_capabilities();
+        let (version, capabilities) = Hello::decode(&hello_buf)?
+            .negotiate(required | self.supported_capabilities(), required)?;
+        send_response(stream, Response::Hello(version, capabilities)).await?;
+
+        let mut flow_control = None;
+        if capabilities & CAP_WINDOW != 0 {
+            let mut flow_control_buf = [0u8; FLOW_CONTROL_SIZE];
+            stream.read_exact(&mut flow_control_buf).await?;
+
+            let agreed = FlowControl::decode(&flow_control_buf)?.agree(self.config.ack_interval);
+            send_response(stream, Response::AckInterval(agreed.ack_interval)).await?;
+            flow_control = Some(agreed);
+        }
+
+        Ok((capabilities, flow_control))
+    }
+
+    /// Asynchronous counterpart of `FileReceiver::admit`.
+    async fn admit(
+        &self,
+        stream: &mut Box<dyn Stream>,
+        client: &Client,
+        capabilities: u32,
+        header: Header,
+        authenticated: &mut bool,
+    ) -> Result<(PartFile, PathBuf, Option<FileGuard>)> {
+        let metadata = read_file_metadata(stream, capabilities).await?;
+
+        if let (Some(secret), false) = (&self.config.secret, *authenticated) {
+            let nonce = auth::new_nonce()?;
+            send_response(stream, Response::Challenge(nonce)).await?;
+
+            let mut mac = [0u8; MAC_SIZE];
+            stream.read_exact(&mut mac).await?;
+            auth::verify(secret, &nonce, &mac)?;
+            *authenticated = true;
+        }
+
+        let output_dir = self.config.output_dir.clone();
+        let naming = self.config.naming.clone();
+        let files_in_progress = self.files_in_progress.clone();
+        let client = client.clone();
+        tokio::task::spawn_blocking(move || {
+            file_receiver::claim_file(
+                &output_dir,
+                &naming,
+                &files_in_progress,
+                &client,
+                capabilities,
+                header,
+                metadata,
+            )
+        })
+        .await
+        .expect("claim task panicked")
+    }
+
+    /// Asynchronous counterpart of `FileReceiver::verify`.
+    async fn verify(
+        &self,
+        stream: &mut Box<dyn Stream>,
+        client: &Client,
+        digest: &[u8],
+        part: &PartFile,
+        file: &File,
+        file_path: &Path,
+    ) -> Result<bool> {
+        let mut digest_buf = [0u8; DIGEST_SIZE];
+        stream.read_exact(&mut digest_buf).await?;
+
+        if digest_buf[..] != digest[..] {
+            part.discard_async().await?;
+            send_response(stream, Response::Verification(VERIFICATION_FAILED)).await?;
+            return Err(Error::Verification);
+        }
+
+        let complete = if part.is_striped() {
+            let naming = self.config.naming.clone();
+            let client = client.clone();
+            let file_path = file_path.to_path_buf();
+            let files_in_progress = self.files_in_progress.clone();
+            part.complete_range_async(file, move || {
+                let (directory, file_name) = protocol::split_path(&file_path);
+                naming.claim(directory, &client, file_name, &files_in_progress)
+            })
+            .await?
+        } else {
+            part.finalize_async(file, file_path).await?;
+            true
+        };
+
+        send_response(stream, Response::Verification(VERIFICATION_OK)).await?;
+        if complete {
+            println!("File transfer completed");
+        } else {
+            println!("Range of the file completed");
+        }
+
+        Ok(complete)
+    }
+
+    /// Asynchronous counterpart of `FileReceiver::required_capabilities`.
+    fn required_capabilities(&self) -> u32 {
+        if self.config.secret.is_some() {
+            CAP_AUTH
+        } else {
+            0
+        }
+    }
+
+    /// Asynchronous counterpart of `FileReceiver::supported_capabilities`.
+    fn supported_capabilities(&self) -> u32 {
+        if self.config.chunk_store.is_some() {
+            SUPPORTED_CAPABILITIES | CAP_DEDUP
+        } else {
+            SUPPORTED_CAPABILITIES
+        }
+    }
+}
+
+/// Asynchronous counterpart of `FileReceiver::reject_on_error`.
+async fn reject_on_error<T>(stream: &mut Box<dyn Stream>, result: Result<T>) -> Result<T> {
+    match result {
+        Err(err @ Error::Busy(_)) => Err(err),
+        Err(err) => {
+            let _ = send_response(stream, Response::Error(err.to_string())).await;
+            Err(err)
+        }
+        Ok(value) => Ok(value),
+    }
+}
+
+/// Reads the header of the next file, or returns `None` if the uploader
+/// ended the session instead.
+async fn read_header(stream: &mut Box<dyn Stream>) -> Result<Option<Header>> {
+    let mut prefix = [0u8; Header::PREFIX_SIZE];
+    match stream.read(&mut prefix[..1]).await {
+        Ok(0) => return Ok(None),
+        Ok(_) => {}
+        Err(err) if protocol::is_session_closed(&err) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    stream.read_exact(&mut prefix[1..]).await?;
+
+    let mut buf = vec![0u8; Header::size(&prefix)?];
+    buf[..Header::PREFIX_SIZE].copy_from_slice(&prefix);
+    stream.read_exact(&mut buf[Header::PREFIX_SIZE..]).await?;
+
+    Ok(Some(Header::decode(&buf)?))
+}
+
+/// Asynchronous counterpart of `file_receiver::read_query`.
+async fn read_query(stream: &mut Box<dyn Stream>) -> Result<ChunkQuery> {
+    let mut prefix = [0u8; ChunkQuery::PREFIX_SIZE];
+    stream.read_exact(&mut prefix).await?;
+
+    let mut buf = vec![0u8; ChunkQuery::size(&prefix)?];
+    buf[..ChunkQuery::PREFIX_SIZE].copy_from_slice(&prefix);
+    stream
+        .read_exact(&mut buf[ChunkQuery::PREFIX_SIZE..])
+        .await?;
+
+    Ok(ChunkQuery::decode(&buf)?)
+}
+
+/// Asynchronous counterpart of `file_receiver::read_file_metadata`.
+async fn read_file_metadata(
+    stream: &mut Box<dyn Stream>,
+    capabilities: u32,
+) -> Result<(FileKind, Attributes, ExtendedAttributes, Stripe)> {
+    let mut decoder = FileMetadataDecoder::new(capabilities);
+
+    while let Some(size) = decoder.next_size()? {
+        let mut buf = vec![0u8; size];
+        stream.read_exact(&mut buf).await?;
+        decoder.feed(&buf)?;
+    }
+
+    Ok(decoder.finish())
+}
+
+/// Asynchronous counterpart of `FileReceiver::receive_chunks`.
+async fn receive_chunks(
+    stream: &mut Box<dyn Stream>,
+    part: &PartFile,
+    file: &mut File,
+    format: &mut ChunkFormat<'_, File>,
+    progress: &mut Progress,
+    hasher: &mut Sha256,
+    durability: Durability,
+) -> Result<()> {
+    let file_size = part.file_size();
+    let mut buf = vec![0u8; MAX_CHUNK_SIZE];
+
+    while !progress.is_complete() {
+        let (chunk, checksum) = read_chunk(stream, &mut buf, file_size, format).await?;
+
+        let data = protocol::chunk_data(&buf, &chunk, format.compression);
+        let data = match progress.check_data(&chunk, data, &checksum)? {
+            Some(data) => data,
+            None => {
+                send_response(stream, Response::Nack(chunk.offset, chunk.length)).await?;
+                continue;
+            }
+        };
+
+        // The chunk is marked as stored only once written, as the
+        // transfer might be interrupted while waiting for the file.
+        file.seek(SeekFrom::Start(chunk.offset)).await?;
+        file.write_all(&data).await?;
+
+        if let Some((start, end)) = progress.mark_stored(&chunk, &data, hasher) {
+            hash_file_range(file, start, end, hasher).await?;
+        }
+
+        if progress.is_ack_due() {
+            file.flush().await?;
+        }
+
+        if progress.must_sync() {
+            file.sync_data().await?;
+            progress.mark_synced();
+        }
+
+        if let Some(offset) = progress.acknowledge() {
+            part.record_received_async(offset, durability).await?;
+            send_response(stream, Response::Ack(offset)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Asynchronous counterpart of `FileReceiver::read_chunk`.
+async fn read_chunk(
+    stream: &mut Box<dyn Stream>,
+    buf: &mut [u8],
+    file_size: u64,
+    format: &mut ChunkFormat<'_, File>,
+) -> Result<(ChunkHeader, [u8; CHUNK_CHECKSUM_SIZE])> {
+    while format.has_chunk_kinds() {
+        let kind = format.chunk_kind(stream.read_u8().await?)?;
+
+        match (kind, &mut format.basis, format.store) {
+            (ChunkKind::Copy, Some(basis), _) => {
+                let mut copy_buf = [0u8; BLOCK_COPY_SIZE];
+                stream.read_exact(&mut copy_buf).await?;
+                let copy = BlockCopy::decode(&copy_buf, basis.block_size(), file_size)?;
+
+                let length = basis.read_block(copy.block, buf).await?;
+                return Ok(protocol::local_chunk(copy.offset, &buf[..length]));
+            }
+            (ChunkKind::Query, _, Some(store)) => {
+                let query = read_query(stream).await?;
+                let missing = store.missing_async(query).await;
+                send_response(stream, Response::Missing(missing)).await?;
+            }
+            (ChunkKind::Stored, _, Some(store)) => {
+                let mut chunk_buf = [0u8; STORED_CHUNK_SIZE];
+                stream.read_exact(&mut chunk_buf).await?;
+                let chunk = StoredChunk::decode(&chunk_buf, file_size)?;
+
+                let length = store.read_async(&chunk.hash, buf).await?;
+                protocol::check_stored_length(&chunk, length)?;
+                return Ok(protocol::local_chunk(chunk.offset, &buf[..length]));
+            }
+            (ChunkKind::Data, ..) => break,
+            _ => unreachable!("chunk kind checked against the format"),
+        }
+    }
+
+    let mut header = [0u8; COMPRESSED_CHUNK_HEADER_SIZE];
+    let header = &mut header[..format.header_size()];
+    stream.read_exact(header).await?;
+    let chunk = format.decode_header(header, file_size)?;
+
+    stream.read_exact(&mut buf[..chunk.data_size()]).await?;
+
+    let mut checksum = [0u8; CHUNK_CHECKSUM_SIZE];
+    stream.read_exact(&mut checksum).await?;
+
+    Ok((chunk, checksum))
+}
+
+async fn send_response(stream: &mut Box<dyn Stream>, response: Response) -> Result<()> {
+    stream.write_all(&response.encode()).await?;
+    Ok(())
+}
+
+/// Feeds the data of the file between `start` and `end` into the hasher.
+async fn hash_file_range(file: &mut File, start: u64, end: u64, hasher: &mut Sha256) -> Result<()> {
+    file.seek(SeekFrom::Start(start)).await?;
+
+    let mut reader = file.take(end - start);
+    let mut buf = [0u8; 8192];
+
+    loop {
+        let bytes_read = reader.read(&mut buf).await?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..bytes_read]);
+    }
+}
@@ -0,0 +1,99 @@
+//! Application of the metadata sent by the uploader to the received files.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use file_protocol::Attributes;
+
+/// Permission bits that are applied. The setuid, setgid and sticky bits
+/// are left out, as they would let uploaders create privileged
+/// executables on a receiver running as root.
+#[cfg(unix)]
+const MODE_MASK: u32 = 0o777;
+
+/// Applies `attributes` to the received `file`. The owner is changed
+/// only when the receiver is privileged enough to do so, and is
+/// otherwise left as is.
+pub fn apply(file: &File, attributes: &Attributes) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{fchown, PermissionsExt};
+
+        // The owner is changed first, as doing so may clear the mode.
+        if let Some((uid, gid)) = attributes.owner {
+            match fchown(file, Some(uid), Some(gid)) {
+                Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {}
+                result => result?,
+            }
+        }
+
+        if let Some(mode) = attributes.mode {
+            file.set_permissions(std::fs::Permissions::from_mode(mode & MODE_MASK))?;
+        }
+    }
+
+    if let Some(mtime) = attributes.mtime.and_then(system_time) {
+        file.set_modified(mtime)?;
+    }
+
+    Ok(())
+}
+
+/// Applies `attributes` to the link at `path` itself. Links have no
+/// permissions of their own, so only their owner and modification time
+/// are applied.
+#[cfg(unix)]
+pub fn apply_to_link(path: &Path, attributes: &Attributes) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::lchown;
+
+    if let Some((uid, gid)) = attributes.owner {
+        match lchown(path, Some(uid), Some(gid)) {
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {}
+            result => result?,
+        }
+    }
+
+    if let Some((secs, nanos)) = attributes.mtime {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let times = [
+            libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            },
+            libc::timespec {
+                tv_sec: secs as libc::time_t,
+                tv_nsec: nanos as libc::c_long,
+            },
+        ];
+
+        let result = unsafe {
+            libc::utimensat(
+                libc::AT_FDCWD,
+                path.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Converts a time in seconds and nanoseconds since the Unix epoch,
+/// returning `None` if it can not be represented.
+fn system_time((secs, nanos): (i64, u32)) -> Option<SystemTime> {
+    let time = if secs >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64))?
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()))?
+    };
+
+    time.checked_add(Duration::from_nanos(nanos as u64))
+}
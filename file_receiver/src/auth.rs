@@ -0,0 +1,22 @@
+//! Authentication of the uploaders that know a pre-shared secret.
+
+use file_protocol::NONCE_SIZE;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use crate::error::{Error, Result};
+
+/// Returns a random nonce to challenge an uploader with.
+pub fn new_nonce() -> Result<[u8; NONCE_SIZE]> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    getrandom::getrandom(&mut nonce).map_err(|err| Error::Io(err.into()))?;
+    Ok(nonce)
+}
+
+/// Checks that `mac` is the HMAC-SHA256 of the nonce keyed with the
+/// shared secret, which proves that the uploader knows the secret.
+pub fn verify(secret: &[u8], nonce: &[u8], mac: &[u8]) -> Result<()> {
+    let mut hmac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    hmac.update(nonce);
+    hmac.verify(mac).map_err(|_| Error::Unauthenticated)
+}
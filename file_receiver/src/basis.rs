@@ -0,0 +1,159 @@
+//! Copies of the files the receiver already holds, which the files sent
+//! as a delta are rebuilt from.
+
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{self, prelude::*, SeekFrom};
+use std::path::Path;
+
+use file_protocol::{BlockSignature, Signatures, MAX_CHUNK_SIZE, MAX_SIGNATURES};
+
+use crate::error::{Error, Result};
+
+/// Smallest size of the blocks a copy is split into, below which the
+/// signatures would take more space than the data they spare.
+const MIN_BLOCK_SIZE: usize = 1024;
+
+/// Copy of a file the receiver holds, split into blocks the uploader
+/// refers to instead of sending their data.
+pub struct Basis<F> {
+    file: F,
+    block_size: usize,
+    block_count: u32,
+}
+
+impl<F> Basis<F> {
+    fn new(file: F, len: u64) -> Basis<F> {
+        let block_size = block_size(len);
+        let block_count = cmp::min(len / block_size as u64, MAX_SIGNATURES as u64);
+
+        Basis {
+            file,
+            block_size,
+            block_count: block_count as u32,
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Returns the offset of the block with the given index, which the
+    /// uploader may have made up.
+    fn block_offset(&self, block: u32) -> Result<u64> {
+        if block >= self.block_count {
+            return Err(Error::Protocol(format!("unknown block: {}", block)));
+        }
+
+        Ok(block as u64 * self.block_size as u64)
+    }
+}
+
+impl Basis<File> {
+    /// Opens the copy of the file at `path`, if it is a regular file
+    /// large enough to be split into blocks.
+    pub fn open(path: &Path) -> Result<Option<Basis<File>>> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_file() && metadata.len() >= MIN_BLOCK_SIZE as u64 => {
+                Ok(Some(Basis::new(File::open(path)?, metadata.len())))
+            }
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Computes the signatures of the blocks of the copy.
+    pub fn signatures(&mut self) -> Result<Signatures> {
+        let mut buf = vec![0u8; self.block_size];
+        let mut blocks = Vec::with_capacity(self.block_count as usize);
+
+        self.file.seek(SeekFrom::Start(0))?;
+        for _ in 0..self.block_count {
+            self.file.read_exact(&mut buf)?;
+            blocks.push(BlockSignature::new(&buf));
+        }
+
+        Ok(Signatures {
+            block_size: self.block_size as u32,
+            blocks,
+        })
+    }
+
+    /// Reads the block with the given index into `buf`, returning its
+    /// size.
+    pub fn read_block(&mut self, block: u32, buf: &mut [u8]) -> Result<usize> {
+        self.file.seek(SeekFrom::Start(self.block_offset(block)?))?;
+        self.file.read_exact(&mut buf[..self.block_size])?;
+        Ok(self.block_size)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Basis<tokio::fs::File> {
+    /// Asynchronous counterpart of `Basis<File>::open`.
+    pub async fn open(path: &Path) -> Result<Option<Basis<tokio::fs::File>>> {
+        match tokio::fs::symlink_metadata(path).await {
+            Ok(metadata) if metadata.is_file() && metadata.len() >= MIN_BLOCK_SIZE as u64 => {
+                let file = tokio::fs::File::open(path).await?;
+                Ok(Some(Basis::new(file, metadata.len())))
+            }
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Asynchronous counterpart of `Basis<File>::signatures`.
+    pub async fn signatures(&mut self) -> Result<Signatures> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let mut buf = vec![0u8; self.block_size];
+        let mut blocks = Vec::with_capacity(self.block_count as usize);
+
+        self.file.seek(SeekFrom::Start(0)).await?;
+        for _ in 0..self.block_count {
+            self.file.read_exact(&mut buf).await?;
+            blocks.push(BlockSignature::new(&buf));
+        }
+
+        Ok(Signatures {
+            block_size: self.block_size as u32,
+            blocks,
+        })
+    }
+
+    /// Asynchronous counterpart of `Basis<File>::read_block`.
+    pub async fn read_block(&mut self, block: u32, buf: &mut [u8]) -> Result<usize> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let offset = self.block_offset(block)?;
+        self.file.seek(SeekFrom::Start(offset)).await?;
+        self.file.read_exact(&mut buf[..self.block_size]).await?;
+        Ok(self.block_size)
+    }
+}
+
+/// Size of the blocks a copy of `len` bytes is split into, which grows
+/// with the square root of the size as in rsync, balancing the size of
+/// the signatures against the data sent for each changed block.
+fn block_size(len: u64) -> usize {
+    ((len as f64).sqrt() as usize).clamp(MIN_BLOCK_SIZE, MAX_CHUNK_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_size() {
+        assert_eq!(block_size(1024), MIN_BLOCK_SIZE);
+        assert_eq!(block_size(100_000_000), 10_000);
+        assert_eq!(block_size(1 << 40), MAX_CHUNK_SIZE);
+
+        let basis = Basis::new((), 10_000_000);
+        assert_eq!(basis.block_size(), 3162);
+        assert_eq!(basis.block_offset(3161).unwrap(), 3161 * 3162);
+        assert!(basis.block_offset(3162).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+//! Content-addressed store of the chunks of the files received, which
+//! deduplicated files are rebuilt from.
+
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use file_protocol::{chunk_hash, ChunkQuery, Chunker, CHUNK_HASH_SIZE};
+use sha2::{Digest, Sha256};
+
+use crate::clients::Client;
+use crate::durability::Durability;
+use crate::error::{Error, Result};
+use crate::part_file;
+
+/// Directory holding the content-defined chunks of the files received,
+/// each under the hex encoding of its hash, inside a directory named
+/// after the first byte of the hash.
+///
+/// The chunks of the files of each client are kept apart, as a client
+/// could otherwise learn whether a file is held by the receiver, or get
+/// the contents of a chunk of it, from the hash of the chunk alone.
+#[derive(Clone)]
+pub struct ChunkStore {
+    dir: PathBuf,
+    next_temp_id: Arc<AtomicUsize>,
+}
+
+impl ChunkStore {
+    pub fn new(dir: impl Into<PathBuf>) -> ChunkStore {
+        ChunkStore {
+            dir: dir.into(),
+            next_temp_id: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Returns the part of the store holding the chunks of the files of
+    /// `client`, which is shared with the clients whose files are stored
+    /// in the same directory, as they can read these files anyway.
+    pub fn for_client(&self, client: &Client) -> ChunkStore {
+        let directory = client.directory().to_string_lossy();
+        ChunkStore {
+            dir: self.dir.join(hex(&Sha256::digest(directory.as_bytes()))),
+            next_temp_id: self.next_temp_id.clone(),
+        }
+    }
+
+    fn chunk_path(&self, hash: &[u8; CHUNK_HASH_SIZE]) -> PathBuf {
+        let name = hex(hash);
+        self.dir.join(&name[..2]).join(name)
+    }
+
+    /// Path the data of a chunk is written to before being moved to its
+    /// final path, which is unique so that connections storing the same
+    /// chunk do not write to the same file.
+    fn temp_path(&self) -> PathBuf {
+        let id = self.next_temp_id.fetch_add(1, Ordering::Relaxed);
+        self.dir
+            .join(format!(".chunk.{}.{}.tmp", std::process::id(), id))
+    }
+
+    /// Returns whether the store holds the chunk with the given hash.
+    pub fn contains(&self, hash: &[u8; CHUNK_HASH_SIZE]) -> bool {
+        self.chunk_path(hash).is_file()
+    }
+
+    /// Returns the indexes of the chunks of `query` the store lacks.
+    pub fn missing(&self, query: &ChunkQuery) -> Vec<u32> {
+        query
+            .hashes
+            .iter()
+            .enumerate()
+            .filter(|(_, hash)| !self.contains(hash))
+            .map(|(index, _)| index as u32)
+            .collect()
+    }
+
+    /// Reads the chunk with the given hash into `buf`, returning its size.
+    /// The chunk might have been removed from the store since it was
+    /// queried, in which case the transfer fails for the uploader to
+    /// query it again.
+    pub fn read(&self, hash: &[u8; CHUNK_HASH_SIZE], buf: &mut [u8]) -> Result<usize> {
+        let data = self.load(hash, buf.len())?;
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    /// Returns the data of the chunk with the given hash, which must not
+    /// be larger than `max_size`.
+    fn load(&self, hash: &[u8; CHUNK_HASH_SIZE], max_size: usize) -> Result<Vec<u8>> {
+        let path = self.chunk_path(hash);
+        let data = fs::read(&path)?;
+
+        check_chunk(&path, hash, &data, max_size)?;
+        Ok(data)
+    }
+
+    /// Adds the chunks of the complete `file` that the store lacks. The
+    /// chunks are synced to disk unless `durability` is `None`.
+    pub fn insert_file(&self, file: &mut File, durability: Durability) -> Result<()> {
+        file.seek(io::SeekFrom::Start(0))?;
+
+        let sync = durability != Durability::None;
+        let mut directories = BTreeSet::new();
+        let mut insert = |data: &[u8]| -> Result<()> {
+            if let Some(path) = self.insert(data, sync)? {
+                directories.extend(path.parent().map(Path::to_owned));
+            }
+            Ok(())
+        };
+
+        let mut chunker = Chunker::new();
+        let mut buf = vec![0u8; 64 * 1024];
+
+        loop {
+            let bytes_read = file.read(&mut buf)?;
+            if bytes_read == 0 {
+                break;
+            }
+
+            chunker.feed(&buf[..bytes_read]);
+            while let Some(chunk) = chunker.next_chunk() {
+                insert(&chunk)?;
+            }
+        }
+
+        while let Some(chunk) = chunker.finish() {
+            insert(&chunk)?;
+        }
+
+        // The chunks are renamed into place once synced, so that a crash
+        // does not leave an incomplete chunk under the name of another.
+        for directory in directories {
+            part_file::sync_dir(&directory)?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds the chunk with the given data, returning its path unless the
+    /// store already held it.
+    fn insert(&self, data: &[u8], sync: bool) -> Result<Option<PathBuf>> {
+        let hash = chunk_hash(data);
+        if self.contains(&hash) {
+            return Ok(None);
+        }
+
+        let path = self.chunk_path(&hash);
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+
+        let temp_path = self.temp_path();
+        let mut temp_file = File::create(&temp_path)?;
+        temp_file.write_all(data)?;
+        if sync {
+            temp_file.sync_data()?;
+        }
+        fs::rename(&temp_path, &path)?;
+
+        Ok(Some(path))
+    }
+}
+
+/// The asynchronous counterparts run the synchronous methods in a thread
+/// where blocking is allowed.
+#[cfg(feature = "tokio")]
+impl ChunkStore {
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&ChunkStore) -> T + Send + 'static,
+    ) -> T {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || f(&store))
+            .await
+            .expect("chunk store task panicked")
+    }
+
+    /// Asynchronous counterpart of `ChunkStore::missing`.
+    pub async fn missing_async(&self, query: ChunkQuery) -> Vec<u32> {
+        self.blocking(move |store| store.missing(&query)).await
+    }
+
+    /// Asynchronous counterpart of `ChunkStore::read`.
+    pub async fn read_async(&self, hash: &[u8; CHUNK_HASH_SIZE], buf: &mut [u8]) -> Result<usize> {
+        let (hash, max_size) = (*hash, buf.len());
+        let data = self
+            .blocking(move |store| store.load(&hash, max_size))
+            .await?;
+
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    /// Asynchronous counterpart of `ChunkStore::insert_file`.
+    pub async fn insert_file_async(
+        &self,
+        file: &tokio::fs::File,
+        durability: Durability,
+    ) -> Result<()> {
+        let mut file = file.try_clone().await?.into_std().await;
+        self.blocking(move |store| store.insert_file(&mut file, durability))
+            .await
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(2 * bytes.len());
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+/// Checks that the data read from the chunk at `path` is the chunk with
+/// the given hash, which might not be the case after a crash, removing
+/// it from the store otherwise so that the uploader sends it again.
+fn check_chunk(
+    path: &Path,
+    hash: &[u8; CHUNK_HASH_SIZE],
+    data: &[u8],
+    buf_size: usize,
+) -> Result<()> {
+    if data.len() > buf_size || chunk_hash(data) != *hash {
+        let _ = fs::remove_file(path);
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("corrupted chunk in the store: {}", path.display()),
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_path() {
+        let store = ChunkStore::new("store");
+        let path = store.chunk_path(&[0xab; CHUNK_HASH_SIZE]);
+
+        assert_eq!(path.parent().unwrap(), Path::new("store/ab"));
+        assert_eq!(path.file_name().unwrap().len(), 2 * CHUNK_HASH_SIZE);
+        assert!(path.ends_with("abababab".repeat(8)));
+    }
+
+    #[test]
+    fn test_chunks_kept_apart_per_client() {
+        let dir = std::env::temp_dir().join(format!("chunk-store-test-{}", std::process::id()));
+        let store = ChunkStore::new(&dir);
+        let alice = store.for_client(&Client::new("alice".to_string(), "alice"));
+        let bob = store.for_client(&Client::new("bob".to_string(), "bob"));
+        let bob_again = store.for_client(&Client::new("bob2".to_string(), "bob"));
+
+        let data = b"data of a file uploaded by alice".repeat(100);
+        let path = dir.join("file");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, &data).unwrap();
+        let result = alice.insert_file(&mut File::open(&path).unwrap(), Durability::EveryAck);
+
+        let query = ChunkQuery {
+            hashes: vec![chunk_hash(&data)],
+        };
+        let missing = [&alice, &bob, &bob_again].map(|store| store.missing(&query));
+        fs::remove_dir_all(&dir).unwrap();
+
+        result.unwrap();
+        assert_eq!(missing, [vec![], vec![0], vec![0]]);
+        assert_eq!(bob.dir, bob_again.dir);
+    }
+}
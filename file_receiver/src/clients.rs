@@ -0,0 +1,70 @@
+//! Identities of the uploaders that authenticate with a certificate.
+
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+
+/// Name the uploaders that did not authenticate go by, which no client
+/// can take so that their files are not mixed up.
+pub(crate) const ANONYMOUS: &str = "anonymous";
+
+/// Uploader identified by the common name in the subject of its
+/// certificate, which determines where its files are stored and which
+/// files it is allowed to upload.
+#[derive(Clone, Debug)]
+pub struct Client {
+    name: String,
+    directory: PathBuf,
+    max_file_size: Option<u64>,
+}
+
+impl Client {
+    pub fn new(name: String, directory: impl Into<PathBuf>) -> Client {
+        Client {
+            name,
+            directory: directory.into(),
+            max_file_size: None,
+        }
+    }
+
+    /// Rejects the files larger than `max_file_size` bytes.
+    pub fn with_max_file_size(mut self, max_file_size: u64) -> Client {
+        self.max_file_size = Some(max_file_size);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Uploader that did not authenticate, whose files are stored in the
+    /// output directory.
+    pub(crate) fn anonymous() -> Client {
+        Client::new(String::new(), PathBuf::new())
+    }
+
+    pub(crate) fn is_anonymous(&self) -> bool {
+        self.name.is_empty()
+    }
+
+    /// Directory the files of the client are stored at, relative to the
+    /// output directory of the receiver unless absolute.
+    pub(crate) fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Checks that the client is allowed to upload a file of
+    /// `file_size` bytes.
+    pub(crate) fn authorize(&self, file_size: u64) -> Result<()> {
+        if let Some(max_file_size) = self.max_file_size {
+            if file_size > max_file_size {
+                return Err(Error::Unauthorized(format!(
+                    "{} can not upload files larger than {} bytes (size={})",
+                    self.name, max_file_size, file_size
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+//! When the received data is synced to disk, and therefore which parts
+//! of the file an acknowledgement vouches for.
+
+use std::cmp;
+use std::str::FromStr;
+
+/// How often the received data is synced to disk. The data is always
+/// synced before it is acknowledged, unless the policy is `None`, so an
+/// acknowledged offset survives a crash of the receiver.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Durability {
+    /// Syncs the data before every acknowledgement.
+    #[default]
+    EveryAck,
+    /// Syncs the data once this many bytes were received since the last
+    /// sync, acknowledging only the data that was synced.
+    EveryBytes(u64),
+    /// Never syncs the data, so an acknowledgement only means that the
+    /// data was handed to the operating system.
+    None,
+}
+
+impl Durability {
+    /// Returns whether the data must be synced before acknowledging
+    /// `offset`, given the number of bytes that were synced already.
+    pub(crate) fn must_sync(&self, bytes_synced: u64, offset: u64, file_size: u64) -> bool {
+        match *self {
+            Durability::EveryAck => offset > bytes_synced,
+            Durability::EveryBytes(bytes) => {
+                offset > bytes_synced && (offset - bytes_synced >= bytes || offset == file_size)
+            }
+            Durability::None => false,
+        }
+    }
+
+    /// Returns the policy to use for an uploader that sends at most
+    /// `window` bytes without them being acknowledged. As only synced
+    /// data is acknowledged, the data is synced at least every half
+    /// window so that the uploader does not wait for it forever.
+    pub(crate) fn within_window(&self, window: u64) -> Durability {
+        match *self {
+            Durability::EveryBytes(bytes) => Durability::EveryBytes(cmp::min(bytes, window / 2)),
+            durability => durability,
+        }
+    }
+
+    /// Returns the offset that can be acknowledged once `offset` bytes
+    /// were received, of which `bytes_synced` were synced.
+    pub(crate) fn acknowledged_offset(&self, bytes_synced: u64, offset: u64) -> u64 {
+        match self {
+            Durability::None => offset,
+            _ => bytes_synced,
+        }
+    }
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Durability, String> {
+        match s {
+            "every-ack" => Ok(Durability::EveryAck),
+            "none" => Ok(Durability::None),
+            bytes => match bytes.parse() {
+                Ok(bytes) if bytes > 0 => Ok(Durability::EveryBytes(bytes)),
+                _ => Err(format!("invalid durability policy: {}", s)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_durability() {
+        assert_eq!("every-ack".parse(), Ok(Durability::EveryAck));
+        assert_eq!("none".parse(), Ok(Durability::None));
+        assert_eq!("4096".parse(), Ok(Durability::EveryBytes(4096)));
+        assert!("0".parse::<Durability>().is_err());
+        assert!("always".parse::<Durability>().is_err());
+    }
+
+    #[test]
+    fn test_sync_every_bytes() {
+        let durability = Durability::EveryBytes(100);
+
+        assert!(!durability.must_sync(0, 99, 1000));
+        assert!(durability.must_sync(0, 100, 1000));
+        assert!(!durability.must_sync(100, 150, 1000));
+        assert!(durability.must_sync(950, 1000, 1000));
+        assert_eq!(durability.acknowledged_offset(100, 150), 100);
+        assert_eq!(Durability::None.acknowledged_offset(100, 150), 150);
+    }
+
+    #[test]
+    fn test_sync_within_window() {
+        assert_eq!(
+            Durability::EveryBytes(1000).within_window(400),
+            Durability::EveryBytes(200)
+        );
+        assert_eq!(
+            Durability::EveryBytes(100).within_window(400),
+            Durability::EveryBytes(100)
+        );
+        assert_eq!(Durability::None.within_window(400), Durability::None);
+    }
+}
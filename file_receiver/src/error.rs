@@ -0,0 +1,77 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to listen for connections.
+    Listen(io::Error),
+    /// The uploader sent a message that does not follow the protocol.
+    Protocol(String),
+    /// The file is already being received through another connection.
+    Busy(String),
+    /// Failed to write the file or to communicate with the uploader.
+    Io(io::Error),
+    /// The file received does not match the original.
+    Verification,
+    /// The transfer was interrupted by `FileReceiver::stop_now`.
+    Cancelled,
+    /// The TLS settings are invalid.
+    Tls(String),
+    /// The uploader is not allowed to upload the file.
+    Unauthorized(String),
+    /// The uploader does not know the shared secret.
+    Unauthenticated,
+    /// The uploader and the receiver do not support a common version of
+    /// the protocol or a required capability.
+    Incompatible(String),
+    /// The naming template is not valid.
+    InvalidTemplate(String),
+    /// The file already exists and the collision policy rejects it.
+    Exists(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Listen(err) => write!(f, "failed to listen for connections: {}", err),
+            Error::Protocol(msg) => write!(f, "protocol violation: {}", msg),
+            Error::Busy(file_name) => write!(f, "file already being received: {}", file_name),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Verification => write!(f, "received file does not match the original"),
+            Error::Cancelled => write!(f, "transfer cancelled"),
+            Error::Tls(msg) => write!(f, "TLS error: {}", msg),
+            Error::Unauthorized(msg) => write!(f, "upload not authorized: {}", msg),
+            Error::Unauthenticated => write!(f, "authentication failed"),
+            Error::Incompatible(msg) => write!(f, "incompatible protocol: {}", msg),
+            Error::InvalidTemplate(msg) => write!(f, "invalid naming template: {}", msg),
+            Error::Exists(file_name) => write!(f, "file already exists: {}", file_name),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Listen(err) | Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<file_protocol::Error> for Error {
+    fn from(err: file_protocol::Error) -> Error {
+        match err {
+            file_protocol::Error::Invalid(msg) => Error::Protocol(msg),
+            file_protocol::Error::Incompatible(msg) => Error::Incompatible(msg),
+        }
+    }
+}
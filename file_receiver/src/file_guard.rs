@@ -0,0 +1,34 @@
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::sync::{Arc, Mutex};
+
+use crate::error::{Error, Result};
+
+/// Paths of the files being written by the connections of a receiver.
+pub type FilesInProgress = Arc<Mutex<HashSet<OsString>>>;
+
+/// Prevents a file from being written by more than one connection at
+/// the same time. The file is released when the guard is dropped.
+pub struct FileGuard {
+    files: FilesInProgress,
+    file_path: OsString,
+}
+
+impl FileGuard {
+    pub fn acquire(files: &FilesInProgress, file_path: &OsStr) -> Result<FileGuard> {
+        if !files.lock().unwrap().insert(file_path.to_owned()) {
+            return Err(Error::Busy(file_path.to_string_lossy().into_owned()));
+        }
+
+        Ok(FileGuard {
+            files: files.clone(),
+            file_path: file_path.to_owned(),
+        })
+    }
+}
+
+impl Drop for FileGuard {
+    fn drop(&mut self) {
+        self.files.lock().unwrap().remove(&self.file_path);
+    }
+}
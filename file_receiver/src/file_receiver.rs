@@ -1,16 +1,66 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::File;
 use std::io::{self, prelude::*, SeekFrom};
-use std::net::{TcpListener, TcpStream};
-use std::path::Path;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::thread;
 use std::time::Duration;
 
-const BUF_SIZE: usize = 1024;
-const MAX_BYTES_NOT_ACKNOWLEDGED: u64 = 1024 * 1024;
-const POLLING_TIME: Duration = Duration::from_millis(200);
+use file_protocol::{
+    Attributes, BlockCopy, ChunkHeader, ChunkKind, ChunkQuery, Compression, ExtendedAttributes,
+    FileKind, FlowControl, Header, Hello, Response, StoredChunk, Stripe, BLOCK_COPY_SIZE,
+    CAP_ATTRIBUTES, CAP_AUTH, CAP_DEDUP, CAP_DELTA, CAP_GZIP, CAP_LINKS, CAP_LZ4, CAP_MULTI_FILE,
+    CAP_STRIPES, CAP_WINDOW, CAP_XATTRS, CAP_ZSTD, CHUNK_CHECKSUM_SIZE,
+    COMPRESSED_CHUNK_HEADER_SIZE, DEFAULT_ACK_INTERVAL, DIGEST_SIZE, FLOW_CONTROL_SIZE, HELLO_SIZE,
+    MAC_SIZE, MAX_CHUNK_SIZE, MAX_FILE_NAME_SIZE, STORED_CHUNK_SIZE, VERIFICATION_FAILED,
+    VERIFICATION_OK,
+};
+use sha2::{Digest, Sha256};
 
-#[derive(PartialEq)]
-enum Command {
+use crate::auth;
+use crate::basis::Basis;
+use crate::chunk_store::ChunkStore;
+use crate::clients::Client;
+use crate::durability::Durability;
+use crate::error::{Error, Result};
+use crate::file_guard::{FileGuard, FilesInProgress};
+use crate::naming::NamingPolicy;
+use crate::part_file::PartFile;
+use crate::progress::Progress;
+use crate::protocol::{self, ChunkFormat, FileMetadataDecoder};
+use crate::tls::{Connection, TlsConfig};
+
+pub(crate) const POLLING_TIME: Duration = Duration::from_millis(200);
+/// Time an uploader may leave the connection idle, e.g. before sending
+/// its `Hello` or between two chunks, before it is dropped.
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Capabilities the receiver supports whatever its configuration.
+pub(crate) const SUPPORTED_CAPABILITIES: u32 = CAP_MULTI_FILE
+    | CAP_ATTRIBUTES
+    | CAP_ZSTD
+    | CAP_LZ4
+    | CAP_GZIP
+    | CAP_DELTA
+    | CAP_STRIPES
+    | CAP_WINDOW
+    | PLATFORM_CAPABILITIES;
+
+/// Links can only be created on Unix, and extended attributes are only
+/// set on Linux.
+#[cfg(target_os = "linux")]
+const PLATFORM_CAPABILITIES: u32 = CAP_LINKS | CAP_XATTRS;
+#[cfg(all(unix, not(target_os = "linux")))]
+const PLATFORM_CAPABILITIES: u32 = CAP_LINKS;
+#[cfg(not(unix))]
+const PLATFORM_CAPABILITIES: u32 = 0;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
     Run = 0,
     Stop = 1,
     StopNow = 2,
@@ -29,49 +79,200 @@ impl From<usize> for Command {
 
 pub struct FileReceiver {
     port: u16,
+    max_connections: usize,
+    tls: Option<TlsConfig>,
+    secret: Option<Vec<u8>>,
+    output_dir: PathBuf,
+    naming: NamingPolicy,
+    durability: Durability,
+    ack_interval: u32,
+    timeout: Duration,
+    chunk_store: Option<ChunkStore>,
     command: AtomicUsize,
+    next_connection_id: AtomicUsize,
+    connections: Mutex<HashMap<usize, TcpStream>>,
+    files_in_progress: FilesInProgress,
 }
 
 impl FileReceiver {
     pub fn new(port: u16) -> FileReceiver {
         FileReceiver {
             port,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            tls: None,
+            secret: None,
+            output_dir: PathBuf::new(),
+            naming: NamingPolicy::default(),
+            durability: Durability::default(),
+            ack_interval: DEFAULT_ACK_INTERVAL,
+            timeout: DEFAULT_TIMEOUT,
+            chunk_store: None,
             command: AtomicUsize::new(Command::Stop as usize),
+            next_connection_id: AtomicUsize::new(0),
+            connections: Mutex::new(HashMap::new()),
+            files_in_progress: FilesInProgress::default(),
         }
     }
 
-    pub fn start(&self) {
+    /// Sets the maximum number of uploads handled in parallel. Further
+    /// connections wait in the listen backlog until a transfer finishes.
+    pub fn with_max_connections(mut self, max_connections: usize) -> FileReceiver {
+        self.max_connections = cmp::max(max_connections, 1);
+        self
+    }
+
+    /// Receives over TLS only, authenticating with the certificate in
+    /// `tls`.
+    pub fn with_tls(mut self, tls: TlsConfig) -> FileReceiver {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Only accepts the uploaders that prove they know `secret`.
+    pub fn with_secret(mut self, secret: Vec<u8>) -> FileReceiver {
+        self.secret = Some(secret);
+        self
+    }
+
+    /// Stores the received files inside `output_dir` instead of the
+    /// working directory.
+    pub fn with_output_dir(mut self, output_dir: impl Into<PathBuf>) -> FileReceiver {
+        self.output_dir = output_dir.into();
+        self
+    }
+
+    /// Sets the names the received files are stored under, which are
+    /// the original names with the `.received` suffix by default.
+    pub fn with_naming(mut self, naming: NamingPolicy) -> FileReceiver {
+        self.naming = naming;
+        self
+    }
+
+    /// Sets how often the received data is synced to disk, which is
+    /// before every acknowledgement by default.
+    pub fn with_durability(mut self, durability: Durability) -> FileReceiver {
+        self.durability = durability;
+        self
+    }
+
+    /// Acknowledges the data received at least every `ack_interval`
+    /// bytes, even for the uploaders that ask for less frequent
+    /// acknowledgements, which bounds the data they send again after a
+    /// reconnection.
+    pub fn with_ack_interval(mut self, ack_interval: u32) -> FileReceiver {
+        self.ack_interval = ack_interval;
+        self
+    }
+
+    /// Drops the connections that stay idle for longer than `timeout`,
+    /// so that uploaders that stop responding do not hold on to one of
+    /// the connections handled in parallel.
+    pub fn with_timeout(mut self, timeout: Duration) -> FileReceiver {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Keeps the content-defined chunks of the files received in a store
+    /// inside `dir`, so that the uploaders that support it only send the
+    /// chunks of their files that are missing from it.
+    pub fn with_chunk_store(mut self, dir: impl Into<PathBuf>) -> FileReceiver {
+        self.chunk_store = Some(ChunkStore::new(dir));
+        self
+    }
+
+    /// Accepts upload requests until `stop` or `stop_now` is called,
+    /// handling each one of them in its own thread. Returns after all
+    /// the transfers in progress have finished. Only failing to listen
+    /// is fatal, as a connection that can not be accepted is dropped.
+    pub fn start(&self) -> Result<()> {
         println!("Listening for file upload requests in port: {}", self.port);
 
         let addr = format!("127.0.0.1:{}", self.port);
-        let listener = TcpListener::bind(addr).expect("Failed to initiate server");
-        listener
-            .set_nonblocking(true)
-            .expect("Failed to non-blocking");
+        let listener = TcpListener::bind(addr).map_err(Error::Listen)?;
+        listener.set_nonblocking(true).map_err(Error::Listen)?;
         self.set_command(Command::Run);
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(s) => self.handle_connection(s),
-                Err(err) => match err.kind() {
-                    io::ErrorKind::WouldBlock => {
-                        if self.get_command() != Command::Run {
-                            break;
+        thread::scope(|scope| {
+            while self.get_command() == Command::Run {
+                if self.active_connections() >= self.max_connections {
+                    thread::sleep(POLLING_TIME);
+                    continue;
+                }
+
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let id = match self.register_connection(&stream) {
+                            Ok(id) => id,
+                            Err(err) => {
+                                eprintln!("Dropping connection: {}", err);
+                                continue;
+                            }
+                        };
+                        scope.spawn(move || {
+                            if let Err(err) = self.handle_connection(stream) {
+                                eprintln!("Dropping connection: {}", err);
+                            }
+                            self.unregister_connection(id);
+                        });
+                    }
+                    Err(err) => {
+                        // Errors such as running out of file descriptors
+                        // go away once other connections are closed.
+                        if err.kind() != io::ErrorKind::WouldBlock {
+                            eprintln!("Failed to accept connection: {}", err);
                         }
                         thread::sleep(POLLING_TIME);
                     }
-                    _ => panic!("Encountered IO error: {}", err),
-                },
+                }
             }
-        }
+        });
+
+        Ok(())
     }
 
+    /// Stops accepting new connections, letting the transfers in
+    /// progress run until completion.
     pub fn stop(&self) {
         self.set_command(Command::Stop);
     }
 
+    /// Stops accepting new connections and interrupts all the transfers
+    /// in progress. These can be resumed later on by the uploaders.
     pub fn stop_now(&self) {
         self.set_command(Command::StopNow);
+
+        // Wake up the transfers blocked waiting for data.
+        for stream in self.connections.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn active_connections(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    fn register_connection(&self, stream: &TcpStream) -> Result<usize> {
+        // Accepted sockets may inherit the non-blocking mode of the
+        // listener on some platforms.
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let mut connections = self.connections.lock().unwrap();
+        connections.insert(id, stream.try_clone()?);
+
+        // Closes the race with a `stop_now` call that happened while
+        // the connection was being accepted.
+        if self.get_command() == Command::StopNow {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+
+        Ok(id)
+    }
+
+    fn unregister_connection(&self, id: usize) {
+        self.connections.lock().unwrap().remove(&id);
     }
 
     fn get_command(&self) -> Command {
@@ -82,86 +283,521 @@ impl FileReceiver {
         self.command.store(command as usize, Ordering::Relaxed);
     }
 
-    fn handle_connection(&self, mut stream: TcpStream) {
-        println!("Handling new request from: {}", stream.peer_addr().unwrap());
+    fn handle_connection(&self, stream: TcpStream) -> Result<()> {
+        let result = self.receive_files(stream);
+
+        // Interrupting a transfer causes all sorts of I/O errors.
+        match result {
+            Err(_) if self.get_command() == Command::StopNow => Err(Error::Cancelled),
+            _ => result,
+        }
+    }
+
+    fn receive_files(&self, stream: TcpStream) -> Result<()> {
+        println!("Handling new request from: {}", stream.peer_addr()?);
 
-        let mut u8_buf = [0u8; 1];
-        let mut u64_buf = [0u8; 8];
+        let (mut stream, client) = match &self.tls {
+            Some(tls) => {
+                let stream = tls.accept(stream)?;
+                let client = tls.identify(stream.peer_certificates())?;
+                (stream, client)
+            }
+            None => (Connection::Plain(stream), Client::anonymous()),
+        };
 
-        stream
-            .read_exact(&mut u8_buf)
-            .expect("Failed to read file name length");
-        let file_name_len = u8::from_be_bytes(u8_buf);
+        if !client.is_anonymous() {
+            println!("Client authenticated as: {}", client.name());
+        }
 
-        let mut file_name_buf = vec![0u8; file_name_len as usize];
-        stream
-            .read_exact(&mut file_name_buf)
-            .expect("Failed to read file_name");
-        let file_name =
-            String::from_utf8(file_name_buf).expect("Failed to construct file name string");
+        let negotiated = self.negotiate(&mut stream);
+        let (capabilities, flow_control) = self.reject_on_error(&mut stream, negotiated)?;
+        let mut authenticated = false;
 
-        stream
-            .read_exact(&mut u64_buf)
-            .expect("Failed to read file size");
-        let file_size = u64::from_be_bytes(u64_buf);
+        // Uploaders that support it send several files over the session,
+        // which ends when they close the connection. The session is also
+        // ended between two files when the receiver is being stopped.
+        loop {
+            let header = match read_header(&mut stream) {
+                Ok(Some(header)) => header,
+                Ok(None) => return Ok(()),
+                Err(err) => return self.reject_on_error(&mut stream, Err(err)),
+            };
+
+            self.receive_file(
+                &mut stream,
+                &client,
+                capabilities,
+                flow_control,
+                header,
+                &mut authenticated,
+            )?;
+
+            if capabilities & CAP_MULTI_FILE == 0 || self.get_command() != Command::Run {
+                return Ok(());
+            }
+        }
+    }
+
+    fn receive_file(
+        &self,
+        stream: &mut Connection,
+        client: &Client,
+        capabilities: u32,
+        flow_control: Option<FlowControl>,
+        header: Header,
+        authenticated: &mut bool,
+    ) -> Result<()> {
+        let admitted = self.admit(stream, client, capabilities, header, authenticated);
+        let (part, file_path, _guard) = self.reject_on_error(stream, admitted)?;
+        let file_size = part.file_size();
+        let (start, _) = part.range();
+
+        let (mut file, offset) = part.open()?;
+
+        // A file that replaces a copy held by the receiver is sent as a
+        // delta against it, when the uploader asked for it. Striped
+        // files are always sent whole.
+        let mut basis = None;
+        if capabilities & CAP_DELTA != 0 && !part.is_link() && !part.is_striped() {
+            basis = Basis::<File>::open(&file_path)?;
+        }
+        if let Some(basis) = &mut basis {
+            self.send_response(stream, Response::Signatures(basis.signatures()?))?;
+        }
 
-        stream
-            .read_exact(&mut u64_buf)
-            .expect("Failed to read file offset");
-        let offset = u64::from_be_bytes(u64_buf);
+        self.send_response(stream, Response::Accept(offset))?;
 
         println!(
             "Receiving file: {} (size={}, offset={})",
-            file_name, file_size, offset
+            file_path.to_string_lossy(),
+            file_size,
+            offset
         );
 
-        let file_name = file_name + ".received";
+        // The digest must cover the whole file, or the whole range of a
+        // striped file, including the data stored by a previous
+        // connection.
+        let mut hasher = Sha256::new();
+        file.seek(SeekFrom::Start(start))?;
+        io::copy(&mut (&file).take(offset - start), &mut hasher)?;
+
+        let mut progress = Progress::new(&part, offset, flow_control, self.durability);
+
+        // Other files are deduplicated against the chunk store, when the
+        // uploader asked for it.
+        let client_store = self
+            .chunk_store
+            .as_ref()
+            .map(|store| store.for_client(client));
+        let store = match &client_store {
+            Some(store)
+                if capabilities & CAP_DEDUP != 0
+                    && basis.is_none()
+                    && !part.is_link()
+                    && !part.is_striped() =>
+            {
+                Some(store)
+            }
+            _ => None,
+        };
+
+        let mut format = ChunkFormat {
+            compression: Compression::negotiated(capabilities),
+            basis,
+            store,
+        };
+        let result = self.receive_chunks(
+            stream,
+            &part,
+            &mut file,
+            &mut format,
+            &mut progress,
+            &mut hasher,
+        );
+        if result.is_err() {
+            progress.discard_incomplete(&file)?;
+        }
+        result?;
 
-        let mut file = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(Path::new(&file_name).file_name().unwrap())
-            .expect("Failed to open file");
+        let complete = self.verify(stream, client, &hasher.finalize(), &part, &file, &file_path)?;
 
-        file.seek(SeekFrom::Start(offset)).expect("Failed to seek");
+        // The file is complete, so failing to store its chunks only
+        // spares less data to the uploads that follow.
+        if let (Some(store), true, false) = (&client_store, complete, part.is_link()) {
+            if let Err(err) = store.insert_file(&mut file, self.durability) {
+                eprintln!("WARNING: failed to store the chunks of the file: {}", err);
+            }
+        }
 
-        let mut bytes_received: u64 = 0;
-        let mut bytes_not_acknowledged: u64 = 0;
-        let mut buf = [0u8; BUF_SIZE];
+        Ok(())
+    }
+
+    /// Tells the uploader why its request failed, as retrying would not
+    /// help. A file that is busy is released soon though, so the
+    /// connection is just closed for the uploader to retry.
+    fn reject_on_error<T>(&self, stream: &mut Connection, result: Result<T>) -> Result<T> {
+        match result {
+            Err(err @ Error::Busy(_)) => Err(err),
+            Err(err) => {
+                let _ = self.send_response(stream, Response::Error(err.to_string()));
+                Err(err)
+            }
+            Ok(value) => Ok(value),
+        }
+    }
+
+    /// Agrees with the uploader on the version of the protocol, on the
+    /// capabilities to use and, for the uploaders that bound the data
+    /// they send without it being acknowledged, on the interval between
+    /// acknowledgements, returning the capabilities and flow control.
+    fn negotiate(&self, stream: &mut Connection) -> Result<(u32, Option<FlowControl>)> {
+        let mut hello_buf = [0u8; HELLO_SIZE];
+        stream.read_exact(&mut hello_buf)?;
+
+        let required = self.required_capabilities();
+        let (version, capabilities) = Hello::decode(&hello_buf)?
+            .negotiate(required | self.supported_capabilities(), required)?;
+        self.send_response(stream, Response::Hello(version, capabilities))?;
+
+        let mut flow_control = None;
+        if capabilities & CAP_WINDOW != 0 {
+            let mut flow_control_buf = [0u8; FLOW_CONTROL_SIZE];
+            stream.read_exact(&mut flow_control_buf)?;
+
+            let agreed = FlowControl::decode(&flow_control_buf)?.agree(self.ack_interval);
+            self.send_response(stream, Response::AckInterval(agreed.ack_interval))?;
+            flow_control = Some(agreed);
+        }
 
-        while self.get_command() != Command::StopNow
-            && match stream.read(&mut buf) {
-                Ok(0) => {
-                    println!("File transfer completed");
-                    false
+        Ok((capabilities, flow_control))
+    }
+
+    /// Authenticates the uploader when a shared secret is set, unless it
+    /// did so for a previous file of the session, and checks that the
+    /// client is allowed to upload the file, returning the part file the
+    /// data is written to, the path the file is stored at once complete
+    /// and the guard that keeps other connections from claiming that
+    /// path.
+    ///
+    /// The ranges of a striped file are received over several
+    /// connections, so the name of the file is only claimed once all of
+    /// them are complete. The path of the file under its original name
+    /// is returned instead.
+    fn admit(
+        &self,
+        stream: &mut Connection,
+        client: &Client,
+        capabilities: u32,
+        header: Header,
+        authenticated: &mut bool,
+    ) -> Result<(PartFile, PathBuf, Option<FileGuard>)> {
+        let metadata = read_file_metadata(stream, capabilities)?;
+
+        if let (Some(secret), false) = (&self.secret, *authenticated) {
+            let nonce = auth::new_nonce()?;
+            self.send_response(stream, Response::Challenge(nonce))?;
+
+            let mut mac = [0u8; MAC_SIZE];
+            stream.read_exact(&mut mac)?;
+            auth::verify(secret, &nonce, &mac)?;
+            *authenticated = true;
+        }
+
+        claim_file(
+            &self.output_dir,
+            &self.naming,
+            &self.files_in_progress,
+            client,
+            capabilities,
+            header,
+            metadata,
+        )
+    }
+
+    /// Capabilities the uploaders must support.
+    fn required_capabilities(&self) -> u32 {
+        if self.secret.is_some() {
+            CAP_AUTH
+        } else {
+            0
+        }
+    }
+
+    /// Capabilities the receiver supports with its configuration.
+    fn supported_capabilities(&self) -> u32 {
+        if self.chunk_store.is_some() {
+            SUPPORTED_CAPABILITIES | CAP_DEDUP
+        } else {
+            SUPPORTED_CAPABILITIES
+        }
+    }
+
+    fn receive_chunks(
+        &self,
+        stream: &mut Connection,
+        part: &PartFile,
+        file: &mut File,
+        format: &mut ChunkFormat<'_, File>,
+        progress: &mut Progress,
+        hasher: &mut Sha256,
+    ) -> Result<()> {
+        let file_size = part.file_size();
+        let mut buf = [0u8; MAX_CHUNK_SIZE];
+
+        while !progress.is_complete() {
+            if self.get_command() == Command::StopNow {
+                return Err(Error::Cancelled);
+            }
+
+            let (chunk, checksum) = self.read_chunk(stream, &mut buf, file_size, format)?;
+
+            let data = protocol::chunk_data(&buf, &chunk, format.compression);
+            let data = match progress.check_data(&chunk, data, &checksum)? {
+                Some(data) => data,
+                None => {
+                    self.send_response(stream, Response::Nack(chunk.offset, chunk.length))?;
+                    continue;
                 }
-                Ok(size) => {
-                    file.write_all(&buf[..size])
-                        .expect("Failed to write to file");
+            };
+
+            file.seek(SeekFrom::Start(chunk.offset))?;
+            file.write_all(&data)?;
 
-                    bytes_received += size as u64;
-                    bytes_not_acknowledged += size as u64;
+            if let Some((start, end)) = progress.mark_stored(&chunk, &data, hasher) {
+                file.seek(SeekFrom::Start(start))?;
+                io::copy(&mut (&*file).take(end - start), hasher)?;
+            }
 
-                    if bytes_not_acknowledged >= MAX_BYTES_NOT_ACKNOWLEDGED
-                        || (offset + bytes_received) == file_size
-                    {
-                        file.flush().expect("Failed to flush the file");
+            if progress.must_sync() {
+                file.sync_data()?;
+                progress.mark_synced();
+            }
 
-                        match stream.write(&(bytes_received + offset).to_be_bytes()) {
-                            Err(err) => {
-                                eprintln!("WARNING: failed to send acknowledgedment: {}", err)
-                            }
-                            Ok(_) => bytes_not_acknowledged = 0,
-                        }
-                    }
+            if let Some(offset) = progress.acknowledge() {
+                part.record_received(offset, self.durability)?;
+                self.send_response(stream, Response::Ack(offset))?;
+            }
+        }
+
+        Ok(())
+    }
 
-                    true
+    /// Reads a chunk of the file into `buf` as it was sent, possibly
+    /// compressed, returning it along with the checksum of its data. A
+    /// corrupted chunk payload is detected by the caller. The data of
+    /// the blocks copied by a delta is read from the basis instead, and
+    /// the data of the chunks of a deduplicated file the receiver holds
+    /// from the chunk store, after answering the queries about them.
+    fn read_chunk(
+        &self,
+        stream: &mut Connection,
+        buf: &mut [u8],
+        file_size: u64,
+        format: &mut ChunkFormat<'_, File>,
+    ) -> Result<(ChunkHeader, [u8; CHUNK_CHECKSUM_SIZE])> {
+        while format.has_chunk_kinds() {
+            let mut kind = [0u8; 1];
+            stream.read_exact(&mut kind)?;
+
+            match (format.chunk_kind(kind[0])?, &mut format.basis, format.store) {
+                (ChunkKind::Copy, Some(basis), _) => {
+                    let mut copy_buf = [0u8; BLOCK_COPY_SIZE];
+                    stream.read_exact(&mut copy_buf)?;
+                    let copy = BlockCopy::decode(&copy_buf, basis.block_size(), file_size)?;
+
+                    let length = basis.read_block(copy.block, buf)?;
+                    return Ok(protocol::local_chunk(copy.offset, &buf[..length]));
                 }
-                Err(err) => {
-                    eprintln!("Error reading from stream: {}", err);
-                    true
+                (ChunkKind::Query, _, Some(store)) => {
+                    let query = read_query(stream)?;
+                    self.send_response(stream, Response::Missing(store.missing(&query)))?;
                 }
+                (ChunkKind::Stored, _, Some(store)) => {
+                    let mut chunk_buf = [0u8; STORED_CHUNK_SIZE];
+                    stream.read_exact(&mut chunk_buf)?;
+                    let chunk = StoredChunk::decode(&chunk_buf, file_size)?;
+
+                    let length = store.read(&chunk.hash, buf)?;
+                    protocol::check_stored_length(&chunk, length)?;
+                    return Ok(protocol::local_chunk(chunk.offset, &buf[..length]));
+                }
+                (ChunkKind::Data, ..) => break,
+                _ => unreachable!("chunk kind checked against the format"),
             }
-        {}
+        }
+
+        let mut header = [0u8; COMPRESSED_CHUNK_HEADER_SIZE];
+        let header = &mut header[..format.header_size()];
+        stream.read_exact(header)?;
+        let chunk = format.decode_header(header, file_size)?;
+
+        stream.read_exact(&mut buf[..chunk.data_size()])?;
+
+        let mut checksum = [0u8; CHUNK_CHECKSUM_SIZE];
+        stream.read_exact(&mut checksum)?;
+
+        Ok((chunk, checksum))
     }
+
+    fn send_response(&self, stream: &mut Connection, response: Response) -> Result<()> {
+        stream.write_all(&response.encode())?;
+        Ok(())
+    }
+
+    /// Reads the digest of the original file from the stream, compares
+    /// it against the digest of the received file and reports back the
+    /// result. A file that matches is moved to `file_path` before its
+    /// reception is confirmed, while one that does not is discarded.
+    ///
+    /// The digest of a range of a striped file covers only the range,
+    /// and the file is moved once all of its ranges are verified, under
+    /// the name claimed for `file_path` then. Returns whether the file
+    /// is complete.
+    fn verify(
+        &self,
+        stream: &mut Connection,
+        client: &Client,
+        digest: &[u8],
+        part: &PartFile,
+        file: &File,
+        file_path: &Path,
+    ) -> Result<bool> {
+        let mut digest_buf = [0u8; DIGEST_SIZE];
+        stream.read_exact(&mut digest_buf)?;
+
+        if digest_buf[..] != digest[..] {
+            part.discard()?;
+            self.send_response(stream, Response::Verification(VERIFICATION_FAILED))?;
+            return Err(Error::Verification);
+        }
+
+        let complete = if part.is_striped() {
+            part.complete_range(file, || {
+                let (directory, file_name) = protocol::split_path(file_path);
+                self.naming
+                    .claim(directory, client, file_name, &self.files_in_progress)
+            })?
+        } else {
+            part.finalize(file, file_path)?;
+            true
+        };
+
+        self.send_response(stream, Response::Verification(VERIFICATION_OK))?;
+        if complete {
+            println!("File transfer completed");
+        } else {
+            println!("Range of the file completed");
+        }
+
+        Ok(complete)
+    }
+}
+
+/// Reads the messages that follow the header as agreed for the session,
+/// which carry the kind and the attributes of the file, and the range of
+/// it that is sent.
+fn read_file_metadata(
+    stream: &mut Connection,
+    capabilities: u32,
+) -> Result<(FileKind, Attributes, ExtendedAttributes, Stripe)> {
+    let mut decoder = FileMetadataDecoder::new(capabilities);
+
+    while let Some(size) = decoder.next_size()? {
+        let mut buf = vec![0u8; size];
+        stream.read_exact(&mut buf)?;
+        decoder.feed(&buf)?;
+    }
+
+    Ok(decoder.finish())
+}
+
+/// Checks that `client` is allowed to upload the file described by
+/// `header` and `metadata`, and claims its part file and the path it is
+/// stored at, as `FileReceiver::admit` returns them.
+pub(crate) fn claim_file(
+    output_dir: &Path,
+    naming: &NamingPolicy,
+    files_in_progress: &FilesInProgress,
+    client: &Client,
+    capabilities: u32,
+    header: Header,
+    metadata: (FileKind, Attributes, ExtendedAttributes, Stripe),
+) -> Result<(PartFile, PathBuf, Option<FileGuard>)> {
+    let Header {
+        file_name,
+        file_size,
+    } = header;
+    let (kind, attributes, xattrs, stripe) = metadata;
+
+    client.authorize(file_size)?;
+
+    if kind == FileKind::Link && file_size > MAX_FILE_NAME_SIZE as u64 {
+        return Err(Error::Protocol(format!(
+            "link target too long: {} bytes",
+            file_size
+        )));
+    }
+
+    if kind == FileKind::Link && !stripe.is_whole() {
+        return Err(Error::Protocol("links can not be striped".to_string()));
+    }
+
+    // The files of a directory are stored under their path relative to
+    // it, which older uploaders do not send.
+    let (directory, file_name) = if capabilities & CAP_MULTI_FILE != 0 {
+        protocol::relative_path(file_name)?
+    } else {
+        (PathBuf::new(), protocol::original_file_name(file_name)?)
+    };
+    let root = output_dir.join(client.directory());
+    protocol::check_no_links(&root, &directory)?;
+    let directory = root.join(directory);
+
+    let part = PartFile::claim(&directory, &file_name, file_size, stripe, files_in_progress)?
+        .with_kind(kind)
+        .with_attributes(attributes)
+        .with_xattrs(xattrs);
+
+    if part.is_striped() {
+        return Ok((part, directory.join(file_name), None));
+    }
+
+    let (file_path, guard) = naming.claim(&directory, client, &file_name, files_in_progress)?;
+
+    Ok((part, file_path, Some(guard)))
+}
+
+/// Reads the query of the uploader about the chunks missing from the
+/// chunk store.
+fn read_query(stream: &mut Connection) -> Result<ChunkQuery> {
+    let mut prefix = [0u8; ChunkQuery::PREFIX_SIZE];
+    stream.read_exact(&mut prefix)?;
+
+    let mut buf = vec![0u8; ChunkQuery::size(&prefix)?];
+    buf[..ChunkQuery::PREFIX_SIZE].copy_from_slice(&prefix);
+    stream.read_exact(&mut buf[ChunkQuery::PREFIX_SIZE..])?;
+
+    Ok(ChunkQuery::decode(&buf)?)
+}
+
+/// Reads the header of the next file, or returns `None` if the uploader
+/// ended the session instead.
+fn read_header(stream: &mut Connection) -> Result<Option<Header>> {
+    let mut prefix = [0u8; Header::PREFIX_SIZE];
+    loop {
+        match stream.read(&mut prefix[..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) if protocol::is_session_closed(&err) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+    }
+    stream.read_exact(&mut prefix[1..])?;
+
+    let mut buf = vec![0u8; Header::size(&prefix)?];
+    buf[..Header::PREFIX_SIZE].copy_from_slice(&prefix);
+    stream.read_exact(&mut buf[Header::PREFIX_SIZE..])?;
+
+    Ok(Some(Header::decode(&buf)?))
 }
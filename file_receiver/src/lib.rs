@@ -1,3 +1,28 @@
+#[cfg(feature = "tokio")]
+mod async_file_receiver;
+mod attributes;
+mod auth;
+mod basis;
+mod chunk_store;
+mod clients;
+mod durability;
+mod error;
+mod file_guard;
 mod file_receiver;
+mod naming;
+mod part_file;
+mod progress;
+mod protocol;
+#[cfg(feature = "tokio")]
+mod timeout;
+mod tls;
+mod xattrs;
 
+#[cfg(feature = "tokio")]
+pub use crate::async_file_receiver::AsyncFileReceiver;
+pub use crate::clients::Client;
+pub use crate::durability::Durability;
+pub use crate::error::{Error, Result};
 pub use crate::file_receiver::FileReceiver;
+pub use crate::naming::{Collision, NamingPolicy};
+pub use crate::tls::TlsConfig;
@@ -1,16 +1,189 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
 use structopt::StructOpt;
 
-use file_receiver::FileReceiver;
+use file_protocol::read_secret;
+
+use file_receiver::{
+    Client, Collision, Durability, Error, FileReceiver, NamingPolicy, Result, TlsConfig,
+};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "filereceiver", about = "Receives a file")]
 struct Cli {
     port: u16,
+
+    #[structopt(long)]
+    max_connections: Option<usize>,
+
+    /// Directory the received files are stored at, instead of the working directory
+    #[structopt(long, parse(from_os_str))]
+    output_dir: Option<PathBuf>,
+
+    /// Stores the received files under their original name
+    #[structopt(long, conflicts_with = "name-template")]
+    keep_name: bool,
+
+    /// Name the received files are stored under, made of the original
+    /// {name}, the {client} that uploaded it and the {timestamp} of the upload
+    #[structopt(long)]
+    name_template: Option<String>,
+
+    /// What to do when the file already exists: overwrite, rename or reject
+    #[structopt(long, default_value = "overwrite")]
+    on_collision: Collision,
+
+    /// When the received data is synced to disk: before every
+    /// acknowledgement (every-ack), every given number of bytes, or never
+    /// (none), in which case an acknowledged upload may not survive a crash
+    #[structopt(long, default_value = "every-ack")]
+    durability: Durability,
+
+    /// Acknowledges the data received at least every given number of
+    /// bytes, 1 MiB by default
+    #[structopt(long)]
+    ack_interval: Option<u32>,
+
+    /// Number of seconds a connection may stay idle before it is dropped,
+    /// 60 by default
+    #[structopt(long)]
+    timeout: Option<u64>,
+
+    /// Directory to keep the content-defined chunks of the received files
+    /// at, so that uploaders send only the chunks missing from it
+    #[structopt(long, parse(from_os_str))]
+    chunk_store: Option<PathBuf>,
+
+    /// Receives over TLS, authenticating with the certificate chain in this PEM file
+    #[structopt(long, parse(from_os_str), requires = "key")]
+    cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the certificate
+    #[structopt(long, parse(from_os_str), requires = "cert")]
+    key: Option<PathBuf>,
+
+    /// Requires the uploaders to authenticate with a certificate issued by
+    /// the certificate authorities in this PEM file
+    #[structopt(long, parse(from_os_str), requires_all = &["cert", "clients"])]
+    client_ca: Option<PathBuf>,
+
+    /// File listing the clients allowed to upload, one per line, as the
+    /// common name of their certificate followed by the directory their
+    /// files are stored at and, optionally, the maximum file size
+    #[structopt(long, parse(from_os_str), requires = "client-ca")]
+    clients: Option<PathBuf>,
+
+    /// Requires the uploaders to prove the knowledge of the secret in this
+    /// file, which is read from the FILE_UPLOAD_SECRET environment variable
+    /// if not given
+    #[structopt(long, parse(from_os_str))]
+    secret_file: Option<PathBuf>,
 }
 
 fn main() {
     let args = Cli::from_args();
 
-    let receiver = FileReceiver::new(args.port);
-    receiver.start();
+    let mut receiver = FileReceiver::new(args.port);
+
+    if let Some(max_connections) = args.max_connections {
+        receiver = receiver.with_max_connections(max_connections);
+    }
+
+    if let Some(output_dir) = &args.output_dir {
+        receiver = receiver.with_output_dir(output_dir);
+    }
+
+    receiver = receiver.with_durability(args.durability);
+
+    if let Some(ack_interval) = args.ack_interval {
+        receiver = receiver.with_ack_interval(ack_interval);
+    }
+
+    if let Some(timeout) = args.timeout {
+        receiver = receiver.with_timeout(Duration::from_secs(timeout));
+    }
+
+    if let Some(chunk_store) = &args.chunk_store {
+        receiver = receiver.with_chunk_store(chunk_store);
+    }
+
+    match naming_policy(&args) {
+        Ok(naming) => receiver = receiver.with_naming(naming),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+    }
+
+    match tls_config(&args) {
+        Ok(Some(tls)) => receiver = receiver.with_tls(tls),
+        Ok(None) => {}
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+    }
+
+    match read_secret(args.secret_file.as_deref()) {
+        Ok(Some(secret)) => receiver = receiver.with_secret(secret),
+        Ok(None) => {}
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+    }
+
+    if let Err(err) = receiver.start() {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+}
+
+fn naming_policy(args: &Cli) -> Result<NamingPolicy> {
+    let naming = match &args.name_template {
+        Some(template) => NamingPolicy::new(template)?,
+        None if args.keep_name => NamingPolicy::original_name(),
+        None => NamingPolicy::default(),
+    };
+
+    Ok(naming.with_collision(args.on_collision))
+}
+
+fn tls_config(args: &Cli) -> Result<Option<TlsConfig>> {
+    let mut tls = match (&args.cert, &args.key) {
+        (Some(cert), Some(key)) => TlsConfig::from_pem_files(cert, key)?,
+        _ => return Ok(None),
+    };
+
+    if let (Some(client_ca), Some(clients)) = (&args.client_ca, &args.clients) {
+        tls = tls.with_client_auth(client_ca, read_clients(clients)?)?;
+    }
+
+    Ok(Some(tls))
+}
+
+fn read_clients(path: &Path) -> Result<Vec<Client>> {
+    let invalid =
+        |line: &str| Error::Tls(format!("invalid client in {}: {}", path.display(), line));
+
+    let mut clients = Vec::new();
+
+    for line in fs::read_to_string(path)?.lines() {
+        let fields: Vec<_> = line.split_whitespace().collect();
+
+        let client = match fields[..] {
+            [] => continue,
+            [name, directory] => Client::new(name.to_string(), directory),
+            [name, directory, max_file_size] => Client::new(name.to_string(), directory)
+                .with_max_file_size(max_file_size.parse().map_err(|_| invalid(line))?),
+            _ => return Err(invalid(line)),
+        };
+
+        clients.push(client);
+    }
+
+    Ok(clients)
 }
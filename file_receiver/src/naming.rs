@@ -0,0 +1,234 @@
+//! Names the received files are stored under.
+
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::clients::{Client, ANONYMOUS};
+use crate::error::{Error, Result};
+use crate::file_guard::{FileGuard, FilesInProgress};
+
+const DEFAULT_TEMPLATE: &str = "{name}.received";
+
+/// What to do when a file with the name of the one being received
+/// already exists.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Collision {
+    /// Replaces the existing file.
+    Overwrite,
+    /// Appends a counter to the name, as in `name.1`, `name.2` and so on.
+    Rename,
+    /// Rejects the upload.
+    Reject,
+}
+
+impl FromStr for Collision {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Collision, String> {
+        match s {
+            "overwrite" => Ok(Collision::Overwrite),
+            "rename" => Ok(Collision::Rename),
+            "reject" => Ok(Collision::Reject),
+            _ => Err(format!("invalid collision policy: {}", s)),
+        }
+    }
+}
+
+/// Template the names of the received files are made from, which may
+/// contain the `{name}` of the original file without its directory, the
+/// `{client}` that uploaded it and the `{timestamp}` in seconds since
+/// the Unix epoch at which the upload started. A template can contain
+/// slashes to store the files in subdirectories.
+#[derive(Clone, Debug)]
+pub struct NamingPolicy {
+    template: Vec<Token>,
+    collision: Collision,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Literal(String),
+    Name,
+    Client,
+    Timestamp,
+}
+
+impl NamingPolicy {
+    pub fn new(template: &str) -> Result<NamingPolicy> {
+        let policy = NamingPolicy {
+            template: parse(template)?,
+            collision: Collision::Overwrite,
+        };
+
+        // The files must stay inside the output directory whatever the
+        // values of the placeholders.
+        let sample = policy.render(&Client::anonymous(), OsStr::new("file"));
+        let is_relative = Path::new(&sample)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if sample.is_empty() || !is_relative {
+            return Err(Error::InvalidTemplate(format!(
+                "{} (must be a relative path without `.` or `..`)",
+                template
+            )));
+        }
+
+        Ok(policy)
+    }
+
+    /// Stores the files under the name of the original.
+    pub fn original_name() -> NamingPolicy {
+        NamingPolicy::new("{name}").unwrap()
+    }
+
+    pub fn with_collision(mut self, collision: Collision) -> NamingPolicy {
+        self.collision = collision;
+        self
+    }
+
+    /// Returns the path inside `directory` the file is stored at,
+    /// preventing other connections from writing to it until the
+    /// returned guard is dropped.
+    pub(crate) fn claim(
+        &self,
+        directory: &Path,
+        client: &Client,
+        file_name: &OsStr,
+        files_in_progress: &FilesInProgress,
+    ) -> Result<(PathBuf, FileGuard)> {
+        let file_path = directory.join(self.render(client, file_name));
+
+        match self.collision {
+            Collision::Overwrite => {
+                let guard = FileGuard::acquire(files_in_progress, file_path.as_os_str())?;
+                Ok((file_path, guard))
+            }
+            Collision::Reject => {
+                let guard = FileGuard::acquire(files_in_progress, file_path.as_os_str())?;
+                if file_path.exists() {
+                    return Err(Error::Exists(file_path.to_string_lossy().into_owned()));
+                }
+                Ok((file_path, guard))
+            }
+            Collision::Rename => {
+                let mut candidate = file_path.clone();
+                let mut counter = 0;
+
+                loop {
+                    if !candidate.exists() {
+                        match FileGuard::acquire(files_in_progress, candidate.as_os_str()) {
+                            Ok(guard) => return Ok((candidate, guard)),
+                            Err(Error::Busy(_)) => {}
+                            Err(err) => return Err(err),
+                        }
+                    }
+
+                    counter += 1;
+                    let mut renamed = file_path.clone().into_os_string();
+                    renamed.push(format!(".{}", counter));
+                    candidate = PathBuf::from(renamed);
+                }
+            }
+        }
+    }
+
+    fn render(&self, client: &Client, file_name: &OsStr) -> OsString {
+        let mut rendered = OsString::new();
+
+        for token in &self.template {
+            match token {
+                Token::Literal(literal) => rendered.push(literal),
+                Token::Name => rendered.push(file_name),
+                Token::Client if client.is_anonymous() => rendered.push(ANONYMOUS),
+                Token::Client => rendered.push(path_component(client.name())),
+                Token::Timestamp => {
+                    let timestamp = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |duration| duration.as_secs());
+                    rendered.push(timestamp.to_string());
+                }
+            }
+        }
+
+        rendered
+    }
+}
+
+impl Default for NamingPolicy {
+    fn default() -> NamingPolicy {
+        NamingPolicy::new(DEFAULT_TEMPLATE).unwrap()
+    }
+}
+
+fn parse(template: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            tokens.push(Token::Literal(rest[..start].to_string()));
+        }
+
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| Error::InvalidTemplate(format!("unclosed `{{` in {}", template)))?;
+
+        tokens.push(match &rest[start + 1..end] {
+            "name" => Token::Name,
+            "client" => Token::Client,
+            "timestamp" => Token::Timestamp,
+            placeholder => {
+                return Err(Error::InvalidTemplate(format!(
+                    "unknown placeholder `{{{}}}` in {}",
+                    placeholder, template
+                )))
+            }
+        });
+
+        rest = &rest[end + 1..];
+    }
+
+    if !rest.is_empty() {
+        tokens.push(Token::Literal(rest.to_string()));
+    }
+
+    Ok(tokens)
+}
+
+/// Makes `name` safe to be used as a single component of a path.
+fn path_component(name: &str) -> String {
+    match name.replace('/', "_") {
+        name if name == "." || name == ".." => name.replace('.', "_"),
+        name => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        let policy = NamingPolicy::new("{client}/{name}-copy").unwrap();
+        let client = Client::new("alice/..".to_string(), "");
+
+        assert_eq!(
+            policy.render(&client, OsStr::new("report.txt")),
+            OsString::from("alice_../report.txt-copy")
+        );
+        assert_eq!(
+            policy.render(&Client::anonymous(), OsStr::new("report.txt")),
+            OsString::from("anonymous/report.txt-copy")
+        );
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        for template in &["", "/{name}", "../{name}", "{name}/..", "{nam}", "{name"] {
+            assert!(NamingPolicy::new(template).is_err(), "{}", template);
+        }
+    }
+}
@@ -0,0 +1,629 @@
+//! Hidden files the data is written to while being received, so that
+//! only complete files ever show up under their final name.
+
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use file_protocol::{Attributes, ExtendedAttributes, FileKind, Stripe};
+
+use crate::attributes;
+use crate::durability::Durability;
+use crate::error::{Error, Result};
+use crate::file_guard::{FileGuard, FilesInProgress};
+use crate::xattrs;
+
+/// Serializes the updates of the metadata files of striped files, whose
+/// ranges are received over several connections at the same time.
+static STRIPES_LOCK: Mutex<()> = Mutex::new(());
+
+/// Progress of a range of a striped file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RangeState {
+    /// The data of the range before the offset was acknowledged.
+    Received(u64),
+    /// The whole range was received and verified.
+    Verified,
+}
+
+/// Part file of an upload, named after the original file so that an
+/// interrupted upload is resumed whatever name the file ends up with.
+/// Next to it is a metadata file with the size of the file, which tells
+/// apart the part files left by uploads of other files with that name,
+/// and the offset acknowledged to the uploader, which the upload resumes
+/// from as the data past it may not have been written in full.
+///
+/// The part file of a link holds its target, and is replaced by the link
+/// once complete. The copies of a part file share the claim on it.
+///
+/// The ranges of a striped file are written to the same part file by
+/// several connections, each of which holds a claim on its own range.
+/// The metadata file then also holds the number of ranges and the
+/// progress of each of them, as the size of the part file no longer
+/// tells how much of it was received.
+#[derive(Clone)]
+pub struct PartFile {
+    path: PathBuf,
+    metadata_path: PathBuf,
+    file_size: u64,
+    stripe: Stripe,
+    kind: FileKind,
+    attributes: Attributes,
+    xattrs: ExtendedAttributes,
+    _guard: Arc<FileGuard>,
+}
+
+impl PartFile {
+    /// Claims the part file inside `directory` of the file named
+    /// `file_name`, or the `stripe` of it, preventing other connections
+    /// from writing to it.
+    pub fn claim(
+        directory: &Path,
+        file_name: &OsStr,
+        file_size: u64,
+        stripe: Stripe,
+        files_in_progress: &FilesInProgress,
+    ) -> Result<PartFile> {
+        let mut part_name = OsString::from(".");
+        part_name.push(file_name);
+        part_name.push(".part");
+        let path = directory.join(&part_name);
+
+        let mut claimed = path.clone().into_os_string();
+        if !stripe.is_whole() {
+            claimed.push(format!(".{}", stripe.index));
+        }
+
+        part_name.push(".meta");
+        let metadata_path = directory.join(part_name);
+
+        Ok(PartFile {
+            _guard: Arc::new(FileGuard::acquire(files_in_progress, &claimed)?),
+            path,
+            metadata_path,
+            file_size,
+            stripe,
+            kind: FileKind::File,
+            attributes: Attributes::default(),
+            xattrs: ExtendedAttributes::default(),
+        })
+    }
+
+    pub fn with_kind(mut self, kind: FileKind) -> PartFile {
+        self.kind = kind;
+        self
+    }
+
+    /// Sets the attributes applied to the file once it is complete.
+    pub fn with_attributes(mut self, attributes: Attributes) -> PartFile {
+        self.attributes = attributes;
+        self
+    }
+
+    /// Sets the extended attributes applied to the file once it is
+    /// complete.
+    pub fn with_xattrs(mut self, xattrs: ExtendedAttributes) -> PartFile {
+        self.xattrs = xattrs;
+        self
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    pub fn is_link(&self) -> bool {
+        self.kind == FileKind::Link
+    }
+
+    /// Returns whether the part file holds a range of a file that is
+    /// received over several connections.
+    pub fn is_striped(&self) -> bool {
+        !self.stripe.is_whole()
+    }
+
+    /// Returns the offsets the data received by this connection starts
+    /// and ends at.
+    pub fn range(&self) -> (u64, u64) {
+        self.stripe.range(self.file_size)
+    }
+
+    /// First line of the metadata file, which tells the file it belongs
+    /// to.
+    pub fn metadata(&self) -> String {
+        if self.is_striped() {
+            format!("{} {}\n", self.file_size, self.stripe.count)
+        } else {
+            format!("{}\n", self.file_size)
+        }
+    }
+
+    /// Contents of the metadata file of a file that is not striped, of
+    /// which the data before `offset` was acknowledged.
+    fn received_metadata(&self, offset: u64) -> String {
+        format!("{}{}\n", self.metadata(), offset)
+    }
+
+    /// Returns the offset acknowledged by a previous connection, given
+    /// the `metadata` and the `len` of the part file, or `None` if the
+    /// part file must be discarded. The part file must be truncated to
+    /// the offset, as the data past it may be incomplete.
+    fn resume_offset(&self, metadata: Option<&str>, len: u64) -> Option<u64> {
+        let mut lines = metadata?.lines();
+        if lines.next()? != self.metadata().trim_end() {
+            return None;
+        }
+
+        let offset = lines.next()?.parse().ok()?;
+        match lines.next() {
+            None if offset <= len && offset <= self.file_size => Some(offset),
+            _ => None,
+        }
+    }
+
+    /// Opens the part file, returning it along with the number of bytes
+    /// the upload must resume from.
+    pub fn open(&self) -> Result<(File, u64)> {
+        if let Some(directory) = self.path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&self.path)?;
+
+        if self.is_striped() {
+            return self.open_range(file);
+        }
+
+        let metadata = self.read_metadata()?;
+        match self.resume_offset(metadata.as_deref(), file.metadata()?.len()) {
+            Some(offset) => {
+                file.set_len(offset)?;
+                Ok((file, offset))
+            }
+            None => {
+                file.set_len(0)?;
+                self.write_metadata(self.received_metadata(0), true)?;
+                Ok((file, 0))
+            }
+        }
+    }
+
+    /// Opens the range of a striped file, returning the offset recorded
+    /// for it. The part file is created at its full size, as the ranges
+    /// are written in any order.
+    fn open_range(&self, file: File) -> Result<(File, u64)> {
+        let _lock = STRIPES_LOCK.lock().unwrap();
+
+        let ranges = match self.read_ranges()? {
+            Some(ranges) => ranges,
+            None => {
+                file.set_len(0)?;
+                file.set_len(self.file_size)?;
+
+                let ranges: Vec<_> = (0..self.stripe.count)
+                    .map(|index| {
+                        let (start, _) =
+                            Stripe::new(index, self.stripe.count).range(self.file_size);
+                        RangeState::Received(start)
+                    })
+                    .collect();
+                self.write_ranges(&ranges, true)?;
+                ranges
+            }
+        };
+
+        let offset = match ranges[self.stripe.index as usize] {
+            RangeState::Received(offset) => offset,
+            RangeState::Verified => self.range().1,
+        };
+        Ok((file, offset))
+    }
+
+    /// Records that the data of the file, or of the range of a striped
+    /// file, before `offset` was acknowledged, so that the upload is
+    /// resumed from there. The record is synced to disk unless
+    /// `durability` is `None`, so that it survives a crash along with
+    /// the data acknowledged.
+    pub fn record_received(&self, offset: u64, durability: Durability) -> Result<()> {
+        let sync = durability != Durability::None;
+        if self.is_striped() {
+            let _lock = STRIPES_LOCK.lock().unwrap();
+            self.update_range(RangeState::Received(offset), sync)?;
+            return Ok(());
+        }
+        self.write_metadata(self.received_metadata(offset), sync)
+    }
+
+    /// Records that the range of a striped file was verified, and once
+    /// all of its ranges are, moves the file to the path returned by
+    /// `claim` as `finalize` does. Returns whether the file is complete.
+    pub fn complete_range(
+        &self,
+        file: &File,
+        claim: impl FnOnce() -> Result<(PathBuf, FileGuard)>,
+    ) -> Result<bool> {
+        let _lock = STRIPES_LOCK.lock().unwrap();
+
+        if !self
+            .update_range(RangeState::Verified, true)?
+            .iter()
+            .all(|&state| state == RangeState::Verified)
+        {
+            return Ok(false);
+        }
+
+        let (file_path, _guard) = claim()?;
+        self.finalize(file, &file_path)?;
+        Ok(true)
+    }
+
+    fn read_metadata(&self) -> Result<Option<String>> {
+        match fs::read_to_string(&self.metadata_path) {
+            Ok(metadata) => Ok(Some(metadata)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Returns the progress of the ranges of a striped file, or `None` if
+    /// the part file is not one of an upload of this file with as many
+    /// ranges.
+    fn read_ranges(&self) -> Result<Option<Vec<RangeState>>> {
+        Ok(self
+            .read_metadata()?
+            .and_then(|metadata| self.parse_ranges(&metadata)))
+    }
+
+    fn parse_ranges(&self, metadata: &str) -> Option<Vec<RangeState>> {
+        let mut lines = metadata.lines();
+        if lines.next()? != self.metadata().trim_end() {
+            return None;
+        }
+
+        let ranges = lines
+            .enumerate()
+            .map(|(index, line)| {
+                let (start, end) =
+                    Stripe::new(index as u32, self.stripe.count).range(self.file_size);
+                match line {
+                    "verified" => Some(RangeState::Verified),
+                    offset => match offset.parse() {
+                        Ok(offset) if offset >= start && offset <= end => {
+                            Some(RangeState::Received(offset))
+                        }
+                        _ => None,
+                    },
+                }
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(ranges).filter(|ranges| ranges.len() == self.stripe.count as usize)
+    }
+
+    /// Writes the metadata file of a striped file.
+    fn write_ranges(&self, ranges: &[RangeState], sync: bool) -> Result<()> {
+        let mut metadata = self.metadata();
+        for state in ranges {
+            match state {
+                RangeState::Received(offset) => metadata.push_str(&format!("{}\n", offset)),
+                RangeState::Verified => metadata.push_str("verified\n"),
+            }
+        }
+        self.write_metadata(metadata, sync)
+    }
+
+    /// Writes the metadata file, replacing the previous one at once so
+    /// that a crash does not lose the progress recorded in it. When
+    /// `sync` is set, the new file is synced before it replaces the
+    /// previous one, and the directory after, so that the metadata file
+    /// is neither empty nor stale after a power loss.
+    fn write_metadata(&self, metadata: String, sync: bool) -> Result<()> {
+        let mut temp_path = self.metadata_path.clone().into_os_string();
+        temp_path.push(".tmp");
+
+        let mut temp_file = File::create(&temp_path)?;
+        temp_file.write_all(metadata.as_bytes())?;
+        if sync {
+            temp_file.sync_all()?;
+        }
+        fs::rename(&temp_path, &self.metadata_path)?;
+
+        if let (true, Some(directory)) = (sync, self.metadata_path.parent()) {
+            sync_dir(directory)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the progress of the range of a striped file, returning the
+    /// progress of all of its ranges. Must be called with the lock held.
+    fn update_range(&self, state: RangeState, sync: bool) -> Result<Vec<RangeState>> {
+        let mut ranges = self.read_ranges()?.ok_or_else(|| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "part file replaced by another upload: {}",
+                    self.path.display()
+                ),
+            ))
+        })?;
+
+        ranges[self.stripe.index as usize] = state;
+        self.write_ranges(&ranges, sync)?;
+
+        Ok(ranges)
+    }
+
+    /// Applies the attributes of the file, makes the received data
+    /// durable and moves it to `file_path`.
+    pub fn finalize(&self, file: &File, file_path: &Path) -> Result<()> {
+        if self.kind == FileKind::Link {
+            return self.finalize_link(file_path);
+        }
+
+        self.apply_attributes(file)?;
+        file.sync_all()?;
+
+        if let Some(directory) = file_path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        fs::rename(&self.path, file_path)?;
+        fs::remove_file(&self.metadata_path)?;
+
+        if let Some(directory) = file_path.parent() {
+            sync_dir(directory)?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies the attributes and extended attributes of the file.
+    fn apply_attributes(&self, file: &File) -> Result<()> {
+        attributes::apply(file, &self.attributes)?;
+        xattrs::apply(file, &self.xattrs)?;
+        Ok(())
+    }
+
+    /// Creates the link whose target the part file holds at `file_path`,
+    /// replacing the part file. The link is created next to the part
+    /// file and then renamed, so that it shows up complete.
+    #[cfg(unix)]
+    fn finalize_link(&self, file_path: &Path) -> Result<()> {
+        use std::os::unix::ffi::OsStringExt;
+
+        let target = OsString::from_vec(fs::read(&self.path)?);
+
+        let mut link_path = self.path.clone().into_os_string();
+        link_path.push(".link");
+        let link_path = PathBuf::from(link_path);
+
+        match fs::remove_file(&link_path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+        std::os::unix::fs::symlink(target, &link_path)?;
+        attributes::apply_to_link(&link_path, &self.attributes)?;
+        xattrs::apply_to_link(&link_path, &self.xattrs)?;
+
+        if let Some(directory) = file_path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        fs::rename(&link_path, file_path)?;
+        fs::remove_file(&self.path)?;
+        fs::remove_file(&self.metadata_path)?;
+
+        if let Some(directory) = file_path.parent() {
+            sync_dir(directory)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn finalize_link(&self, _file_path: &Path) -> Result<()> {
+        Err(crate::error::Error::Protocol(
+            "links are not supported".to_string(),
+        ))
+    }
+
+    /// Removes the part file, so that a future upload does not resume
+    /// from corrupted data. Only the range of a striped file is received
+    /// again, as the other ranges were verified on their own.
+    pub fn discard(&self) -> Result<()> {
+        if self.is_striped() {
+            let _lock = STRIPES_LOCK.lock().unwrap();
+            self.update_range(RangeState::Received(self.range().0), true)?;
+            return Ok(());
+        }
+
+        fs::remove_file(&self.path)?;
+        fs::remove_file(&self.metadata_path)?;
+        Ok(())
+    }
+}
+
+/// The asynchronous counterparts run the synchronous methods in a thread
+/// where blocking is allowed.
+#[cfg(feature = "tokio")]
+impl PartFile {
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&PartFile) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let part = self.clone();
+        tokio::task::spawn_blocking(move || f(&part))
+            .await
+            .expect("part file task panicked")
+    }
+
+    /// Asynchronous counterpart of `PartFile::open`.
+    pub async fn open_async(&self) -> Result<(tokio::fs::File, u64)> {
+        let (file, offset) = self.blocking(PartFile::open).await?;
+        Ok((tokio::fs::File::from_std(file), offset))
+    }
+
+    /// Asynchronous counterpart of `PartFile::record_received`.
+    pub async fn record_received_async(&self, offset: u64, durability: Durability) -> Result<()> {
+        self.blocking(move |part| part.record_received(offset, durability))
+            .await
+    }
+
+    /// Asynchronous counterpart of `PartFile::complete_range`.
+    pub async fn complete_range_async(
+        &self,
+        file: &tokio::fs::File,
+        claim: impl FnOnce() -> Result<(PathBuf, FileGuard)> + Send + 'static,
+    ) -> Result<bool> {
+        let file = file.try_clone().await?.into_std().await;
+        self.blocking(move |part| part.complete_range(&file, claim))
+            .await
+    }
+
+    /// Asynchronous counterpart of `PartFile::finalize`.
+    pub async fn finalize_async(&self, file: &tokio::fs::File, file_path: &Path) -> Result<()> {
+        let file = file.try_clone().await?.into_std().await;
+        let file_path = file_path.to_path_buf();
+        self.blocking(move |part| part.finalize(&file, &file_path))
+            .await
+    }
+
+    /// Asynchronous counterpart of `PartFile::discard`.
+    pub async fn discard_async(&self) -> Result<()> {
+        self.blocking(PartFile::discard).await
+    }
+}
+
+/// Makes the creation of the entries of `directory` durable.
+#[cfg(unix)]
+pub fn sync_dir(directory: &Path) -> io::Result<()> {
+    let directory = match directory {
+        directory if directory.as_os_str().is_empty() => Path::new("."),
+        directory => directory,
+    };
+    File::open(directory)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub fn sync_dir(_directory: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume_offset() {
+        let files_in_progress = FilesInProgress::default();
+        let part = PartFile::claim(
+            Path::new("dir"),
+            OsStr::new("file"),
+            100,
+            Stripe::whole(),
+            &files_in_progress,
+        )
+        .unwrap();
+
+        assert_eq!(part.path, Path::new("dir/.file.part"));
+        assert_eq!(part.metadata_path, Path::new("dir/.file.part.meta"));
+
+        assert_eq!(part.received_metadata(30), "100\n30\n");
+
+        // Data written past the offset acknowledged is not trusted.
+        assert_eq!(part.resume_offset(Some("100\n30\n"), 40), Some(30));
+        assert_eq!(part.resume_offset(Some("100\n100\n"), 100), Some(100));
+        assert_eq!(part.resume_offset(Some("100\n30\n"), 20), None);
+        assert_eq!(part.resume_offset(Some("100\n101\n"), 101), None);
+        assert_eq!(part.resume_offset(Some("100\n"), 40), None);
+        assert_eq!(part.resume_offset(Some("200\n30\n"), 40), None);
+        assert_eq!(part.resume_offset(None, 40), None);
+    }
+
+    #[test]
+    fn test_resume_from_offset_recorded() {
+        let dir = std::env::temp_dir().join(format!("part-file-test-{}", std::process::id()));
+        let files_in_progress = FilesInProgress::default();
+        let claim = || {
+            PartFile::claim(
+                &dir,
+                OsStr::new("file"),
+                100,
+                Stripe::whole(),
+                &files_in_progress,
+            )
+            .unwrap()
+        };
+
+        let part = claim();
+        let (mut file, offset) = part.open().unwrap();
+        assert_eq!(offset, 0);
+        file.write_all(&[1; 60]).unwrap();
+        part.record_received(40, Durability::EveryAck).unwrap();
+        drop(file);
+        drop(part);
+
+        // The offset acknowledged is on disk, and the data written past
+        // it is dropped when the upload is resumed.
+        assert_eq!(
+            fs::read_to_string(dir.join(".file.part.meta")).unwrap(),
+            "100\n40\n"
+        );
+        assert!(!dir.join(".file.part.meta.tmp").exists());
+        let part = claim();
+        let (file, offset) = part.open().unwrap();
+        assert_eq!(offset, 40);
+        assert_eq!(file.metadata().unwrap().len(), 40);
+
+        part.discard().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_range_states() {
+        let files_in_progress = FilesInProgress::default();
+        let part = PartFile::claim(
+            Path::new("dir"),
+            OsStr::new("file"),
+            100,
+            Stripe::new(1, 3),
+            &files_in_progress,
+        )
+        .unwrap();
+
+        // The other ranges of the file can be claimed, but not this one.
+        let claim = |index| {
+            PartFile::claim(
+                Path::new("dir"),
+                OsStr::new("file"),
+                100,
+                Stripe::new(index, 3),
+                &files_in_progress,
+            )
+        };
+        assert!(claim(0).is_ok());
+        assert!(claim(1).is_err());
+
+        assert_eq!(part.range(), (33, 66));
+        assert_eq!(
+            part.parse_ranges("100 3\n10\nverified\n66\n"),
+            Some(vec![
+                RangeState::Received(10),
+                RangeState::Verified,
+                RangeState::Received(66)
+            ])
+        );
+
+        // Offsets outside of their range, a different number of ranges
+        // and the metadata of a file that is not striped.
+        assert_eq!(part.parse_ranges("100 3\n10\n20\n66\n"), None);
+        assert_eq!(part.parse_ranges("100 3\n0\n33\n"), None);
+        assert_eq!(part.parse_ranges("100 2\n0\n50\n"), None);
+        assert_eq!(part.parse_ranges("100\n"), None);
+    }
+}
@@ -0,0 +1,229 @@
+use std::borrow::Cow;
+use std::cmp;
+use std::fs::File;
+use std::io;
+
+use file_protocol::{
+    chunk_checksum, ChunkHeader, FlowControl, CHUNK_CHECKSUM_SIZE, DEFAULT_ACK_INTERVAL,
+};
+use sha2::{Digest, Sha256};
+
+use crate::durability::Durability;
+use crate::error::{Error, Result};
+use crate::part_file::PartFile;
+
+/// Keeps track of the parts of the file that were stored, hashed, synced
+/// and acknowledged. Chunks that arrived corrupted leave gaps in the file
+/// until they are retransmitted.
+///
+/// The progress does no I/O, which is left to the synchronous and
+/// asynchronous receivers, so that both acknowledge the data alike.
+pub struct Progress {
+    bytes_received: u64,
+    /// Offset the data received by this connection ends at, which is
+    /// the end of the range for the ranges of striped files.
+    end: u64,
+    /// Number of bytes after which the data received is acknowledged.
+    ack_interval: u64,
+    durability: Durability,
+    bytes_hashed: u64,
+    bytes_synced: u64,
+    bytes_acknowledged: u64,
+    bytes_not_acknowledged: u64,
+    missing: Vec<(u64, u32)>,
+    striped: bool,
+}
+
+impl Progress {
+    /// Progress of the data of `part` received from `offset`, within the
+    /// `flow_control` agreed on for the session, if any, and synced to
+    /// disk as `durability` says.
+    pub fn new(
+        part: &PartFile,
+        offset: u64,
+        flow_control: Option<FlowControl>,
+        durability: Durability,
+    ) -> Progress {
+        let ack_interval = flow_control.map_or(DEFAULT_ACK_INTERVAL, |flow_control| {
+            flow_control.ack_interval
+        });
+        let durability = match flow_control {
+            Some(flow_control) => durability.within_window(flow_control.window as u64),
+            None => durability,
+        };
+
+        Progress {
+            bytes_received: offset,
+            end: part.range().1,
+            ack_interval: ack_interval as u64,
+            durability,
+            bytes_hashed: offset,
+            bytes_synced: offset,
+            bytes_acknowledged: offset,
+            bytes_not_acknowledged: 0,
+            missing: Vec::new(),
+            striped: part.is_striped(),
+        }
+    }
+
+    /// Number of bytes from the start of the file stored without gaps.
+    pub fn contiguous_bytes(&self) -> u64 {
+        self.missing
+            .iter()
+            .map(|&(offset, _)| offset)
+            .min()
+            .unwrap_or(self.bytes_received)
+    }
+
+    /// Returns whether all the data this connection receives is stored.
+    pub fn is_complete(&self) -> bool {
+        self.contiguous_bytes() == self.end
+    }
+
+    /// Checks that `chunk` is either the chunk that follows the data
+    /// received or the retransmission of a missing one, and returns its
+    /// `data` if it matches `checksum`. A corrupted chunk is recorded as
+    /// missing instead, and `None` returned for it to be retransmitted.
+    pub fn check_data<'d>(
+        &mut self,
+        chunk: &ChunkHeader,
+        data: Option<Cow<'d, [u8]>>,
+        checksum: &[u8; CHUNK_CHECKSUM_SIZE],
+    ) -> Result<Option<Cow<'d, [u8]>>> {
+        self.check_chunk(chunk)?;
+
+        match data {
+            Some(data) if chunk_checksum(&data) == *checksum => Ok(Some(data)),
+            _ => {
+                eprintln!(
+                    "WARNING: corrupted chunk (offset={}, length={})",
+                    chunk.offset, chunk.length
+                );
+                self.mark_missing(chunk.offset, chunk.length);
+                Ok(None)
+            }
+        }
+    }
+
+    fn check_chunk(&self, chunk: &ChunkHeader) -> Result<()> {
+        let is_retransmission = self.is_missing(chunk.offset, chunk.length);
+
+        if (!is_retransmission && chunk.offset != self.bytes_received)
+            || chunk.offset + chunk.length as u64 > self.end
+        {
+            return Err(Error::Protocol(format!(
+                "unexpected chunk offset: {}",
+                chunk.offset
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn is_missing(&self, offset: u64, length: u32) -> bool {
+        self.missing.contains(&(offset, length))
+    }
+
+    fn mark_missing(&mut self, offset: u64, length: u32) {
+        if !self.is_missing(offset, length) {
+            self.missing.push((offset, length));
+            self.bytes_received = offset + length as u64;
+        }
+    }
+
+    /// Records that the `data` of `chunk` was stored. The digest is
+    /// computed over the data stored without gaps, so the data is fed
+    /// into `hasher` only when it follows the data hashed so far. Returns
+    /// the range of the file that must then be fed into it as well, which
+    /// was stored after a corrupted chunk that `chunk` is the
+    /// retransmission of.
+    pub fn mark_stored(
+        &mut self,
+        chunk: &ChunkHeader,
+        data: &[u8],
+        hasher: &mut Sha256,
+    ) -> Option<(u64, u64)> {
+        self.missing
+            .retain(|&range| range != (chunk.offset, chunk.length));
+        self.bytes_received = cmp::max(self.bytes_received, chunk.offset + chunk.length as u64);
+        self.bytes_not_acknowledged += chunk.length as u64;
+
+        if chunk.offset == self.bytes_hashed {
+            hasher.update(data);
+            self.bytes_hashed += chunk.length as u64;
+        }
+
+        let contiguous_bytes = self.contiguous_bytes();
+        if self.bytes_hashed < contiguous_bytes {
+            let range = (self.bytes_hashed, contiguous_bytes);
+            self.bytes_hashed = contiguous_bytes;
+            return Some(range);
+        }
+
+        None
+    }
+
+    /// Returns whether enough data was stored since the last
+    /// acknowledgement for the next one to be sent.
+    pub fn is_ack_due(&self) -> bool {
+        let contiguous_bytes = self.contiguous_bytes();
+        contiguous_bytes > self.bytes_acknowledged
+            && (self.bytes_not_acknowledged >= self.ack_interval || contiguous_bytes == self.end)
+    }
+
+    /// Returns whether the data must be synced to disk before the next
+    /// acknowledgement is sent, so that an upload resumed after a crash
+    /// does not skip it.
+    pub fn must_sync(&self) -> bool {
+        self.is_ack_due()
+            && self
+                .durability
+                .must_sync(self.bytes_synced, self.contiguous_bytes(), self.end)
+    }
+
+    pub fn mark_synced(&mut self) {
+        self.bytes_synced = self.contiguous_bytes();
+    }
+
+    /// Returns the offset to acknowledge, if an acknowledgement is due
+    /// and more data is durable than was acknowledged already. The
+    /// offset must be recorded with `PartFile::record_received` before
+    /// the acknowledgement is sent.
+    pub fn acknowledge(&mut self) -> Option<u64> {
+        if !self.is_ack_due() {
+            return None;
+        }
+
+        let offset = self
+            .durability
+            .acknowledged_offset(self.bytes_synced, self.contiguous_bytes());
+        if offset <= self.bytes_acknowledged {
+            return None;
+        }
+
+        self.bytes_acknowledged = offset;
+        self.bytes_not_acknowledged = 0;
+        Some(offset)
+    }
+
+    /// Returns whether data was stored after a chunk that is missing.
+    fn has_gaps(&self) -> bool {
+        !self.missing.is_empty()
+    }
+
+    /// Returns whether the data stored after the first gap must be
+    /// discarded when the transfer fails, which is not the case for
+    /// striped files as they resume from the offset acknowledged.
+    pub fn must_discard_incomplete(&self) -> bool {
+        self.has_gaps() && !self.striped
+    }
+
+    /// Discards the data stored after the first gap, so that the size
+    /// of the file is the offset to resume the upload from.
+    pub fn discard_incomplete(&self, file: &File) -> io::Result<()> {
+        if self.must_discard_incomplete() {
+            file.set_len(self.contiguous_bytes())?;
+        }
+        Ok(())
+    }
+}
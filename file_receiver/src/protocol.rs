@@ -0,0 +1,374 @@
+//! Parts of the protocol that are specific to the receiver.
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use file_protocol::{
+    chunk_checksum, Attributes, ChunkHeader, ChunkKind, Compression, ExtendedAttributes, FileKind,
+    StoredChunk, Stripe, ATTRIBUTES_SIZE, CAP_ATTRIBUTES, CAP_LINKS, CAP_STRIPES, CAP_XATTRS,
+    CHUNK_CHECKSUM_SIZE, CHUNK_HEADER_SIZE, COMPRESSED_CHUNK_HEADER_SIZE, STRIPE_SIZE,
+};
+
+use crate::basis::Basis;
+use crate::chunk_store::ChunkStore;
+use crate::error::{Error, Result};
+
+/// How the chunks of a file are sent, as agreed on for the session and
+/// for the file.
+pub struct ChunkFormat<'a, F> {
+    pub compression: Option<Compression>,
+    /// Copy of the file the chunks refer to, when the file is sent as a
+    /// delta.
+    pub basis: Option<Basis<F>>,
+    /// Store of the chunks the chunks refer to, when the file is
+    /// deduplicated.
+    pub store: Option<&'a ChunkStore>,
+}
+
+impl<F> ChunkFormat<'_, F> {
+    /// Returns whether each chunk is preceded by its kind.
+    pub fn has_chunk_kinds(&self) -> bool {
+        self.basis.is_some() || self.store.is_some()
+    }
+
+    /// Decodes the kind of the next chunk, rejecting the kinds that were
+    /// not agreed on for the file.
+    pub fn chunk_kind(&self, kind: u8) -> Result<ChunkKind> {
+        match ChunkKind::decode(kind)? {
+            ChunkKind::Data => Ok(ChunkKind::Data),
+            ChunkKind::Copy if self.basis.is_some() => Ok(ChunkKind::Copy),
+            kind @ (ChunkKind::Query | ChunkKind::Stored) if self.store.is_some() => Ok(kind),
+            kind => Err(Error::Protocol(format!(
+                "unexpected chunk kind: {:?}",
+                kind
+            ))),
+        }
+    }
+
+    /// Size of the header of the chunks of data.
+    pub fn header_size(&self) -> usize {
+        if self.compression.is_some() {
+            COMPRESSED_CHUNK_HEADER_SIZE
+        } else {
+            CHUNK_HEADER_SIZE
+        }
+    }
+
+    /// Decodes the header of a chunk of data, of `header_size` bytes.
+    pub fn decode_header(&self, buf: &[u8], file_size: u64) -> Result<ChunkHeader> {
+        let header = if self.compression.is_some() {
+            ChunkHeader::decode_compressed(sized(buf)?, file_size)?
+        } else {
+            ChunkHeader::decode(sized(buf)?, file_size)?
+        };
+        Ok(header)
+    }
+}
+
+/// Returns the header and the checksum of the chunk of `data` the
+/// receiver already holds, which is stored at `offset` as if it was sent.
+pub fn local_chunk(offset: u64, data: &[u8]) -> (ChunkHeader, [u8; CHUNK_CHECKSUM_SIZE]) {
+    (ChunkHeader::new(offset, data.len()), chunk_checksum(data))
+}
+
+/// Messages that follow the header of a file, as agreed for the session.
+#[derive(Debug, PartialEq)]
+enum Field {
+    Kind,
+    Attributes,
+    ExtendedAttributesPrefix,
+    ExtendedAttributes([u8; ExtendedAttributes::PREFIX_SIZE]),
+    Stripe,
+}
+
+/// Decodes the messages that follow the header of a file, which carry
+/// the kind and the attributes of the file, and the range of it that is
+/// sent. The decoder does no I/O: the caller reads the number of bytes
+/// returned by `next_size` and passes them to `feed`, until there is
+/// nothing left to read.
+pub struct FileMetadataDecoder {
+    fields: VecDeque<Field>,
+    kind: FileKind,
+    attributes: Attributes,
+    xattrs: ExtendedAttributes,
+    stripe: Stripe,
+}
+
+impl FileMetadataDecoder {
+    pub fn new(capabilities: u32) -> FileMetadataDecoder {
+        let fields = vec![
+            (CAP_LINKS, Field::Kind),
+            (CAP_ATTRIBUTES, Field::Attributes),
+            (CAP_XATTRS, Field::ExtendedAttributesPrefix),
+            (CAP_STRIPES, Field::Stripe),
+        ]
+        .into_iter()
+        .filter(|(capability, _)| capabilities & capability != 0)
+        .map(|(_, field)| field)
+        .collect();
+
+        FileMetadataDecoder {
+            fields,
+            kind: FileKind::File,
+            attributes: Attributes::default(),
+            xattrs: ExtendedAttributes::default(),
+            stripe: Stripe::whole(),
+        }
+    }
+
+    /// Returns the number of bytes to read next, or `None` once all the
+    /// messages were decoded.
+    pub fn next_size(&self) -> Result<Option<usize>> {
+        let size = match self.fields.front() {
+            Some(Field::Kind) => 1,
+            Some(Field::Attributes) => ATTRIBUTES_SIZE,
+            Some(Field::ExtendedAttributesPrefix) => ExtendedAttributes::PREFIX_SIZE,
+            Some(Field::ExtendedAttributes(prefix)) => {
+                ExtendedAttributes::size(prefix)? - ExtendedAttributes::PREFIX_SIZE
+            }
+            Some(Field::Stripe) => STRIPE_SIZE,
+            None => return Ok(None),
+        };
+        Ok(Some(size))
+    }
+
+    /// Decodes the `next_size` bytes read.
+    pub fn feed(&mut self, buf: &[u8]) -> Result<()> {
+        match self.fields.pop_front() {
+            Some(Field::Kind) => self.kind = FileKind::decode(sized::<1>(buf)?[0])?,
+            Some(Field::Attributes) => self.attributes = Attributes::decode(sized(buf)?)?,
+            Some(Field::ExtendedAttributesPrefix) => self
+                .fields
+                .push_front(Field::ExtendedAttributes(*sized(buf)?)),
+            Some(Field::ExtendedAttributes(prefix)) => {
+                let mut xattrs_buf = prefix.to_vec();
+                xattrs_buf.extend_from_slice(buf);
+                self.xattrs = ExtendedAttributes::decode(&xattrs_buf)?;
+            }
+            Some(Field::Stripe) => self.stripe = Stripe::decode(sized(buf)?)?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> (FileKind, Attributes, ExtendedAttributes, Stripe) {
+        (self.kind, self.attributes, self.xattrs, self.stripe)
+    }
+}
+
+/// Returns `buf` as the fixed size message it was read as.
+fn sized<const N: usize>(buf: &[u8]) -> Result<&[u8; N]> {
+    buf.try_into()
+        .map_err(|_| Error::Protocol(format!("invalid message size: {}", buf.len())))
+}
+
+/// Returns the name of the file sent by the uploader without its
+/// directory. The name is used as is on Unix, where it does not need to
+/// be valid UTF-8.
+pub fn original_file_name(file_name: Vec<u8>) -> Result<OsString> {
+    let file_name = os_string(file_name)?;
+
+    let base_name = Path::new(&file_name).file_name().ok_or_else(|| {
+        Error::Protocol(format!(
+            "invalid file name: {}",
+            file_name.to_string_lossy()
+        ))
+    })?;
+
+    Ok(base_name.to_owned())
+}
+
+/// Splits the path of a file relative to the directory being uploaded
+/// into its directory and its name, rejecting the paths that could lead
+/// outside of the directory the file is stored at.
+pub fn relative_path(file_name: Vec<u8>) -> Result<(PathBuf, OsString)> {
+    let file_name = os_string(file_name)?;
+    let path = Path::new(&file_name);
+
+    let is_relative = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+
+    match (path.parent(), path.file_name()) {
+        (Some(directory), Some(base_name)) if is_relative => {
+            Ok((directory.to_owned(), base_name.to_owned()))
+        }
+        _ => Err(Error::Protocol(format!(
+            "invalid file name: {}",
+            file_name.to_string_lossy()
+        ))),
+    }
+}
+
+/// Splits the path of a file into its directory and its name.
+pub fn split_path(path: &Path) -> (&Path, &OsStr) {
+    (
+        path.parent().unwrap_or_else(|| Path::new("")),
+        path.file_name().unwrap_or_default(),
+    )
+}
+
+/// Rejects the `directory` of a file relative to `root` when it goes
+/// through a symbolic link, as the links uploaded by a client could
+/// otherwise lead the files that follow outside of `root`.
+pub fn check_no_links(root: &Path, directory: &Path) -> Result<()> {
+    let mut path = root.to_owned();
+
+    for component in directory.components() {
+        path.push(component);
+
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(Error::Protocol(format!(
+                    "path goes through a link: {}",
+                    path.display()
+                )))
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the data of a chunk read into `buf`, decompressing it when it
+/// was sent compressed. Data that does not decompress to the length of
+/// the chunk is reported as `None`, to be handled like corrupted data.
+pub fn chunk_data<'a>(
+    buf: &'a [u8],
+    chunk: &ChunkHeader,
+    compression: Option<Compression>,
+) -> Option<Cow<'a, [u8]>> {
+    let data = &buf[..chunk.data_size()];
+
+    match (compression, chunk.compressed_length) {
+        (Some(compression), Some(_)) => compression
+            .decompress(data, chunk.length as usize)
+            .ok()
+            .filter(|data| data.len() == chunk.length as usize)
+            .map(Cow::Owned),
+        _ => Some(Cow::Borrowed(data)),
+    }
+}
+
+/// Checks that the chunk read from the store has the length the uploader
+/// announced, which the progress of the transfer relies on.
+pub fn check_stored_length(chunk: &StoredChunk, length: usize) -> Result<()> {
+    if length != chunk.length as usize {
+        return Err(Error::Protocol(format!(
+            "invalid stored chunk length: {} (stored={})",
+            chunk.length, length
+        )));
+    }
+
+    Ok(())
+}
+
+/// Returns whether `err` means that the uploader closed the connection.
+pub fn is_session_closed(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+#[cfg(unix)]
+fn os_string(file_name: Vec<u8>) -> Result<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(OsString::from_vec(file_name))
+}
+
+#[cfg(not(unix))]
+fn os_string(file_name: Vec<u8>) -> Result<OsString> {
+    String::from_utf8(file_name)
+        .map(OsString::from)
+        .map_err(|_| Error::Protocol("file name is not valid UTF-8".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(
+            relative_path(b"photos/2020/beach.jpg".to_vec()).unwrap(),
+            (PathBuf::from("photos/2020"), OsString::from("beach.jpg"))
+        );
+        assert_eq!(
+            relative_path(b"beach.jpg".to_vec()).unwrap(),
+            (PathBuf::new(), OsString::from("beach.jpg"))
+        );
+
+        for file_name in &[
+            "",
+            "/etc/passwd",
+            "../beach.jpg",
+            "photos/../../beach.jpg",
+            "./a",
+        ] {
+            assert!(
+                relative_path(file_name.as_bytes().to_vec()).is_err(),
+                "{}",
+                file_name
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_file_metadata() {
+        let xattrs = ExtendedAttributes {
+            entries: vec![(b"user.comment".to_vec(), b"beach".to_vec())],
+        };
+        let mut sent = vec![FileKind::Link.encode()];
+        sent.extend_from_slice(&xattrs.encode().unwrap());
+        sent.extend_from_slice(&Stripe::new(1, 3).encode());
+
+        let mut decoder = FileMetadataDecoder::new(CAP_LINKS | CAP_XATTRS | CAP_STRIPES);
+        let mut rest = &sent[..];
+        while let Some(size) = decoder.next_size().unwrap() {
+            decoder.feed(&rest[..size]).unwrap();
+            rest = &rest[size..];
+        }
+
+        assert!(rest.is_empty());
+        assert_eq!(
+            decoder.finish(),
+            (
+                FileKind::Link,
+                Attributes::default(),
+                xattrs,
+                Stripe::new(1, 3)
+            )
+        );
+    }
+
+    #[test]
+    fn test_compressed_chunk_data() {
+        let data = b"abcdabcdabcdabcd".repeat(64);
+        let mut compressed = Vec::new();
+        Compression::Lz4.compress(&data, &mut compressed);
+
+        let chunk = ChunkHeader::new(0, data.len()).with_compressed_length(compressed.len());
+        assert_eq!(
+            chunk_data(&compressed, &chunk, Some(Compression::Lz4)).as_deref(),
+            Some(&data[..])
+        );
+
+        // Data that decompresses to fewer bytes than the chunk holds.
+        let chunk = ChunkHeader::new(0, data.len() + 1).with_compressed_length(compressed.len());
+        assert!(chunk_data(&compressed, &chunk, Some(Compression::Lz4)).is_none());
+
+        let chunk = ChunkHeader::new(0, data.len()).with_compressed_length(compressed.len() - 1);
+        assert!(chunk_data(&compressed, &chunk, Some(Compression::Lz4)).is_none());
+    }
+}
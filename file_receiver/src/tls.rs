@@ -0,0 +1,252 @@
+//! TLS transport for the connections with the uploaders.
+
+use std::collections::HashMap;
+use std::io::{self, prelude::*};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    CertificateError, DigitallySignedStruct, DistinguishedName, RootCertStore, ServerConfig,
+    ServerConnection, SignatureScheme, StreamOwned,
+};
+
+use crate::clients::{Client, ANONYMOUS};
+use crate::error::{Error, Result};
+
+/// Certificate and private key the receiver authenticates itself with
+/// when receiving over TLS, along with the uploaders that are allowed
+/// to connect when client authentication is required.
+#[derive(Clone)]
+pub struct TlsConfig {
+    config: Arc<ServerConfig>,
+    clients: Option<Arc<HashMap<String, Client>>>,
+}
+
+impl TlsConfig {
+    /// Loads the certificate chain and the private key from PEM files.
+    pub fn from_pem_files(
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Result<TlsConfig> {
+        let cert_path = cert_path.as_ref();
+        let key_path = key_path.as_ref();
+
+        let certs = read_certs(cert_path)?;
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .map_err(|err| Error::Tls(format!("{}: {}", key_path.display(), err)))?;
+
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(|err| Error::Tls(err.to_string()))?;
+
+        Ok(TlsConfig {
+            config: Arc::new(config),
+            clients: None,
+        })
+    }
+
+    /// Requires the uploaders to authenticate with a certificate issued
+    /// by one of the certificate authorities in the PEM bundle at
+    /// `ca_path`. Only the `clients` whose name matches the common name
+    /// in the subject of the certificate are allowed to connect. No
+    /// client can be named `anonymous`, which is the name the uploaders
+    /// that do not authenticate go by.
+    pub fn with_client_auth(
+        self,
+        ca_path: impl AsRef<Path>,
+        clients: Vec<Client>,
+    ) -> Result<TlsConfig> {
+        if clients.iter().any(|client| client.name() == ANONYMOUS) {
+            return Err(Error::Tls(format!(
+                "client name reserved for anonymous uploaders: {}",
+                ANONYMOUS
+            )));
+        }
+
+        let mut roots = RootCertStore::empty();
+        let (added, _) = roots.add_parsable_certificates(read_certs(ca_path.as_ref())?);
+
+        if added == 0 {
+            return Err(Error::Tls(format!(
+                "no valid certificates in: {}",
+                ca_path.as_ref().display()
+            )));
+        }
+
+        let clients: HashMap<_, _> = clients
+            .into_iter()
+            .map(|client| (client.name().to_string(), client))
+            .collect();
+        let clients = Arc::new(clients);
+
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider())
+            .build()
+            .map_err(|err| Error::Tls(err.to_string()))?;
+
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|err| Error::Tls(err.to_string()))?
+            .with_client_cert_verifier(Arc::new(KnownClientVerifier {
+                verifier,
+                clients: clients.clone(),
+            }))
+            .with_cert_resolver(self.config.cert_resolver.clone());
+
+        Ok(TlsConfig {
+            config: Arc::new(config),
+            clients: Some(clients),
+        })
+    }
+
+    pub(crate) fn server_config(&self) -> Arc<ServerConfig> {
+        self.config.clone()
+    }
+
+    /// Starts a TLS session over `stream`, performing the handshake.
+    pub(crate) fn accept(&self, stream: TcpStream) -> Result<Connection> {
+        let conn = ServerConnection::new(self.server_config())
+            .map_err(|err| Error::Tls(err.to_string()))?;
+
+        let mut stream = StreamOwned::new(conn, stream);
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock)?;
+        }
+
+        Ok(Connection::Tls(Box::new(stream)))
+    }
+
+    /// Returns the client that authenticated with `peer_certs`, or an
+    /// anonymous client when client authentication is not required.
+    pub(crate) fn identify(&self, peer_certs: Option<&[CertificateDer<'_>]>) -> Result<Client> {
+        let clients = match &self.clients {
+            Some(clients) => clients,
+            None => return Ok(Client::anonymous()),
+        };
+
+        peer_certs
+            .and_then(|certs| certs.first())
+            .and_then(common_name)
+            .and_then(|name| clients.get(&name))
+            .cloned()
+            .ok_or_else(|| Error::Unauthorized("unknown client certificate".to_string()))
+    }
+}
+
+/// Connection with an uploader, which is encrypted when TLS is enabled.
+pub enum Connection {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Connection {
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        match self {
+            Connection::Plain(_) => None,
+            Connection::Tls(stream) => stream.conn.peer_certificates(),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Verifies the certificates of the uploaders, rejecting those that do
+/// not belong to any of the known clients.
+#[derive(Debug)]
+struct KnownClientVerifier {
+    verifier: Arc<dyn ClientCertVerifier>,
+    clients: Arc<HashMap<String, Client>>,
+}
+
+impl ClientCertVerifier for KnownClientVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.verifier.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> std::result::Result<ClientCertVerified, rustls::Error> {
+        let verified = self
+            .verifier
+            .verify_client_cert(end_entity, intermediates, now)?;
+
+        match common_name(end_entity) {
+            Some(name) if self.clients.contains_key(&name) => Ok(verified),
+            _ => Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            )),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+}
+
+/// Returns the common name in the subject of the certificate.
+fn common_name(cert: &CertificateDer<'_>) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
+    let name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(name.to_string())
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect())
+        .map_err(|err| Error::Tls(format!("{}: {}", path.display(), err)))
+}
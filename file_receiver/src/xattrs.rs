@@ -0,0 +1,155 @@
+//! Application of the extended attributes sent by the uploader to the
+//! received files.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use file_protocol::ExtendedAttributes;
+
+/// Names of the extended attributes of the `system` namespace that are
+/// applied, which hold the POSIX ACLs of the file.
+const ACL_NAMES: [&[u8]; 2] = [b"system.posix_acl_access", b"system.posix_acl_default"];
+
+/// Returns whether the extended attribute `name` is applied to the
+/// received files. Only the `user` namespace and the POSIX ACLs are, as
+/// the other namespaces grant capabilities or set security labels that
+/// an uploader must not control, even if the receiver is privileged.
+fn is_allowed(name: &[u8]) -> bool {
+    name.starts_with(b"user.") || ACL_NAMES.contains(&name)
+}
+
+/// Sets the extended attributes of the received `file`. Attributes that
+/// are not allowed by `is_allowed` are dropped, while those that the
+/// receiver is not permitted to set or that the filesystem does not
+/// support are skipped.
+#[cfg(target_os = "linux")]
+pub fn apply(file: &File, xattrs: &ExtendedAttributes) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    apply_with(xattrs, |name, value| unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            name,
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    })
+}
+
+/// Sets the extended attributes of the link at `path` itself, skipping
+/// the same attributes as `apply`.
+#[cfg(target_os = "linux")]
+pub fn apply_to_link(path: &Path, xattrs: &ExtendedAttributes) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+
+    apply_with(xattrs, |name, value| unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name,
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn apply_with(
+    xattrs: &ExtendedAttributes,
+    set: impl Fn(*const libc::c_char, &[u8]) -> libc::c_int,
+) -> io::Result<()> {
+    use std::ffi::CString;
+
+    for (name, value) in xattrs.entries.iter().filter(|(name, _)| is_allowed(name)) {
+        let name = CString::new(name.as_slice())?;
+        if set(name.as_ptr(), value) == 0 {
+            continue;
+        }
+
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EPERM) | Some(libc::EACCES) | Some(libc::ENOTSUP) => {}
+            _ => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply(_file: &File, _xattrs: &ExtendedAttributes) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply_to_link(_path: &Path, _xattrs: &ExtendedAttributes) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::env;
+    use std::ffi::CString;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::*;
+
+    fn get(path: &Path, name: &str) -> Option<Vec<u8>> {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = CString::new(name).unwrap();
+        let mut buf = [0u8; 64];
+
+        let size = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if size < 0 {
+            None
+        } else {
+            Some(buf[..size as usize].to_vec())
+        }
+    }
+
+    #[test]
+    fn test_apply_only_allowed_names() {
+        assert!(is_allowed(b"user.comment"));
+        assert!(is_allowed(b"system.posix_acl_access"));
+        assert!(is_allowed(b"system.posix_acl_default"));
+        assert!(!is_allowed(b"system.nfs4_acl"));
+        assert!(!is_allowed(b"security.capability"));
+        assert!(!is_allowed(b"security.selinux"));
+        assert!(!is_allowed(b"trusted.overlay.opaque"));
+
+        let path = env::temp_dir().join(format!("xattrs-test-{}", std::process::id()));
+        let file = File::create(&path).unwrap();
+
+        let xattrs = ExtendedAttributes {
+            entries: vec![
+                (b"trusted.comment".to_vec(), b"trusted".to_vec()),
+                (b"security.comment".to_vec(), b"security".to_vec()),
+                (b"user.comment".to_vec(), b"user".to_vec()),
+            ],
+        };
+        let result = apply(&file, &xattrs);
+
+        let trusted = get(&path, "trusted.comment");
+        let security = get(&path, "security.comment");
+        fs::remove_file(&path).unwrap();
+
+        // Even a privileged receiver, which could set them, leaves
+        // these namespaces alone.
+        result.unwrap();
+        assert_eq!(trusted, None);
+        assert_eq!(security, None);
+    }
+}
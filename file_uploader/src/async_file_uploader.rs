@@ -0,0 +1,753 @@
+use std::cmp;
+use std::future::{self, Future};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use tokio::io::{
+    self as aio, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf,
+};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, error::TryRecvError};
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+use tokio_rustls::TlsConnector;
+
+use crate::async_rate_limit::AsyncRateLimitedStream;
+use crate::auth;
+use crate::backoff::Backoff;
+use crate::batch::{self, BatchFile};
+use file_protocol::{
+    chunk_checksum, ChunkHeader, ChunkKind, Compression, FlowControl, Hello, Response, Signatures,
+    CAP_ATTRIBUTES, CAP_AUTH, CAP_DEDUP, CAP_DELTA, CAP_LINKS, CAP_MULTI_FILE, CAP_STRIPES,
+    CAP_WINDOW, CAP_XATTRS, DEFAULT_ACK_INTERVAL, DEFAULT_WINDOW, VERIFICATION_OK,
+};
+
+use crate::compressor::Compressor;
+use crate::error::{Error, Result};
+use crate::file_uploader::{buf_size, is_connection_lost, ResponseReader};
+use crate::preserve::Preserve;
+use crate::protocol;
+use crate::tls::TlsConfig;
+use crate::transfer::{AsyncSource, Chunk, Transfer};
+
+/// Stream the file is sent through, which is encrypted when TLS is
+/// enabled.
+trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+/// Asynchronous counterpart of `FileUploader`, to be used from within a
+/// tokio runtime.
+pub struct AsyncFileUploader {
+    host: String,
+    port: u16,
+    rate_limit: Option<u32>,
+    tls: Option<TlsConfig>,
+    secret: Option<Vec<u8>>,
+    preserve: Preserve,
+    compression: Option<Compression>,
+    delta: bool,
+    dedup: bool,
+    streams: u32,
+    window: u32,
+    ack_interval: u32,
+}
+
+impl AsyncFileUploader {
+    pub fn new(host: String, port: u16, rate_limit: Option<u32>) -> AsyncFileUploader {
+        AsyncFileUploader {
+            host,
+            port,
+            rate_limit,
+            tls: None,
+            secret: None,
+            preserve: Preserve::default(),
+            compression: None,
+            delta: false,
+            dedup: false,
+            streams: 1,
+            window: DEFAULT_WINDOW,
+            ack_interval: DEFAULT_ACK_INTERVAL,
+        }
+    }
+
+    /// Uploads over TLS, authenticating the receiver with `tls`.
+    pub fn with_tls(mut self, tls: TlsConfig) -> AsyncFileUploader {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Authenticates with the secret shared with the receiver when it
+    /// requires one.
+    pub fn with_secret(mut self, secret: Vec<u8>) -> AsyncFileUploader {
+        self.secret = Some(secret);
+        self
+    }
+
+    /// Has the receiver apply the `preserve`d attributes of the files to
+    /// its copies, provided it supports doing so.
+    pub fn with_preserve(mut self, preserve: Preserve) -> AsyncFileUploader {
+        self.preserve = preserve;
+        self
+    }
+
+    /// Compresses the data sent with `compression`, provided the
+    /// receiver supports it.
+    pub fn with_compression(mut self, compression: Compression) -> AsyncFileUploader {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Sends the files the receiver already holds a copy of as a delta
+    /// against that copy, provided the receiver supports it.
+    pub fn with_delta(mut self, delta: bool) -> AsyncFileUploader {
+        self.delta = delta;
+        self
+    }
+
+    /// Sends only the content-defined chunks of the files that are
+    /// missing from the store of the receiver, provided it keeps one.
+    pub fn with_dedup(mut self, dedup: bool) -> AsyncFileUploader {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Splits large files into up to `streams` ranges uploaded over as
+    /// many connections in parallel, provided the receiver supports it.
+    /// The rate limit applies to each connection.
+    pub fn with_streams(mut self, streams: u32) -> AsyncFileUploader {
+        self.streams = cmp::max(streams, 1);
+        self
+    }
+
+    /// Sends at most `window` bytes without them being acknowledged,
+    /// provided the receiver supports it.
+    pub fn with_window(mut self, window: u32) -> AsyncFileUploader {
+        self.window = window;
+        self
+    }
+
+    /// Asks the receiver to acknowledge the data at least every
+    /// `ack_interval` bytes.
+    pub fn with_ack_interval(mut self, ack_interval: u32) -> AsyncFileUploader {
+        self.ack_interval = ack_interval;
+        self
+    }
+
+    /// Uploads a file, or all the files of a directory, to the receiver.
+    /// The upload is cancelled by dropping the returned future, and can
+    /// be resumed later on.
+    pub async fn upload(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.upload_all(&[path]).await
+    }
+
+    /// Asynchronous counterpart of `FileUploader::upload_all`.
+    pub async fn upload_all(&self, paths: &[impl AsRef<Path>]) -> Result<()> {
+        let mut total_bytes_sent = 0;
+
+        // Walking the directories is done by a blocking task, which can
+        // not borrow the paths.
+        let paths: Vec<PathBuf> = paths.iter().map(|path| path.as_ref().to_owned()).collect();
+        let files = task::spawn_blocking(move || batch::collect_files(&paths))
+            .await
+            .map_err(|err| Error::Io(io::Error::other(err)))??;
+
+        let (stream, mut responses, mut capabilities) = self.open_session().await?;
+        let mut stream = AsyncRateLimitedStream::new(stream, self.rate_limit);
+
+        let mut buf = vec![0u8; buf_size(self.rate_limit, self.compression)];
+
+        let now = Instant::now();
+
+        for file in &files {
+            let stripes = match capabilities & CAP_STRIPES {
+                0 => None,
+                _ => file.stripes(self.streams),
+            };
+
+            total_bytes_sent += match stripes {
+                Some(stripes) => {
+                    self.upload_stripes(
+                        &mut stream,
+                        &mut responses,
+                        &mut capabilities,
+                        &stripes,
+                        &mut buf,
+                    )
+                    .await?
+                }
+                None => {
+                    self.upload_file(
+                        &mut stream,
+                        &mut responses,
+                        &mut capabilities,
+                        file,
+                        &mut buf,
+                    )
+                    .await?
+                }
+            };
+        }
+
+        let secs = now.elapsed().as_secs_f64();
+        let upload_speed = total_bytes_sent as f64 / secs;
+
+        println!("File transfer completed");
+        println!("Elapsed time: {:.2} seconds", secs);
+        println!("Bytes transferred: {} bytes", total_bytes_sent);
+        println!("Average upload speed: {} bytes/sec", upload_speed.round());
+
+        Ok(())
+    }
+
+    /// Asynchronous counterpart of `FileUploader::upload_stripes`. The
+    /// ranges are uploaded concurrently rather than by threads of their
+    /// own, and the upload of the others is dropped as soon as one of
+    /// them fails.
+    async fn upload_stripes(
+        &self,
+        stream: &mut AsyncRateLimitedStream<WriteHalf<Box<dyn Stream>>>,
+        responses: &mut Responses,
+        capabilities: &mut u32,
+        stripes: &[BatchFile],
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let mut uploads: Vec<Upload<'_>> = vec![Box::pin(self.upload_file(
+            stream,
+            responses,
+            capabilities,
+            &stripes[0],
+            buf,
+        ))];
+        for stripe in &stripes[1..] {
+            uploads.push(Box::pin(self.upload_stripe(stripe)));
+        }
+
+        try_join_all(uploads).await
+    }
+
+    /// Asynchronous counterpart of `FileUploader::upload_stripe`.
+    async fn upload_stripe(&self, file: &BatchFile) -> Result<usize> {
+        let (stream, mut responses, mut capabilities) = self.open_session().await?;
+        let mut stream = AsyncRateLimitedStream::new(stream, self.rate_limit);
+
+        let mut buf = vec![0u8; buf_size(self.rate_limit, self.compression)];
+
+        self.upload_file(
+            &mut stream,
+            &mut responses,
+            &mut capabilities,
+            file,
+            &mut buf,
+        )
+        .await
+    }
+
+    /// Asynchronous counterpart of `FileUploader::upload_file`.
+    async fn upload_file(
+        &self,
+        stream: &mut AsyncRateLimitedStream<WriteHalf<Box<dyn Stream>>>,
+        responses: &mut Responses,
+        capabilities: &mut u32,
+        file: &BatchFile,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        if file.link.is_some() && *capabilities & CAP_LINKS == 0 {
+            eprintln!(
+                "Skipping link: {} (not supported by the receiver)",
+                file.path.display()
+            );
+            return Ok(0);
+        }
+
+        let (start, end) = file.range();
+        let mut transfer =
+            Transfer::new(file.open_async().await?, file.size).with_range(start, end);
+        let mut compressor = Compression::negotiated(*capabilities).map(Compressor::new);
+        let mut bytes_sent = 0;
+        let mut accepted = false;
+        let mut backoff = Backoff::new();
+
+        if file.stripe.is_whole() {
+            println!(
+                "Uploading file: {} (size={})",
+                file.path.display(),
+                file.size
+            );
+        } else {
+            println!(
+                "Uploading file: {} (size={}, range={}-{})",
+                file.path.display(),
+                file.size,
+                start,
+                end
+            );
+        }
+
+        loop {
+            let result = if !accepted {
+                match self
+                    .start_file(stream, responses, *capabilities, file)
+                    .await
+                {
+                    Ok((offset, signatures)) => transfer.resume(offset).await.map(|_| {
+                        transfer.set_window(self.window(*capabilities));
+                        match signatures {
+                            Some(signatures) => transfer.send_as_delta(signatures),
+                            None if *capabilities & CAP_DEDUP != 0
+                                && file.link.is_none()
+                                && file.stripe.is_whole() =>
+                            {
+                                transfer.deduplicate()
+                            }
+                            None => {}
+                        }
+                        accepted = true;
+                        backoff.reset();
+                        false
+                    }),
+                    Err(err) => Err(err),
+                }
+            } else if !transfer.is_acknowledged() {
+                let compressor = compressor.as_mut();
+                self.send_next_chunk(stream, responses, &mut transfer, compressor, buf)
+                    .await
+                    .map(|chunk_size| {
+                        bytes_sent += chunk_size;
+                        false
+                    })
+            } else {
+                let digest = transfer.digest();
+                self.verify(stream, responses, &digest).await.map(|_| true)
+            };
+
+            match result {
+                Ok(true) => return Ok(bytes_sent),
+                Ok(false) => {}
+                Err(Error::Io(err)) if is_connection_lost(&err) => {
+                    if accepted {
+                        eprintln!("Connection reset");
+                    } else {
+                        let delay = backoff.next_delay()?;
+                        eprintln!("Connection closed by the receiver. Retrying...");
+                        time::sleep(delay).await;
+                    }
+
+                    let (new_stream, new_responses, new_capabilities) = self.open_session().await?;
+                    stream.update_stream(new_stream);
+                    *responses = new_responses;
+                    *capabilities = new_capabilities;
+                    compressor = Compression::negotiated(new_capabilities).map(Compressor::new);
+                    accepted = false;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn connect(&self) -> Result<TcpStream> {
+        let addr = format!("{}:{}", self.host, self.port);
+
+        let stream = loop {
+            match TcpStream::connect(&addr).await {
+                Ok(stream) => break stream,
+                Err(err) => match err.kind() {
+                    // A connection accepted by a receiver that is shutting
+                    // down is reset before it can be used.
+                    ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset => {
+                        eprintln!("Connection refused. Retrying...");
+                        time::sleep(Duration::from_secs(1)).await;
+                    }
+                    _ => return Err(Error::Connect(err)),
+                },
+            }
+        };
+
+        println!("Connection established with: {}", stream.peer_addr()?);
+
+        Ok(stream)
+    }
+
+    /// Connects to the receiver and agrees on the protocol, returning
+    /// the stream to send the files through and the responses sent by
+    /// the receiver, along with the capabilities of the session.
+    async fn open_session(&self) -> Result<(WriteHalf<Box<dyn Stream>>, Responses, u32)> {
+        let mut backoff = Backoff::new();
+
+        loop {
+            let stream = self.connect().await?;
+
+            match self.handshake(stream).await {
+                Ok((stream, reader, capabilities)) => {
+                    let (read_half, writer) = aio::split(stream);
+                    return Ok((writer, Responses::spawn(read_half, reader), capabilities));
+                }
+                Err(Error::Io(err)) if is_connection_lost(&err) => {
+                    let delay = backoff.next_delay()?;
+                    eprintln!("Connection closed by the receiver. Retrying...");
+                    time::sleep(delay).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn handshake(&self, stream: TcpStream) -> Result<(Box<dyn Stream>, ResponseReader, u32)> {
+        let mut stream: Box<dyn Stream> = match &self.tls {
+            Some(tls) => {
+                let connector = TlsConnector::from(tls.client_config());
+                Box::new(
+                    connector
+                        .connect(tls.server_name(&self.host)?, stream)
+                        .await?,
+                )
+            }
+            None => Box::new(stream),
+        };
+
+        let mut reader = ResponseReader::new();
+        let capabilities = self.negotiate(&mut stream, &mut reader).await?;
+
+        Ok((stream, reader, capabilities))
+    }
+
+    /// Asynchronous counterpart of `FileUploader::start_file`.
+    async fn start_file(
+        &self,
+        stream: &mut AsyncRateLimitedStream<WriteHalf<Box<dyn Stream>>>,
+        responses: &mut Responses,
+        capabilities: u32,
+        file: &BatchFile,
+    ) -> Result<(u64, Option<Signatures>)> {
+        // Reading the extended attributes of the file blocks.
+        let (batch_file, preserve) = (file.clone(), self.preserve);
+        let header =
+            task::spawn_blocking(move || batch_file.encode_header(capabilities, &preserve))
+                .await
+                .map_err(|err| Error::Io(io::Error::other(err)))??;
+        stream.write_all(&header).await?;
+
+        let mut signatures = None;
+
+        loop {
+            match responses.next().await? {
+                Response::Accept(offset) => {
+                    let file_offset = protocol::validate_offset(offset, file.range())?;
+
+                    if file_offset > file.range().0 {
+                        println!("Resuming upload from offset: {}", file_offset);
+                    }
+
+                    return Ok((file_offset, signatures));
+                }
+                Response::Signatures(blocks) if capabilities & CAP_DELTA != 0 => {
+                    signatures = Some(blocks);
+                }
+                Response::Challenge(nonce) => {
+                    let answer = auth::answer_challenge(self.secret.as_deref(), &nonce)?;
+                    stream.write_all(&answer).await?;
+                }
+                Response::Error(message) => return Err(Error::Rejected(message)),
+                _ => {
+                    return Err(Error::Protocol(
+                        "unexpected response to the header".to_string(),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Asynchronous counterpart of `FileUploader::negotiate`.
+    async fn negotiate(
+        &self,
+        stream: &mut Box<dyn Stream>,
+        reader: &mut ResponseReader,
+    ) -> Result<u32> {
+        let hello = Hello::new(self.capabilities());
+        stream.write_all(&hello.encode()).await?;
+
+        let capabilities = match read_response(stream, reader).await? {
+            Response::Hello(version, capabilities) => {
+                hello.validate_agreement(version, capabilities)?
+            }
+            Response::Error(message) => return Err(Error::Rejected(message)),
+            _ => {
+                return Err(Error::Protocol(
+                    "unexpected response to the hello".to_string(),
+                ))
+            }
+        };
+
+        if capabilities & CAP_WINDOW != 0 {
+            let flow_control = self.flow_control();
+            stream.write_all(&flow_control.encode()).await?;
+
+            match read_response(stream, reader).await? {
+                Response::AckInterval(ack_interval) => {
+                    flow_control.validate_agreement(ack_interval)?;
+                }
+                Response::Error(message) => return Err(Error::Rejected(message)),
+                _ => {
+                    return Err(Error::Protocol(
+                        "unexpected response to the flow control".to_string(),
+                    ))
+                }
+            }
+        }
+
+        Ok(capabilities)
+    }
+
+    fn flow_control(&self) -> FlowControl {
+        FlowControl::new(self.window, self.ack_interval)
+    }
+
+    /// Asynchronous counterpart of `FileUploader::window`.
+    fn window(&self, capabilities: u32) -> Option<u32> {
+        match capabilities & CAP_WINDOW {
+            0 => None,
+            _ => Some(self.flow_control().window),
+        }
+    }
+
+    fn capabilities(&self) -> u32 {
+        let mut capabilities = CAP_MULTI_FILE | CAP_LINKS | CAP_WINDOW;
+
+        if self.secret.is_some() {
+            capabilities |= CAP_AUTH;
+        }
+        if !self.preserve.is_empty() {
+            capabilities |= CAP_ATTRIBUTES;
+        }
+        if self.preserve.xattrs {
+            capabilities |= CAP_XATTRS;
+        }
+        if let Some(compression) = self.compression {
+            capabilities |= compression.capability();
+        }
+        if self.delta {
+            capabilities |= CAP_DELTA;
+        }
+        if self.dedup {
+            capabilities |= CAP_DEDUP;
+        }
+        if self.streams > 1 {
+            capabilities |= CAP_STRIPES;
+        }
+
+        capabilities
+    }
+
+    /// Processes the responses received so far and sends the next
+    /// chunk, giving priority to the chunks that must be retransmitted.
+    /// Waits for a response when there is nothing left to send.
+    /// Returns the number of bytes of the file that were sent, which
+    /// excludes the blocks copied by the receiver.
+    async fn send_next_chunk(
+        &self,
+        stream: &mut AsyncRateLimitedStream<WriteHalf<Box<dyn Stream>>>,
+        responses: &mut Responses,
+        transfer: &mut Transfer<Box<dyn AsyncSource>>,
+        compressor: Option<&mut Compressor>,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        while let Some(response) = responses.try_next()? {
+            transfer.handle_response(response)?;
+        }
+
+        match transfer.next_chunk(buf).await? {
+            Some(Chunk::Data(offset, length)) => {
+                if transfer.has_chunk_kinds() {
+                    stream.write_u8(ChunkKind::Data.encode()).await?;
+                }
+
+                let data = &buf[..length];
+                let header = ChunkHeader::new(offset, length);
+
+                match compressor {
+                    Some(compressor) => match compressor.compress(data) {
+                        Some(compressed) => {
+                            let header = header.with_compressed_length(compressed.len());
+                            stream.write_all(&header.encode_compressed()).await?;
+                            stream.write_all(compressed).await?;
+                        }
+                        None => {
+                            stream.write_all(&header.encode_compressed()).await?;
+                            stream.write_all(data).await?;
+                        }
+                    },
+                    None => {
+                        stream.write_all(&header.encode()).await?;
+                        stream.write_all(data).await?;
+                    }
+                }
+
+                stream.write_all(&chunk_checksum(data)).await?;
+                Ok(length)
+            }
+            Some(Chunk::Copy(copy)) => {
+                stream.write_u8(ChunkKind::Copy.encode()).await?;
+                stream.write_all(&copy.encode()).await?;
+                Ok(0)
+            }
+            Some(Chunk::Query(query)) => {
+                stream.write_u8(ChunkKind::Query.encode()).await?;
+                stream.write_all(&query.encode()).await?;
+                Ok(0)
+            }
+            Some(Chunk::Stored(chunk)) => {
+                stream.write_u8(ChunkKind::Stored.encode()).await?;
+                stream.write_all(&chunk.encode()).await?;
+                Ok(0)
+            }
+            None => {
+                transfer.handle_response(responses.next().await?)?;
+                Ok(0)
+            }
+        }
+    }
+
+    /// Sends the digest of the whole file and waits for the receiver
+    /// to confirm that it matches the digest of the data it stored.
+    async fn verify(
+        &self,
+        stream: &mut AsyncRateLimitedStream<WriteHalf<Box<dyn Stream>>>,
+        responses: &mut Responses,
+        digest: &[u8],
+    ) -> Result<()> {
+        stream.write_all(digest).await?;
+
+        loop {
+            match responses.next().await? {
+                Response::Verification(VERIFICATION_OK) => {
+                    println!("File verified successfully");
+                    return Ok(());
+                }
+                Response::Verification(_) => return Err(Error::Verification),
+                Response::Error(message) => return Err(Error::Rejected(message)),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Responses sent by the receiver, which are read by a separate task so
+/// that they can be processed while the file is being sent.
+struct Responses {
+    receiver: mpsc::UnboundedReceiver<Result<Response>>,
+    task: JoinHandle<()>,
+}
+
+impl Responses {
+    /// Reads the responses from `stream` with the `reader` used during
+    /// the handshake, starting with those it received already.
+    fn spawn(mut stream: ReadHalf<Box<dyn Stream>>, mut reader: ResponseReader) -> Responses {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let task = tokio::spawn(async move {
+            let mut read_buf = [0u8; 64];
+
+            loop {
+                while let Some(response) = reader.pop() {
+                    if sender.send(Ok(response)).is_err() {
+                        return;
+                    }
+                }
+
+                let result = match stream.read(&mut read_buf).await {
+                    Ok(0) => Err(connection_closed()),
+                    Ok(size) => reader.feed(&read_buf[..size]),
+                    Err(err) => Err(Error::Io(err)),
+                };
+
+                if let Err(err) = result {
+                    let _ = sender.send(Err(err));
+                    return;
+                }
+            }
+        });
+
+        Responses { receiver, task }
+    }
+
+    /// Returns the next response if it was received already.
+    fn try_next(&mut self) -> Result<Option<Response>> {
+        match self.receiver.try_recv() {
+            Ok(result) => result.map(Some),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(connection_closed()),
+        }
+    }
+
+    async fn next(&mut self) -> Result<Response> {
+        match self.receiver.recv().await {
+            Some(result) => result,
+            None => Err(connection_closed()),
+        }
+    }
+}
+
+impl Drop for Responses {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Reads from `stream` until a whole response is received, unless one
+/// is queued already, before the responses are handed over to
+/// `Responses`.
+async fn read_response(
+    stream: &mut Box<dyn Stream>,
+    reader: &mut ResponseReader,
+) -> Result<Response> {
+    let mut read_buf = [0u8; 64];
+
+    loop {
+        if let Some(response) = reader.pop() {
+            return Ok(response);
+        }
+
+        let size = stream.read(&mut read_buf).await?;
+        if size == 0 {
+            return Err(connection_closed());
+        }
+
+        reader.feed(&read_buf[..size])?;
+    }
+}
+
+/// Upload of a range of a striped file.
+type Upload<'a> = Pin<Box<dyn Future<Output = Result<usize>> + Send + 'a>>;
+
+/// Runs the uploads concurrently until they all complete, returning the
+/// number of bytes they sent, or until one of them fails, in which case
+/// the others are dropped.
+async fn try_join_all(mut uploads: Vec<Upload<'_>>) -> Result<usize> {
+    let mut bytes_sent: Vec<Option<usize>> = vec![None; uploads.len()];
+
+    future::poll_fn(|cx| {
+        for (upload, bytes_sent) in uploads.iter_mut().zip(bytes_sent.iter_mut()) {
+            if bytes_sent.is_none() {
+                if let Poll::Ready(result) = upload.as_mut().poll(cx) {
+                    *bytes_sent = Some(result?);
+                }
+            }
+        }
+
+        match bytes_sent.iter().copied().sum::<Option<usize>>() {
+            Some(total_bytes_sent) => Poll::Ready(Ok(total_bytes_sent)),
+            None => Poll::Pending,
+        }
+    })
+    .await
+}
+
+fn connection_closed() -> Error {
+    Error::Io(io::Error::new(
+        ErrorKind::UnexpectedEof,
+        "Connection closed by the receiver",
+    ))
+}
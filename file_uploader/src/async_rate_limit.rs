@@ -0,0 +1,123 @@
+use std::cmp;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+use crate::rate_limit::TokenBucket;
+
+/// Stream that waits with a timer, instead of blocking the thread, for
+/// the tokens required to write the data to become available.
+pub struct AsyncRateLimitedStream<T> {
+    stream: T,
+    bucket: Option<TokenBucket>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T: AsyncWrite + Unpin> AsyncRateLimitedStream<T> {
+    pub fn new(stream: T, token_rate: Option<u32>) -> AsyncRateLimitedStream<T> {
+        AsyncRateLimitedStream {
+            stream,
+            bucket: token_rate.map(TokenBucket::new),
+            delay: None,
+        }
+    }
+
+    pub fn update_stream(&mut self, stream: T) {
+        self.stream = stream;
+        self.delay = None;
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for AsyncRateLimitedStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for AsyncRateLimitedStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        let bucket = match &mut this.bucket {
+            Some(bucket) => bucket,
+            None => return Pin::new(&mut this.stream).poll_write(cx, buf),
+        };
+
+        // Writes larger than the capacity of the bucket are allowed to
+        // be partial, so they are shortened instead of rejected.
+        let buf = &buf[..cmp::min(buf.len(), bucket.capacity())];
+
+        if this.delay.is_none() {
+            if let Some(waiting_time) = bucket.waiting_time(buf.len()) {
+                this.delay = Some(Box::pin(tokio::time::sleep(waiting_time)));
+            }
+        }
+
+        if let Some(delay) = &mut this.delay {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            this.delay = None;
+            bucket.sync();
+        }
+
+        // Only the bytes actually written consume tokens.
+        let result = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(size)) = result {
+            bucket.consume(size);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_required_tokens_not_available_yet() {
+        let mut stream = AsyncRateLimitedStream::new(tokio::io::sink(), Some(1));
+
+        let now = Instant::now();
+
+        stream.write_all(&[0u8; 1]).await.unwrap();
+        assert_eq!(now.elapsed().as_millis() / 10, 100);
+
+        stream.write_all(&[0u8; 1]).await.unwrap();
+        assert_eq!(now.elapsed().as_millis() / 10, 200);
+    }
+
+    #[tokio::test]
+    async fn test_write_larger_than_capacity_is_partial() {
+        let mut stream = AsyncRateLimitedStream::new(tokio::io::sink(), Some(2));
+
+        let now = Instant::now();
+
+        assert_eq!(stream.write(&[0u8; 3]).await.unwrap(), 2);
+        assert_eq!(now.elapsed().as_millis() / 10, 100);
+    }
+}
@@ -0,0 +1,17 @@
+//! Authentication with a secret shared with the receiver.
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use crate::error::{Error, Result};
+
+/// Proves the knowledge of the shared secret by signing the nonce sent
+/// by the receiver with HMAC-SHA256.
+pub fn answer_challenge(secret: Option<&[u8]>, nonce: &[u8]) -> Result<Vec<u8>> {
+    let secret = secret
+        .ok_or_else(|| Error::Rejected("the receiver requires a shared secret".to_string()))?;
+
+    let mut hmac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    hmac.update(nonce);
+    Ok(hmac.finalize().into_bytes().to_vec())
+}
@@ -0,0 +1,68 @@
+//! Delays between the attempts to upload a file that the receiver closes
+//! the connection for before accepting it, which it does while the file
+//! is busy receiving from another uploader.
+
+use std::cmp;
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+
+/// Delay before the first retry, doubled on each of the following ones.
+const INITIAL_DELAY: Duration = Duration::from_millis(250);
+
+/// Longest delay between two retries.
+const MAX_DELAY: Duration = Duration::from_secs(8);
+
+/// Number of retries before the upload is given up.
+const MAX_RETRIES: u32 = 10;
+
+pub struct Backoff {
+    retries: u32,
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff { retries: 0 }
+    }
+
+    /// Returns the delay to wait for before retrying, or an error once
+    /// the retries are exhausted.
+    pub fn next_delay(&mut self) -> Result<Duration> {
+        if self.retries == MAX_RETRIES {
+            return Err(Error::Rejected(format!(
+                "connection closed by the receiver {} times in a row",
+                MAX_RETRIES + 1
+            )));
+        }
+
+        let delay = INITIAL_DELAY * 2u32.saturating_pow(self.retries);
+        self.retries += 1;
+        Ok(cmp::min(delay, MAX_DELAY))
+    }
+
+    /// Starts over once the receiver accepted the file.
+    pub fn reset(&mut self) {
+        self.retries = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounded_retries() {
+        let mut backoff = Backoff::new();
+
+        let delays: Vec<_> = (0..MAX_RETRIES)
+            .map(|_| backoff.next_delay().unwrap())
+            .collect();
+        assert_eq!(delays[0], INITIAL_DELAY);
+        assert_eq!(delays[1], INITIAL_DELAY * 2);
+        assert_eq!(delays[MAX_RETRIES as usize - 1], MAX_DELAY);
+        assert!(matches!(backoff.next_delay(), Err(Error::Rejected(_))));
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay().unwrap(), INITIAL_DELAY);
+    }
+}
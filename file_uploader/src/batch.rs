@@ -0,0 +1,242 @@
+//! Files sent over a single session.
+
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+
+use file_protocol::{
+    Attributes, FileKind, Header, Stripe, CAP_ATTRIBUTES, CAP_LINKS, CAP_STRIPES, CAP_XATTRS,
+    MAX_FILE_NAME_SIZE, MAX_STRIPES,
+};
+
+use crate::error::{Error, Result};
+use crate::preserve::{self, Preserve};
+use crate::transfer::Source;
+use crate::xattrs;
+
+/// Smallest range a file is split into, so that striping is only used
+/// for files large enough to benefit from it.
+const MIN_STRIPE_SIZE: u64 = 1024 * 1024;
+
+/// File to upload, along with the name it is sent under.
+#[derive(Clone, Debug)]
+pub struct BatchFile {
+    pub path: PathBuf,
+    pub name: Vec<u8>,
+    pub size: u64,
+    pub attributes: Attributes,
+    /// Target of the file if it is a symbolic link, which is sent as
+    /// its data.
+    pub link: Option<Vec<u8>>,
+    /// Range of the file to upload, which is the whole file unless it
+    /// is striped.
+    pub stripe: Stripe,
+}
+
+impl BatchFile {
+    /// Encodes the header of the file, followed by the messages that the
+    /// `capabilities` of the session call for, which carry the kind of
+    /// the file and the attributes selected by `preserve`.
+    pub fn encode_header(&self, capabilities: u32, preserve: &Preserve) -> Result<Vec<u8>> {
+        let mut buf = Header::new(self.name.clone(), self.size).encode()?;
+
+        if capabilities & CAP_LINKS != 0 {
+            let kind = match self.link {
+                Some(_) => FileKind::Link,
+                None => FileKind::File,
+            };
+            buf.push(kind.encode());
+        } else if self.link.is_some() {
+            return Err(Error::Protocol(
+                "the receiver does not support links".to_string(),
+            ));
+        }
+
+        if capabilities & CAP_ATTRIBUTES != 0 {
+            buf.extend_from_slice(&preserve.select(&self.attributes).encode());
+        }
+
+        // The links given as arguments to upload are followed, and are
+        // sent with the extended attributes of their target.
+        if capabilities & CAP_XATTRS != 0 {
+            let follow_links = self.link.is_none();
+            buf.extend_from_slice(&xattrs::read(&self.path, follow_links)?.encode()?);
+        }
+
+        if capabilities & CAP_STRIPES != 0 {
+            buf.extend_from_slice(&self.stripe.encode());
+        } else if !self.stripe.is_whole() {
+            return Err(Error::Protocol(
+                "the receiver does not support striped files".to_string(),
+            ));
+        }
+
+        Ok(buf)
+    }
+
+    /// Returns the offsets the range of the file to upload starts and
+    /// ends at.
+    pub fn range(&self) -> (u64, u64) {
+        self.stripe.range(self.size)
+    }
+
+    /// Splits the file into at most `streams` ranges to upload over as
+    /// many connections, each of them of at least `MIN_STRIPE_SIZE`
+    /// bytes. Returns `None` for links and for the files too small to
+    /// be split.
+    pub fn stripes(&self, streams: u32) -> Option<Vec<BatchFile>> {
+        let count = cmp::min(
+            cmp::min(streams, MAX_STRIPES) as u64,
+            self.size / MIN_STRIPE_SIZE,
+        ) as u32;
+        if count < 2 || self.link.is_some() {
+            return None;
+        }
+
+        let stripes = (0..count)
+            .map(|index| BatchFile {
+                stripe: Stripe::new(index, count),
+                ..self.clone()
+            })
+            .collect();
+        Some(stripes)
+    }
+
+    /// Opens the data of the file to upload.
+    pub fn open(&self) -> io::Result<Box<dyn Source>> {
+        Ok(match &self.link {
+            Some(target) => Box::new(Cursor::new(target.clone())),
+            None => Box::new(File::open(&self.path)?),
+        })
+    }
+
+    /// Opens the data of the file to upload along with another handle of
+    /// the open file, for regular files, which their data can be sent
+    /// from with `sendfile`.
+    pub fn open_zero_copy(&self) -> io::Result<(Box<dyn Source>, Option<File>)> {
+        match &self.link {
+            Some(_) => Ok((self.open()?, None)),
+            None => {
+                let file = File::open(&self.path)?;
+                let handle = file.try_clone()?;
+                Ok((Box::new(file), Some(handle)))
+            }
+        }
+    }
+
+    /// Asynchronous counterpart of `BatchFile::open`.
+    #[cfg(feature = "tokio")]
+    pub async fn open_async(&self) -> io::Result<Box<dyn crate::transfer::AsyncSource>> {
+        Ok(match &self.link {
+            Some(target) => Box::new(Cursor::new(target.clone())),
+            None => Box::new(tokio::fs::File::open(&self.path).await?),
+        })
+    }
+}
+
+/// Lists the files to upload given the `paths` of files and directories.
+///
+/// A file is sent under its own name, while the files of a directory are
+/// found recursively and sent under their path relative to the parent of
+/// the directory, so that the receiver recreates the directory. The
+/// symbolic links found in directories are sent as links, while those
+/// given in `paths` are followed. Empty directories and files that are
+/// neither regular files nor links are skipped.
+pub fn collect_files(paths: &[impl AsRef<Path>]) -> Result<Vec<BatchFile>> {
+    let mut files = Vec::new();
+
+    for path in paths {
+        let path = path.as_ref();
+
+        // `.` and `/` have no name, so their contents are sent as if
+        // they were uploaded one by one.
+        let name = match path.file_name() {
+            Some(name) => PathBuf::from(name),
+            None if path.is_dir() => PathBuf::new(),
+            None => return Err(Error::FileName(format!("no file name: {}", path.display()))),
+        };
+
+        if path.is_dir() {
+            collect_directory(path, &name, &mut files)?;
+        } else {
+            files.push(batch_file(path, &name)?);
+        }
+    }
+
+    Ok(files)
+}
+
+fn collect_directory(directory: &Path, name: &Path, files: &mut Vec<BatchFile>) -> Result<()> {
+    let mut entries = fs::read_dir(directory)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = name.join(entry.file_name());
+
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_directory(&path, &name, files)?;
+        } else if file_type.is_symlink() {
+            files.push(link_file(&path, &name)?);
+        } else if file_type.is_file() {
+            files.push(batch_file(&path, &name)?);
+        }
+    }
+
+    Ok(())
+}
+
+fn batch_file(path: &Path, name: &Path) -> Result<BatchFile> {
+    let metadata = fs::metadata(path)?;
+
+    Ok(BatchFile {
+        path: path.to_owned(),
+        name: file_name_bytes(name)?,
+        size: metadata.len(),
+        attributes: preserve::attributes(&metadata),
+        link: None,
+        stripe: Stripe::whole(),
+    })
+}
+
+fn link_file(path: &Path, name: &Path) -> Result<BatchFile> {
+    let metadata = fs::symlink_metadata(path)?;
+    let target = file_name_bytes(&fs::read_link(path)?)?;
+
+    Ok(BatchFile {
+        path: path.to_owned(),
+        name: file_name_bytes(name)?,
+        size: target.len() as u64,
+        attributes: preserve::attributes(&metadata),
+        link: Some(target),
+        stripe: Stripe::whole(),
+    })
+}
+
+/// Returns the name of the file as sent in the header, which is made of
+/// the raw bytes of the path on Unix.
+pub fn file_name_bytes(path: &Path) -> Result<Vec<u8>> {
+    #[cfg(unix)]
+    let file_name = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    };
+    #[cfg(not(unix))]
+    let file_name = path
+        .to_str()
+        .ok_or_else(|| Error::FileName(format!("not valid Unicode: {}", path.display())))?
+        .replace('\\', "/")
+        .into_bytes();
+
+    if file_name.len() > MAX_FILE_NAME_SIZE {
+        return Err(Error::FileName(format!(
+            "longer than {} bytes: {}",
+            MAX_FILE_NAME_SIZE,
+            path.display()
+        )));
+    }
+
+    Ok(file_name)
+}
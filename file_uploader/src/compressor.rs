@@ -0,0 +1,76 @@
+//! Compression of the chunks sent during a session that agreed on a
+//! `Compression`.
+
+use file_protocol::Compression;
+
+/// Number of chunks sent as they are after one that barely compressed,
+/// before trying to compress the data again.
+const CHUNKS_SKIPPED: u32 = 32;
+
+/// Compresses the chunks of a file, giving up for a while on data that
+/// does not compress, such as archives and media files, to spare the
+/// time spent compressing it.
+pub(crate) struct Compressor {
+    compression: Compression,
+    buf: Vec<u8>,
+    chunks_to_skip: u32,
+}
+
+impl Compressor {
+    pub fn new(compression: Compression) -> Compressor {
+        Compressor {
+            compression,
+            buf: Vec::new(),
+            chunks_to_skip: 0,
+        }
+    }
+
+    /// Returns the compressed `data`, or `None` when the data must be
+    /// sent as it is.
+    pub fn compress(&mut self, data: &[u8]) -> Option<&[u8]> {
+        if self.chunks_to_skip > 0 {
+            self.chunks_to_skip -= 1;
+            return None;
+        }
+
+        self.buf.clear();
+        self.compression.compress(data, &mut self.buf);
+
+        // Saving less than 1/16 of the data is not worth the effort.
+        if self.buf.len() > data.len() - data.len() / 16 {
+            self.chunks_to_skip = CHUNKS_SKIPPED;
+        }
+
+        if self.buf.len() < data.len() {
+            Some(&self.buf)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_incompressible_data() {
+        let compressible = vec![b'a'; 4096];
+        let mut state = 1u32;
+        let incompressible: Vec<u8> = (0..4096)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+
+        let mut compressor = Compressor::new(Compression::Lz4);
+        assert!(compressor.compress(&compressible).is_some());
+        assert!(compressor.compress(&incompressible).is_none());
+
+        for _ in 0..CHUNKS_SKIPPED {
+            assert!(compressor.compress(&compressible).is_none());
+        }
+        assert!(compressor.compress(&compressible).is_some());
+    }
+}
@@ -0,0 +1,204 @@
+//! Deduplication of a file against the chunks the receiver holds in its
+//! store.
+
+use std::cmp;
+use std::collections::VecDeque;
+
+use file_protocol::{chunk_hash, ChunkQuery, Chunker, StoredChunk, MAX_QUERY_CHUNKS};
+
+use crate::error::{Error, Result};
+use crate::transfer::Chunk;
+
+/// Splits the file into content-defined chunks as it is read, asks the
+/// receiver which of them its store lacks, and turns the file into
+/// references to the chunks it holds interleaved with the data of the
+/// others. The file is read ahead while waiting for the answer, up to a
+/// whole query.
+pub struct Dedup {
+    chunker: Chunker,
+    /// Offset of the next chunk cut from the data read.
+    offset: u64,
+    /// Chunks that were not queried yet.
+    batch: Vec<StoredChunk>,
+    /// Chunks of the query waiting for an answer.
+    queried: Option<Vec<StoredChunk>>,
+    /// Chunks the receiver answered about, along with whether they are
+    /// missing from its store, in which case what is left of their data
+    /// must still be sent.
+    answered: VecDeque<(StoredChunk, bool)>,
+    /// Whether the whole file was read.
+    finished: bool,
+}
+
+impl Dedup {
+    /// Deduplication of the file read from `offset`.
+    pub fn new(offset: u64) -> Dedup {
+        Dedup {
+            chunker: Chunker::new(),
+            offset,
+            batch: Vec::new(),
+            queried: None,
+            answered: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    /// Returns whether more data must be read from the file to carry on.
+    pub fn needs_data(&self) -> bool {
+        !self.finished && self.batch.len() < MAX_QUERY_CHUNKS
+    }
+
+    /// Adds the data read from the file next.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.chunker.feed(data);
+        while let Some(chunk) = self.chunker.next_chunk() {
+            self.push(&chunk);
+        }
+    }
+
+    /// Records that the whole file was read.
+    pub fn finish(&mut self) {
+        while let Some(chunk) = self.chunker.finish() {
+            self.push(&chunk);
+        }
+        self.finished = true;
+    }
+
+    /// Returns the next chunk, or `None` if more data must be read or
+    /// the receiver must answer first. The data of the missing chunks is
+    /// returned as `Chunk::Data` of at most `buf_size` bytes, which must
+    /// be read from the file.
+    pub fn next_chunk(&mut self, buf_size: usize) -> Option<Chunk> {
+        if self.queried.is_none()
+            && !self.batch.is_empty()
+            && (self.batch.len() >= MAX_QUERY_CHUNKS || self.finished)
+        {
+            let size = cmp::min(self.batch.len(), MAX_QUERY_CHUNKS);
+            let queried: Vec<_> = self.batch.drain(..size).collect();
+            let query = ChunkQuery {
+                hashes: queried.iter().map(|chunk| chunk.hash).collect(),
+            };
+            self.queried = Some(queried);
+            return Some(Chunk::Query(query));
+        }
+
+        match self.answered.pop_front()? {
+            (chunk, false) => Some(Chunk::Stored(chunk)),
+            (mut chunk, true) => {
+                let length = cmp::min(chunk.length as usize, buf_size);
+                let data = Chunk::Data(chunk.offset, length);
+
+                chunk.offset += length as u64;
+                chunk.length -= length as u32;
+                if chunk.length > 0 {
+                    self.answered.push_front((chunk, true));
+                }
+
+                Some(data)
+            }
+        }
+    }
+
+    /// Handles the answer to the query sent, given by the indexes of the
+    /// chunks that are missing in increasing order.
+    pub fn answer(&mut self, missing: Vec<u32>) -> Result<()> {
+        let queried = self.queried.take().ok_or_else(|| {
+            Error::Protocol("missing chunks sent without being queried".to_string())
+        })?;
+
+        let is_valid = missing.windows(2).all(|pair| pair[0] < pair[1])
+            && missing
+                .last()
+                .is_none_or(|&index| (index as usize) < queried.len());
+        if !is_valid {
+            return Err(Error::Protocol(format!(
+                "invalid missing chunks: {:?}",
+                missing
+            )));
+        }
+
+        let mut missing = missing.into_iter().peekable();
+        for (index, chunk) in queried.into_iter().enumerate() {
+            let is_missing = missing.next_if_eq(&(index as u32)).is_some();
+            self.answered.push_back((chunk, is_missing));
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.batch
+            .push(StoredChunk::new(self.offset, data.len(), chunk_hash(data)));
+        self.offset += data.len() as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_missing_chunks() {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let data: Vec<u8> = (0..500_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+
+        let mut dedup = Dedup::new(1000);
+        for piece in data[1000..].chunks(4096) {
+            assert!(dedup.needs_data());
+            dedup.feed(piece);
+            assert!(dedup.next_chunk(1024).is_none());
+        }
+        dedup.finish();
+        assert!(!dedup.needs_data());
+
+        let count = match dedup.next_chunk(1024) {
+            Some(Chunk::Query(query)) => query.hashes.len(),
+            chunk => panic!("unexpected chunk: {:?}", chunk),
+        };
+        assert!(dedup.next_chunk(1024).is_none());
+
+        // The chunks with an odd index are missing.
+        let missing = (1..count as u32).step_by(2).collect();
+        dedup.answer(missing).unwrap();
+        assert!(dedup.answer(Vec::new()).is_err());
+
+        let mut offset = 1000;
+        let mut stored = 0;
+        while let Some(chunk) = dedup.next_chunk(1024) {
+            match chunk {
+                Chunk::Data(chunk_offset, length) => {
+                    assert_eq!(chunk_offset, offset);
+                    assert!(length <= 1024);
+                    offset += length as u64;
+                }
+                Chunk::Stored(chunk) => {
+                    assert_eq!(chunk.offset, offset);
+                    let end = offset as usize + chunk.length as usize;
+                    assert_eq!(chunk.hash, chunk_hash(&data[offset as usize..end]));
+                    offset = end as u64;
+                    stored += 1;
+                }
+                chunk => panic!("unexpected chunk: {:?}", chunk),
+            }
+        }
+
+        assert_eq!(offset, data.len() as u64);
+        assert_eq!(stored, count.div_ceil(2));
+    }
+
+    #[test]
+    fn test_invalid_answer() {
+        let mut dedup = Dedup::new(0);
+        dedup.feed(b"data");
+        dedup.finish();
+        assert!(matches!(dedup.next_chunk(1024), Some(Chunk::Query(_))));
+        assert!(dedup.answer(vec![1]).is_err());
+    }
+}
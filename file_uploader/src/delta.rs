@@ -0,0 +1,206 @@
+//! Delta of a file against the copy of it the receiver already holds.
+
+use std::collections::HashMap;
+
+use file_protocol::{
+    strong_checksum, BlockCopy, RollingChecksum, Signatures, STRONG_CHECKSUM_SIZE,
+};
+
+use crate::transfer::Chunk;
+
+/// Looks for the blocks of the copy held by the receiver in the data of
+/// the file as it is read, as in rsync, turning the file into chunks of
+/// data interleaved with copies of the blocks found.
+pub struct Delta {
+    block_size: usize,
+    /// Blocks by their rolling checksum, along with their strong one.
+    blocks: HashMap<u32, Vec<(u32, [u8; STRONG_CHECKSUM_SIZE])>>,
+    /// Data read from the file that was not turned into chunks yet.
+    pending: Vec<u8>,
+    /// Offset of the pending data in the file.
+    offset: u64,
+    /// Number of pending bytes that did not match any block, which
+    /// precede the window the blocks are looked for in.
+    literal: usize,
+    /// Rolling checksum of the window, if it was computed already.
+    rolling: Option<RollingChecksum>,
+    /// Block that matches the window.
+    matched: Option<u32>,
+}
+
+impl Delta {
+    /// Delta of the file read from `offset` against the blocks with the
+    /// given `signatures`.
+    pub fn new(signatures: Signatures, offset: u64) -> Delta {
+        let mut blocks: HashMap<_, Vec<_>> = HashMap::new();
+        for (index, block) in signatures.blocks.into_iter().enumerate() {
+            blocks
+                .entry(block.rolling)
+                .or_default()
+                .push((index as u32, block.strong));
+        }
+
+        Delta {
+            block_size: signatures.block_size as usize,
+            blocks,
+            pending: Vec::new(),
+            offset,
+            literal: 0,
+            rolling: None,
+            matched: None,
+        }
+    }
+
+    /// Size of the blocks of the copy, which each copy covers.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Adds the data read from the file next.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+    }
+
+    /// Returns the next chunk, whose data is copied into `buf`, or `None`
+    /// if more data must be read to tell what comes next. Data chunks
+    /// are as large as `buf` unless followed by a copy.
+    pub fn next_chunk(&mut self, buf: &mut [u8]) -> Option<Chunk> {
+        loop {
+            if self.literal == buf.len() {
+                return Some(self.take_literal(buf));
+            }
+
+            if let Some(block) = self.matched {
+                if self.literal > 0 {
+                    return Some(self.take_literal(buf));
+                }
+
+                let copy = BlockCopy::new(self.offset, block);
+                self.pending.drain(..self.block_size);
+                self.offset += self.block_size as u64;
+                self.rolling = None;
+                self.matched = None;
+                return Some(Chunk::Copy(copy));
+            }
+
+            let end = self.literal + self.block_size;
+            if self.pending.len() < end {
+                return None;
+            }
+
+            let window = &self.pending[self.literal..end];
+            let rolling = self
+                .rolling
+                .get_or_insert_with(|| RollingChecksum::new(window));
+
+            if let Some(blocks) = self.blocks.get(&rolling.value()) {
+                let strong = strong_checksum(window);
+                if let Some(&(block, _)) = blocks.iter().find(|(_, other)| *other == strong) {
+                    self.matched = Some(block);
+                    continue;
+                }
+            }
+
+            match self.pending.get(end) {
+                Some(&added) => rolling.roll(self.pending[self.literal], added),
+                None => self.rolling = None,
+            }
+            self.literal += 1;
+        }
+    }
+
+    /// Returns the chunks left once the whole file was read, which are
+    /// data chunks but for the blocks found.
+    pub fn finish(&mut self, buf: &mut [u8]) -> Option<Chunk> {
+        if let Some(chunk) = self.next_chunk(buf) {
+            return Some(chunk);
+        }
+
+        self.literal = self.pending.len().min(buf.len());
+        if self.literal == 0 {
+            return None;
+        }
+        Some(self.take_literal(buf))
+    }
+
+    /// Turns the pending bytes that did not match into a data chunk.
+    fn take_literal(&mut self, buf: &mut [u8]) -> Chunk {
+        let length = self.literal;
+        let offset = self.offset;
+
+        buf[..length].copy_from_slice(&self.pending[..length]);
+        self.pending.drain(..length);
+        self.offset += length as u64;
+        self.literal = 0;
+
+        Chunk::Data(offset, length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use file_protocol::BlockSignature;
+
+    /// Runs the delta of `data` against the blocks of `basis`, feeding
+    /// the data in small pieces, and rebuilds the data from the chunks.
+    fn rebuild(basis: &[u8], data: &[u8], block_size: usize) -> (Vec<u8>, usize) {
+        let signatures = Signatures {
+            block_size: block_size as u32,
+            blocks: basis
+                .chunks_exact(block_size)
+                .map(BlockSignature::new)
+                .collect(),
+        };
+        let mut delta = Delta::new(signatures, 0);
+        let mut buf = [0u8; 100];
+        let mut rebuilt = Vec::new();
+        let mut copies = 0;
+
+        let mut append = |chunk, buf: &[u8], rebuilt: &mut Vec<u8>| match chunk {
+            Chunk::Data(offset, length) => {
+                assert_eq!(offset, rebuilt.len() as u64);
+                rebuilt.extend_from_slice(&buf[..length]);
+            }
+            Chunk::Copy(copy) => {
+                assert_eq!(copy.offset, rebuilt.len() as u64);
+                let start = copy.block as usize * block_size;
+                rebuilt.extend_from_slice(&basis[start..start + block_size]);
+                copies += 1;
+            }
+            chunk => panic!("unexpected chunk: {:?}", chunk),
+        };
+
+        for piece in data.chunks(37) {
+            delta.feed(piece);
+            while let Some(chunk) = delta.next_chunk(&mut buf) {
+                append(chunk, &buf, &mut rebuilt);
+            }
+        }
+        while let Some(chunk) = delta.finish(&mut buf) {
+            append(chunk, &buf, &mut rebuilt);
+        }
+
+        (rebuilt, copies)
+    }
+
+    #[test]
+    fn test_rebuild_modified_data() {
+        let basis: Vec<u8> = (0..10_000u32).map(|i| (i * 7919 % 251) as u8).collect();
+
+        let mut data = basis.clone();
+        data.splice(1234..1234, b"inserted".iter().copied());
+        data.drain(5000..5100);
+        data[8000] ^= 0xff;
+        data.extend_from_slice(b"appended");
+
+        let (rebuilt, copies) = rebuild(&basis, &data, 256);
+        assert_eq!(rebuilt, data);
+        // Only the blocks around the changes are missing.
+        assert!(copies >= 10_000 / 256 - 4, "{} copies", copies);
+
+        let (rebuilt, copies) = rebuild(&basis, b"short", 256);
+        assert_eq!(rebuilt, b"short");
+        assert_eq!(copies, 0);
+    }
+}
@@ -0,0 +1,65 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to establish a connection with the receiver.
+    Connect(io::Error),
+    /// The receiver sent a message that does not follow the protocol.
+    Protocol(String),
+    /// Failed to read the file or to communicate with the receiver.
+    Io(io::Error),
+    /// The file stored by the receiver does not match the original.
+    Verification,
+    /// The upload was cancelled.
+    Cancelled,
+    /// The TLS settings are invalid.
+    Tls(String),
+    /// The receiver refused the upload.
+    Rejected(String),
+    /// The name of the file can not be sent to the receiver.
+    FileName(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Connect(err) => write!(f, "failed to connect to the receiver: {}", err),
+            Error::Protocol(msg) => write!(f, "protocol violation: {}", msg),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Verification => write!(f, "received file does not match the original"),
+            Error::Cancelled => write!(f, "upload cancelled"),
+            Error::Tls(msg) => write!(f, "TLS error: {}", msg),
+            Error::Rejected(msg) => write!(f, "upload rejected: {}", msg),
+            Error::FileName(msg) => write!(f, "invalid file name: {}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Connect(err) | Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<file_protocol::Error> for Error {
+    fn from(err: file_protocol::Error) -> Error {
+        match err {
+            file_protocol::Error::Invalid(msg) | file_protocol::Error::Incompatible(msg) => {
+                Error::Protocol(msg)
+            }
+        }
+    }
+}
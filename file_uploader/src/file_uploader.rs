@@ -1,19 +1,56 @@
 use std::cmp;
-use std::fs::{metadata, File};
-use std::io::{self, prelude::*, ErrorKind, SeekFrom};
-use std::net::TcpStream;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, prelude::*, ErrorKind};
+use std::net;
+use std::panic;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::{Duration, Instant};
 
+use mio::net::TcpStream;
+use mio::{Events, Interest, Poll, Token};
+
+use crate::auth;
+use crate::backoff::Backoff;
+use crate::batch::{self, BatchFile};
+use file_protocol::{
+    chunk_checksum, ChunkHeader, ChunkKind, Compression, FlowControl, Hello, Response,
+    ResponseDecoder, Signatures, CAP_ATTRIBUTES, CAP_AUTH, CAP_DEDUP, CAP_DELTA, CAP_LINKS,
+    CAP_MULTI_FILE, CAP_STRIPES, CAP_WINDOW, CAP_XATTRS, DEFAULT_ACK_INTERVAL, DEFAULT_WINDOW,
+    MAX_CHUNK_SIZE, VERIFICATION_OK,
+};
+
+use crate::compressor::Compressor;
+use crate::error::{Error, Result};
+use crate::preserve::Preserve;
+use crate::protocol;
 use crate::rate_limit::RateLimitedStream;
+use crate::tls::{Connection, TlsConfig};
+use crate::transfer::{self, Chunk, Source, Transfer};
+use crate::zero_copy;
 
-const BUF_SIZE: usize = 1024;
+pub const BUF_SIZE: usize = 1024;
+/// Compression works better over larger chunks.
+const COMPRESSION_BUF_SIZE: usize = 32 * 1024;
+const CONNECTION: Token = Token(0);
+const POLLING_TIME: Duration = Duration::from_millis(200);
 
 pub struct FileUploader {
     host: String,
     port: u16,
     rate_limit: Option<u32>,
+    tls: Option<TlsConfig>,
+    secret: Option<Vec<u8>>,
+    preserve: Preserve,
+    compression: Option<Compression>,
+    delta: bool,
+    dedup: bool,
+    streams: u32,
+    window: u32,
+    ack_interval: u32,
+    cancelled: AtomicBool,
 }
 
 impl FileUploader {
@@ -22,168 +59,933 @@ impl FileUploader {
             host,
             port,
             rate_limit,
+            tls: None,
+            secret: None,
+            preserve: Preserve::default(),
+            compression: None,
+            delta: false,
+            dedup: false,
+            streams: 1,
+            window: DEFAULT_WINDOW,
+            ack_interval: DEFAULT_ACK_INTERVAL,
+            cancelled: AtomicBool::new(false),
         }
     }
 
-    pub fn upload(&self, file_name: impl AsRef<Path>) {
-        let mut stream = RateLimitedStream::new(self.connect(), self.rate_limit);
+    /// Uploads over TLS, authenticating the receiver with `tls`.
+    pub fn with_tls(mut self, tls: TlsConfig) -> FileUploader {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Authenticates with the secret shared with the receiver when it
+    /// requires one.
+    pub fn with_secret(mut self, secret: Vec<u8>) -> FileUploader {
+        self.secret = Some(secret);
+        self
+    }
+
+    /// Has the receiver apply the `preserve`d attributes of the files to
+    /// its copies, provided it supports doing so.
+    pub fn with_preserve(mut self, preserve: Preserve) -> FileUploader {
+        self.preserve = preserve;
+        self
+    }
+
+    /// Compresses the data sent with `compression`, provided the
+    /// receiver supports it.
+    pub fn with_compression(mut self, compression: Compression) -> FileUploader {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Sends the files the receiver already holds a copy of as a delta
+    /// against that copy, provided the receiver supports it, so that
+    /// only the parts of the files that changed are sent.
+    pub fn with_delta(mut self, delta: bool) -> FileUploader {
+        self.delta = delta;
+        self
+    }
+
+    /// Sends only the content-defined chunks of the files that are
+    /// missing from the store of the receiver, provided it keeps one.
+    pub fn with_dedup(mut self, dedup: bool) -> FileUploader {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Splits large files into up to `streams` ranges uploaded over as
+    /// many connections in parallel, provided the receiver supports it,
+    /// which fills links that a single connection can not. The rate
+    /// limit applies to each connection.
+    pub fn with_streams(mut self, streams: u32) -> FileUploader {
+        self.streams = cmp::max(streams, 1);
+        self
+    }
+
+    /// Sends at most `window` bytes without them being acknowledged,
+    /// provided the receiver supports it, which bounds the memory used
+    /// by the connection and the data sent again after a reconnection.
+    /// The window is raised to two acknowledgement intervals if smaller.
+    pub fn with_window(mut self, window: u32) -> FileUploader {
+        self.window = window;
+        self
+    }
 
-        let mut bytes_acknowledged = 0;
+    /// Asks the receiver to acknowledge the data at least every
+    /// `ack_interval` bytes, which it may do more often.
+    pub fn with_ack_interval(mut self, ack_interval: u32) -> FileUploader {
+        self.ack_interval = ack_interval;
+        self
+    }
+
+    /// Uploads a file, or all the files of a directory.
+    pub fn upload(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.upload_all(&[path])
+    }
+
+    /// Uploads several files and directories over a single session.
+    /// After a disconnection, the upload resumes from the first file that
+    /// was not verified by the receiver yet.
+    pub fn upload_all(&self, paths: &[impl AsRef<Path>]) -> Result<()> {
         let mut total_bytes_sent = 0;
 
-        let file_size = metadata(&file_name)
-            .expect("Failed to read file size")
-            .len();
+        self.cancelled.store(false, Ordering::Relaxed);
+
+        let files = batch::collect_files(paths)?;
+
+        let mut readiness = Readiness::new()?;
+
+        let (stream, mut reader, mut capabilities) = self.open_session(&readiness)?;
+        let mut stream = RateLimitedStream::new(stream, self.rate_limit);
 
-        let mut file = File::open(&file_name).expect("Failed to open the file");
+        let mut buf = vec![0u8; self.buf_size()];
 
-        let file_name = file_name.as_ref().to_string_lossy();
+        let now = Instant::now();
 
-        self.send_header(&mut stream, &file_name, file_size, 0);
+        for file in &files {
+            let stripes = match capabilities & CAP_STRIPES {
+                0 => None,
+                _ => file.stripes(self.streams),
+            };
+
+            total_bytes_sent += match stripes {
+                Some(stripes) => self.upload_stripes(
+                    &mut stream,
+                    &mut readiness,
+                    &mut reader,
+                    &mut capabilities,
+                    &stripes,
+                    &mut buf,
+                )?,
+                None => self.upload_file(
+                    &mut stream,
+                    &mut readiness,
+                    &mut reader,
+                    &mut capabilities,
+                    file,
+                    &mut buf,
+                )?,
+            };
+        }
 
-        let buf_size = match self.rate_limit {
-            Some(val) => cmp::min(val as usize, BUF_SIZE),
-            None => BUF_SIZE,
+        let secs = now.elapsed().as_secs_f64();
+        let upload_speed = total_bytes_sent as f64 / secs;
+
+        println!("File transfer completed");
+        println!("Elapsed time: {:.2} seconds", secs);
+        println!("Bytes transferred: {} bytes", total_bytes_sent);
+        println!("Average upload speed: {} bytes/sec", upload_speed.round());
+
+        Ok(())
+    }
+
+    /// Aborts the upload in progress, making `upload` return
+    /// `Error::Cancelled`.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Uploads the ranges of a striped file in parallel, the first one
+    /// over the session of the batch and the others over sessions of
+    /// their own, which are closed once their range is verified. The
+    /// upload of all the ranges is cancelled as soon as one of them
+    /// fails. Returns the number of bytes of the file that were sent.
+    fn upload_stripes(
+        &self,
+        stream: &mut RateLimitedStream<Connection<TcpStream>>,
+        readiness: &mut Readiness,
+        reader: &mut ResponseReader,
+        capabilities: &mut u32,
+        stripes: &[BatchFile],
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let cancel_on_error = |result: Result<usize>| {
+            if result.is_err() {
+                self.cancel();
+            }
+            result
         };
 
-        let mut buf = vec![0u8; buf_size];
-        let mut u64_buf = [0u8; 8];
+        let results = thread::scope(|scope| {
+            let threads: Vec<_> = stripes[1..]
+                .iter()
+                .map(|stripe| scope.spawn(move || cancel_on_error(self.upload_stripe(stripe))))
+                .collect();
+
+            let mut results = vec![cancel_on_error(self.upload_file(
+                stream,
+                readiness,
+                reader,
+                capabilities,
+                &stripes[0],
+                buf,
+            ))];
+            for thread in threads {
+                results.push(
+                    thread
+                        .join()
+                        .unwrap_or_else(|err| panic::resume_unwind(err)),
+                );
+            }
+            results
+        });
+
+        // The ranges cancelled because another one failed report the
+        // cancellation rather than the error that caused it.
+        let mut bytes_sent = 0;
+        let mut cancelled = false;
+        for result in results {
+            match result {
+                Ok(stripe_bytes_sent) => bytes_sent += stripe_bytes_sent,
+                Err(Error::Cancelled) => cancelled = true,
+                Err(err) => return Err(err),
+            }
+        }
 
-        let now = Instant::now();
+        if cancelled {
+            return Err(Error::Cancelled);
+        }
+
+        Ok(bytes_sent)
+    }
+
+    /// Uploads a range of a striped file over a session of its own.
+    fn upload_stripe(&self, file: &BatchFile) -> Result<usize> {
+        let mut readiness = Readiness::new()?;
+
+        let (stream, mut reader, mut capabilities) = self.open_session(&readiness)?;
+        let mut stream = RateLimitedStream::new(stream, self.rate_limit);
+
+        let mut buf = vec![0u8; self.buf_size()];
+
+        self.upload_file(
+            &mut stream,
+            &mut readiness,
+            &mut reader,
+            &mut capabilities,
+            file,
+            &mut buf,
+        )
+    }
+
+    /// Uploads a file of the batch, opening a new session whenever the
+    /// connection is lost, in which case `capabilities` is updated with
+    /// those of the new session. Returns the number of bytes of the file
+    /// that were sent.
+    fn upload_file(
+        &self,
+        stream: &mut RateLimitedStream<Connection<TcpStream>>,
+        readiness: &mut Readiness,
+        reader: &mut ResponseReader,
+        capabilities: &mut u32,
+        file: &BatchFile,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        if file.link.is_some() && *capabilities & CAP_LINKS == 0 {
+            eprintln!(
+                "Skipping link: {} (not supported by the receiver)",
+                file.path.display()
+            );
+            return Ok(0);
+        }
+
+        let (source, sendfile) = match self.is_zero_copy() {
+            true => file.open_zero_copy()?,
+            false => (file.open()?, None),
+        };
+
+        let (start, end) = file.range();
+        let mut transfer = Transfer::new(source, file.size)
+            .with_range(start, end)
+            .with_sendfile(sendfile);
+        let mut compressor = Compression::negotiated(*capabilities).map(Compressor::new);
+        let mut bytes_sent = 0;
+        let mut accepted = false;
+        let mut backoff = Backoff::new();
+
+        if file.stripe.is_whole() {
+            println!(
+                "Uploading file: {} (size={})",
+                file.path.display(),
+                file.size
+            );
+        } else {
+            println!(
+                "Uploading file: {} (size={}, range={}-{})",
+                file.path.display(),
+                file.size,
+                start,
+                end
+            );
+        }
 
         loop {
-            match stream.read_exact(&mut u64_buf) {
-                Ok(_) => {
-                    bytes_acknowledged = u64::from_be_bytes(u64_buf);
-                    self.update_progress_bar(bytes_acknowledged, file_size);
+            if self.cancelled.load(Ordering::Relaxed) {
+                return Err(Error::Cancelled);
+            }
+
+            let result = if !accepted {
+                self.start_file(stream, readiness, reader, *capabilities, file)
+                    .and_then(|(offset, signatures)| {
+                        transfer.resume(offset)?;
+                        transfer.set_window(self.window(*capabilities));
+                        match signatures {
+                            Some(signatures) => transfer.send_as_delta(signatures),
+                            None if *capabilities & CAP_DEDUP != 0
+                                && file.link.is_none()
+                                && file.stripe.is_whole() =>
+                            {
+                                transfer.deduplicate()
+                            }
+                            None => {}
+                        }
+                        accepted = true;
+                        backoff.reset();
+                        Ok(false)
+                    })
+            } else if !transfer.is_acknowledged() {
+                let compressor = compressor.as_mut();
+                self.send_next_chunk(stream, readiness, reader, &mut transfer, compressor, buf)
+                    .map(|chunk_size| {
+                        bytes_sent += chunk_size;
+                        false
+                    })
+            } else {
+                let digest = transfer.digest();
+                self.verify(stream, readiness, reader, &digest)
+                    .map(|_| true)
+            };
+
+            match result {
+                Ok(true) => return Ok(bytes_sent),
+                Ok(false) => {}
+                Err(Error::Io(err)) if is_connection_lost(&err) => {
+                    // The receiver closes the connection before accepting
+                    // a file that is busy receiving from another one,
+                    // which is retried for a while before giving up.
+                    if accepted {
+                        eprintln!("Connection reset");
+                    } else {
+                        let delay = backoff.next_delay()?;
+                        eprintln!("Connection closed by the receiver. Retrying...");
+                        thread::sleep(delay);
+                    }
+
+                    let (new_stream, new_reader, new_capabilities) =
+                        self.open_session(readiness)?;
+                    stream.update_stream(new_stream);
+                    *reader = new_reader;
+                    *capabilities = new_capabilities;
+                    compressor = Compression::negotiated(new_capabilities).map(Compressor::new);
+                    accepted = false;
                 }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn connect(&self) -> Result<net::TcpStream> {
+        let addr = format!("{}:{}", self.host, self.port);
+
+        let stream = loop {
+            if self.cancelled.load(Ordering::Relaxed) {
+                return Err(Error::Cancelled);
+            }
+
+            match net::TcpStream::connect(&addr) {
+                Ok(stream) => break stream,
                 Err(err) => match err.kind() {
-                    ErrorKind::WouldBlock => {}
-                    _ => eprintln!("WARNING: failed to read acknowledgement: {}", err),
+                    // A connection accepted by a receiver that is shutting
+                    // down is reset before it can be used.
+                    ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset => {
+                        eprintln!("Connection refused. Retrying...");
+                        thread::sleep(Duration::from_secs(1));
+                    }
+                    _ => return Err(Error::Connect(err)),
                 },
             }
+        };
+
+        println!("Connection established with: {}", stream.peer_addr()?);
+
+        Ok(stream)
+    }
+
+    /// Connects to the receiver and agrees on the protocol, returning a
+    /// stream that is non-blocking and registered for readiness events,
+    /// which the upload waits for whenever it can not progress, along
+    /// with the reader of the responses sent over it, which holds on to
+    /// those received during the handshake, and the capabilities of the
+    /// session.
+    ///
+    /// A connection closed during the handshake is retried for a while,
+    /// as the receiver might be shutting down.
+    fn open_session(
+        &self,
+        readiness: &Readiness,
+    ) -> Result<(Connection<TcpStream>, ResponseReader, u32)> {
+        let mut backoff = Backoff::new();
+
+        loop {
+            let stream = self.connect()?;
+            let mut stream = match &self.tls {
+                Some(tls) => tls.connect(&self.host, stream)?,
+                None => Connection::Plain(stream),
+            };
+
+            let mut reader = ResponseReader::new();
+
+            match self.negotiate(&mut stream, &mut reader) {
+                Ok(capabilities) => {
+                    let mut stream = stream.map(|stream| {
+                        stream.set_nonblocking(true)?;
+                        Ok(TcpStream::from_std(stream))
+                    })?;
+                    readiness.register(stream.socket())?;
+                    return Ok((stream, reader, capabilities));
+                }
+                Err(Error::Io(err)) if is_connection_lost(&err) => {
+                    let delay = backoff.next_delay()?;
+                    eprintln!("Connection closed by the receiver. Retrying...");
+                    thread::sleep(delay);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Agrees with the receiver on the version of the protocol and on
+    /// the capabilities to use, returning the capabilities.
+    fn negotiate(
+        &self,
+        stream: &mut Connection<net::TcpStream>,
+        reader: &mut ResponseReader,
+    ) -> Result<u32> {
+        let hello = Hello::new(self.capabilities());
+        stream.write_all(&hello.encode())?;
+
+        let capabilities = match reader.read(stream)? {
+            Response::Hello(version, capabilities) => {
+                hello.validate_agreement(version, capabilities)?
+            }
+            Response::Error(message) => return Err(Error::Rejected(message)),
+            _ => {
+                return Err(Error::Protocol(
+                    "unexpected response to the hello".to_string(),
+                ))
+            }
+        };
+
+        if capabilities & CAP_WINDOW != 0 {
+            let flow_control = self.flow_control();
+            stream.write_all(&flow_control.encode())?;
 
-            let bytes_read = file.read(&mut buf[..]).expect("Failed to read");
-            if bytes_read == 0 {
-                break;
+            match reader.read(stream)? {
+                Response::AckInterval(ack_interval) => {
+                    flow_control.validate_agreement(ack_interval)?;
+                }
+                Response::Error(message) => return Err(Error::Rejected(message)),
+                _ => {
+                    return Err(Error::Protocol(
+                        "unexpected response to the flow control".to_string(),
+                    ))
+                }
             }
+        }
 
-            let mut bytes_sent = 0;
+        Ok(capabilities)
+    }
 
-            while bytes_sent != bytes_read {
-                match stream.write(&buf[bytes_sent..bytes_read]) {
-                    Ok(size) => {
-                        bytes_sent += size;
-                        total_bytes_sent += size;
-                    }
-                    Err(e) => match e.kind() {
-                        ErrorKind::WouldBlock => {}
-                        ErrorKind::ConnectionReset | ErrorKind::BrokenPipe => {
-                            eprintln!("Connection reset");
-                            file.seek(SeekFrom::Start(bytes_acknowledged))
-                                .expect("Failed to seek");
-                            stream.update_stream(self.connect());
-                            self.send_header(
-                                &mut stream,
-                                &file_name,
-                                file_size,
-                                bytes_acknowledged,
-                            );
-                            break;
+    fn flow_control(&self) -> FlowControl {
+        FlowControl::new(self.window, self.ack_interval)
+    }
+
+    /// Returns the window of the sessions with the given capabilities,
+    /// which is only bounded when the receiver supports it.
+    fn window(&self, capabilities: u32) -> Option<u32> {
+        match capabilities & CAP_WINDOW {
+            0 => None,
+            _ => Some(self.flow_control().window),
+        }
+    }
+
+    /// Returns whether the data of regular files is sent with `sendfile`.
+    fn is_zero_copy(&self) -> bool {
+        zero_copy::is_possible(
+            self.rate_limit.is_some(),
+            self.compression.is_some(),
+            self.tls.is_some(),
+        )
+    }
+
+    /// Returns the size of the chunks to send, which are as large as the
+    /// protocol allows when sent with `sendfile`, to make the most of
+    /// each call.
+    fn buf_size(&self) -> usize {
+        match self.is_zero_copy() {
+            true => MAX_CHUNK_SIZE,
+            false => buf_size(self.rate_limit, self.compression),
+        }
+    }
+
+    fn capabilities(&self) -> u32 {
+        let mut capabilities = CAP_MULTI_FILE | CAP_LINKS | CAP_WINDOW;
+
+        if self.secret.is_some() {
+            capabilities |= CAP_AUTH;
+        }
+        if !self.preserve.is_empty() {
+            capabilities |= CAP_ATTRIBUTES;
+        }
+        if self.preserve.xattrs {
+            capabilities |= CAP_XATTRS;
+        }
+        if let Some(compression) = self.compression {
+            capabilities |= compression.capability();
+        }
+        if self.delta {
+            capabilities |= CAP_DELTA;
+        }
+        if self.dedup {
+            capabilities |= CAP_DEDUP;
+        }
+        if self.streams > 1 {
+            capabilities |= CAP_STRIPES;
+        }
+
+        capabilities
+    }
+
+    /// Sends the header of the file, followed by its kind and attributes
+    /// as agreed for the session, and waits for the receiver to accept
+    /// it, answering its challenge when it requires a shared secret.
+    /// Returns the offset the upload must resume from, along with the
+    /// signatures of the copy of the file the receiver holds when the
+    /// file is to be sent as a delta.
+    ///
+    /// The offset is chosen by the receiver, as it is the only side
+    /// that knows how many bytes of the file it already holds.
+    fn start_file(
+        &self,
+        stream: &mut RateLimitedStream<Connection<TcpStream>>,
+        readiness: &mut Readiness,
+        reader: &mut ResponseReader,
+        capabilities: u32,
+        file: &BatchFile,
+    ) -> Result<(u64, Option<Signatures>)> {
+        let header = file.encode_header(capabilities, &self.preserve)?;
+        self.send_all(stream, readiness, &header)?;
+
+        let mut signatures = None;
+
+        loop {
+            while let Some(response) = reader.poll(stream)? {
+                match response {
+                    Response::Accept(offset) => {
+                        let file_offset = protocol::validate_offset(offset, file.range())?;
+
+                        if file_offset > file.range().0 {
+                            println!("Resuming upload from offset: {}", file_offset);
                         }
-                        _ => panic!("Unhandled error: {}", e),
-                    },
+
+                        return Ok((file_offset, signatures));
+                    }
+                    Response::Signatures(blocks) if capabilities & CAP_DELTA != 0 => {
+                        signatures = Some(blocks);
+                    }
+                    Response::Challenge(nonce) => {
+                        let answer = auth::answer_challenge(self.secret.as_deref(), &nonce)?;
+                        self.send_all(stream, readiness, &answer)?;
+                    }
+                    Response::Error(message) => return Err(Error::Rejected(message)),
+                    _ => {
+                        return Err(Error::Protocol(
+                            "unexpected response to the header".to_string(),
+                        ))
+                    }
+                }
+            }
+
+            self.wait(readiness)?;
+        }
+    }
+
+    /// Processes the responses received so far and sends the next
+    /// chunk, giving priority to the chunks that must be retransmitted.
+    /// Waits for the stream to become ready when there is nothing left
+    /// to send. Returns the number of bytes of the file that were sent,
+    /// which excludes the blocks and chunks the receiver already holds.
+    fn send_next_chunk(
+        &self,
+        stream: &mut RateLimitedStream<Connection<TcpStream>>,
+        readiness: &mut Readiness,
+        reader: &mut ResponseReader,
+        transfer: &mut Transfer<Box<dyn Source>>,
+        compressor: Option<&mut Compressor>,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        while let Some(response) = reader.poll(stream)? {
+            transfer.handle_response(response)?;
+        }
+
+        match transfer.next_chunk(buf)? {
+            Some(Chunk::Data(offset, length)) => {
+                if transfer.has_chunk_kinds() {
+                    self.send_all(stream, readiness, &[ChunkKind::Data.encode()])?;
                 }
+                let data = &buf[..length];
+                let sendfile = transfer.sendfile();
+                self.send_chunk(stream, readiness, compressor, sendfile, offset, data)?;
+                Ok(length)
+            }
+            Some(Chunk::Copy(copy)) => {
+                self.send_all(stream, readiness, &[ChunkKind::Copy.encode()])?;
+                self.send_all(stream, readiness, &copy.encode())?;
+                Ok(0)
+            }
+            Some(Chunk::Query(query)) => {
+                self.send_all(stream, readiness, &[ChunkKind::Query.encode()])?;
+                self.send_all(stream, readiness, &query.encode())?;
+                Ok(0)
+            }
+            Some(Chunk::Stored(chunk)) => {
+                self.send_all(stream, readiness, &[ChunkKind::Stored.encode()])?;
+                self.send_all(stream, readiness, &chunk.encode())?;
+                Ok(0)
+            }
+            None => {
+                self.wait(readiness)?;
+                Ok(0)
             }
         }
+    }
 
-        while bytes_acknowledged != file_size {
-            match stream.read_exact(&mut u64_buf) {
-                Ok(_) => {
-                    bytes_acknowledged = u64::from_be_bytes(u64_buf);
-                    self.update_progress_bar(bytes_acknowledged, file_size);
+    /// Sends a chunk of the file framed with its offset, its length and
+    /// the checksums that allow the receiver to detect corrupted data.
+    /// The data is compressed with the `compressor` of sessions that
+    /// agreed on a compression, and otherwise sent from the `sendfile`
+    /// handle of the file over plain connections, if any. The `data` is
+    /// still read from the same handle, for its checksum and the digest
+    /// of the file, but the copy into the socket is spared.
+    fn send_chunk(
+        &self,
+        stream: &mut RateLimitedStream<Connection<TcpStream>>,
+        readiness: &mut Readiness,
+        compressor: Option<&mut Compressor>,
+        sendfile: Option<&File>,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        let header = ChunkHeader::new(offset, data.len());
+
+        match compressor {
+            Some(compressor) => match compressor.compress(data) {
+                Some(compressed) => {
+                    let header = header.with_compressed_length(compressed.len());
+                    self.send_all(stream, readiness, &header.encode_compressed())?;
+                    self.send_all(stream, readiness, compressed)?;
                 }
+                None => {
+                    self.send_all(stream, readiness, &header.encode_compressed())?;
+                    self.send_all(stream, readiness, data)?;
+                }
+            },
+            None => {
+                self.send_all(stream, readiness, &header.encode())?;
+                match (sendfile, stream.get_mut()) {
+                    (Some(file), Connection::Plain(_)) => {
+                        self.send_file(stream, readiness, file, offset, data.len())?
+                    }
+                    _ => self.send_all(stream, readiness, data)?,
+                }
+            }
+        }
+
+        self.send_all(stream, readiness, &chunk_checksum(data))
+    }
+
+    /// Sends `length` bytes of `file` from `offset` over a plain
+    /// connection, straight from the page cache.
+    fn send_file(
+        &self,
+        stream: &mut RateLimitedStream<Connection<TcpStream>>,
+        readiness: &mut Readiness,
+        file: &File,
+        offset: u64,
+        length: usize,
+    ) -> Result<()> {
+        let mut bytes_sent = 0;
+
+        while bytes_sent != length {
+            let socket = stream.get_mut().socket();
+            let remaining = length - bytes_sent;
+            match zero_copy::send_file(socket, file, offset + bytes_sent as u64, remaining) {
+                Ok(0) => return Err(transfer::file_truncated()),
+                Ok(size) => bytes_sent += size,
                 Err(err) => match err.kind() {
-                    ErrorKind::WouldBlock => {}
-                    _ => eprintln!("WARNING: failed to read acknowledgement: {}", err),
+                    ErrorKind::WouldBlock => self.wait(readiness)?,
+                    _ => return Err(Error::Io(err)),
                 },
             }
         }
 
-        let secs = now.elapsed().as_secs_f64();
-        let upload_speed = total_bytes_sent as f64 / secs;
+        Ok(())
+    }
 
-        println!("File transfer completed");
-        println!("Elapsed time: {:.2} seconds", secs);
-        println!("Bytes transferred: {} bytes", total_bytes_sent);
-        println!("Average upload speed: {} bytes/sec", upload_speed.round());
+    fn send_all(
+        &self,
+        stream: &mut RateLimitedStream<Connection<TcpStream>>,
+        readiness: &mut Readiness,
+        buf: &[u8],
+    ) -> Result<()> {
+        let mut bytes_sent = 0;
+
+        while bytes_sent != buf.len() {
+            match stream.write(&buf[bytes_sent..]) {
+                Ok(size) => bytes_sent += size,
+                Err(err) => match err.kind() {
+                    ErrorKind::WouldBlock => self.wait(readiness)?,
+                    _ => return Err(Error::Io(err)),
+                },
+            }
+        }
+
+        Ok(())
     }
 
-    fn connect(&self) -> TcpStream {
-        let addr = format!("{}:{}", self.host, self.port);
-        let mut stream;
+    /// Sends the digest of the whole file and waits for the receiver
+    /// to confirm that it matches the digest of the data it stored.
+    fn verify(
+        &self,
+        stream: &mut RateLimitedStream<Connection<TcpStream>>,
+        readiness: &mut Readiness,
+        reader: &mut ResponseReader,
+        digest: &[u8],
+    ) -> Result<()> {
+        self.send_all(stream, readiness, digest)?;
+
+        loop {
+            while let Some(response) = reader.poll(stream)? {
+                match response {
+                    Response::Verification(VERIFICATION_OK) => {
+                        println!("File verified successfully");
+                        return Ok(());
+                    }
+                    Response::Verification(_) => return Err(Error::Verification),
+                    Response::Error(message) => return Err(Error::Rejected(message)),
+                    _ => {}
+                }
+            }
+
+            self.wait(readiness)?;
+        }
+    }
+
+    /// Waits for the stream to become ready, waking up periodically to
+    /// check whether the upload was cancelled.
+    fn wait(&self, readiness: &mut Readiness) -> Result<()> {
+        readiness.wait(POLLING_TIME)?;
+
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(Error::Cancelled);
+        }
+
+        Ok(())
+    }
+}
+
+/// Readiness events of the connection with the receiver. The stream is
+/// registered in edge-triggered mode, so it must be read or written
+/// until it would block before waiting for the next event.
+struct Readiness {
+    poll: Poll,
+    events: Events,
+}
+
+impl Readiness {
+    fn new() -> io::Result<Readiness> {
+        Ok(Readiness {
+            poll: Poll::new()?,
+            events: Events::with_capacity(8),
+        })
+    }
+
+    /// Registers a new connection, replacing the previous one, which is
+    /// deregistered when its stream is closed.
+    fn register(&self, stream: &mut TcpStream) -> io::Result<()> {
+        self.poll
+            .registry()
+            .register(stream, CONNECTION, Interest::READABLE | Interest::WRITABLE)
+    }
+
+    fn wait(&mut self, timeout: Duration) -> io::Result<()> {
+        match self.poll.poll(&mut self.events, Some(timeout)) {
+            Err(err) if err.kind() != ErrorKind::Interrupted => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reads the responses sent by the receiver over a connection. The
+/// responses are decoded as the data arrives and handed over one at a
+/// time, so that none is lost when several arrive together.
+pub struct ResponseReader {
+    decoder: ResponseDecoder,
+    queued: VecDeque<Response>,
+}
+
+impl ResponseReader {
+    pub fn new() -> ResponseReader {
+        ResponseReader {
+            decoder: ResponseDecoder::new(),
+            queued: VecDeque::new(),
+        }
+    }
+
+    /// Decodes the responses completed by `data`, read from the
+    /// connection, queueing them in the order they were sent.
+    pub fn feed(&mut self, data: &[u8]) -> Result<()> {
+        self.decoder.feed(data);
+        self.queued.extend(self.decoder.decode()?);
+        Ok(())
+    }
+
+    /// Returns the oldest response that was not handed over yet.
+    pub fn pop(&mut self) -> Option<Response> {
+        self.queued.pop_front()
+    }
+
+    /// Returns the next response if it was received already, reading
+    /// from the non-blocking `stream` when none is queued.
+    fn poll(&mut self, stream: &mut impl Read) -> Result<Option<Response>> {
+        if let Some(response) = self.pop() {
+            return Ok(Some(response));
+        }
+
+        let mut read_buf = [0u8; 64];
+        let mut closed = None;
 
         loop {
-            stream = TcpStream::connect(&addr);
-            match stream {
+            match stream.read(&mut read_buf) {
+                Ok(0) => {
+                    closed = Some(connection_closed());
+                    break;
+                }
+                Ok(size) => self.feed(&read_buf[..size])?,
                 Err(err) => match err.kind() {
-                    ErrorKind::ConnectionRefused => {
-                        eprintln!("Connection refused. Retrying...");
-                        thread::sleep(Duration::from_secs(1));
+                    ErrorKind::WouldBlock => break,
+                    _ => {
+                        closed = Some(err);
+                        break;
                     }
-                    _ => panic!("Unhandled error: {}", err),
                 },
-                Ok(_) => break,
             }
         }
 
-        let stream = stream.unwrap();
+        // The responses sent right before the connection was closed or
+        // reset, such as the result of the verification, are still
+        // delivered. The next poll reports the closure.
+        match (self.pop(), closed) {
+            (None, Some(err)) => Err(Error::Io(err)),
+            (response, _) => Ok(response),
+        }
+    }
 
-        println!(
-            "Connection established with: {}",
-            stream.peer_addr().unwrap()
-        );
+    /// Reads from the blocking `stream` until a whole response is
+    /// received, unless one is queued already.
+    fn read(&mut self, stream: &mut impl Read) -> Result<Response> {
+        let mut read_buf = [0u8; 64];
+
+        loop {
+            if let Some(response) = self.pop() {
+                return Ok(response);
+            }
 
-        stream
-            .set_nonblocking(true)
-            .expect("set_nonblocking call failed");
+            let size = stream.read(&mut read_buf)?;
+            if size == 0 {
+                return Err(Error::Io(connection_closed()));
+            }
 
-        stream
+            self.feed(&read_buf[..size])?;
+        }
     }
+}
 
-    fn send_header(
-        &self,
-        stream: &mut RateLimitedStream<TcpStream>,
-        file_name: &str,
-        file_size: u64,
-        file_offset: u64,
-    ) {
-        stream
-            .write(&(file_name.len() as u8).to_be_bytes())
-            .expect("Failed to send file name length");
-
-        stream
-            .write(file_name.as_bytes())
-            .expect("Failed to send file name");
-
-        stream
-            .write(&file_size.to_be_bytes())
-            .expect("Failed to send file size");
-
-        stream
-            .write(&file_offset.to_be_bytes())
-            .expect("Failed to send file offset");
-    }
-
-    fn update_progress_bar(&self, bytes_acknowledged: u64, file_size: u64) {
-        let percentage = bytes_acknowledged as f64 / file_size as f64 * 100.0;
-        let progress = "=".repeat(percentage as usize / 2);
-        print!("\r[{:50}] {:.2}%", progress, percentage);
-        io::stdout().flush().unwrap();
-
-        if bytes_acknowledged == file_size {
-            print!("\x1B[2K\r"); // Clear current line
-            io::stdout().flush().unwrap();
-        }
+fn connection_closed() -> io::Error {
+    io::Error::new(
+        ErrorKind::UnexpectedEof,
+        "Connection closed by the receiver",
+    )
+}
+
+/// Returns the size of the chunks to send, which must not exceed the
+/// number of bytes that can be sent per second.
+pub fn buf_size(rate_limit: Option<u32>, compression: Option<Compression>) -> usize {
+    let buf_size = match compression {
+        Some(_) => COMPRESSION_BUF_SIZE,
+        None => BUF_SIZE,
+    };
+
+    match rate_limit {
+        Some(val) => cmp::min(val as usize, buf_size),
+        None => buf_size,
+    }
+}
+
+pub fn is_connection_lost(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_responses_read_together() {
+        let mut data = Response::Hello(1, 0).encode();
+        data.extend(Response::AckInterval(4096).encode());
+        data.extend(Response::Accept(0).encode());
+        data.extend(Response::Ack(1000).encode());
+        let mut stream = io::Cursor::new(data);
+
+        // The responses received along with the one waited for are
+        // handed over one at a time, and only then is the closure of
+        // the connection reported.
+        let mut reader = ResponseReader::new();
+        assert_eq!(reader.read(&mut stream).unwrap(), Response::Hello(1, 0));
+        assert_eq!(
+            reader.read(&mut stream).unwrap(),
+            Response::AckInterval(4096)
+        );
+        assert_eq!(reader.poll(&mut stream).unwrap(), Some(Response::Accept(0)));
+        assert_eq!(reader.poll(&mut stream).unwrap(), Some(Response::Ack(1000)));
+        assert!(matches!(reader.poll(&mut stream), Err(Error::Io(_))));
     }
 }
@@ -1,4 +1,27 @@
+#[cfg(feature = "tokio")]
+mod async_file_uploader;
+#[cfg(feature = "tokio")]
+mod async_rate_limit;
+mod auth;
+mod backoff;
+mod batch;
+mod compressor;
+mod dedup;
+mod delta;
+mod error;
 mod file_uploader;
+mod preserve;
+mod protocol;
 mod rate_limit;
+mod tls;
+mod transfer;
+mod xattrs;
+mod zero_copy;
 
+#[cfg(feature = "tokio")]
+pub use crate::async_file_uploader::AsyncFileUploader;
+pub use crate::error::{Error, Result};
 pub use crate::file_uploader::FileUploader;
+pub use crate::preserve::Preserve;
+pub use crate::tls::TlsConfig;
+pub use file_protocol::Compression;
@@ -1,11 +1,14 @@
 use std::path::PathBuf;
+use std::process;
 
 use structopt::StructOpt;
 
-use file_uploader::FileUploader;
+use file_protocol::read_secret;
+
+use file_uploader::{Compression, Error, FileUploader, Preserve, Result, TlsConfig};
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "fileuploader", about = "Uploads a file")]
+#[structopt(name = "fileuploader", about = "Uploads files and directories")]
 struct Cli {
     #[structopt(long)]
     host: String,
@@ -16,13 +19,160 @@ struct Cli {
     #[structopt(long)]
     rate_limit: Option<u32>,
 
-    #[structopt(parse(from_os_str), name = "FILE")]
-    file_name: PathBuf,
+    /// Uploads over TLS, trusting the certificate authorities in this PEM file
+    #[structopt(long, parse(from_os_str), conflicts_with = "pinned-cert")]
+    ca_file: Option<PathBuf>,
+
+    /// Uploads over TLS, trusting only the certificate in this PEM file
+    #[structopt(long, parse(from_os_str))]
+    pinned_cert: Option<PathBuf>,
+
+    /// Name the certificate of the receiver must be valid for, if not the host
+    #[structopt(long)]
+    server_name: Option<String>,
+
+    /// Authenticates with the certificate chain in this PEM file
+    #[structopt(long, parse(from_os_str), requires = "key")]
+    cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the certificate
+    #[structopt(long, parse(from_os_str), requires = "cert")]
+    key: Option<PathBuf>,
+
+    /// File with the secret shared with the receiver, which is read from
+    /// the FILE_UPLOAD_SECRET environment variable if not given
+    #[structopt(long, parse(from_os_str))]
+    secret_file: Option<PathBuf>,
+
+    /// Preserves the permissions of the files
+    #[structopt(long)]
+    preserve_mode: bool,
+
+    /// Preserves the modification time of the files
+    #[structopt(long)]
+    preserve_mtime: bool,
+
+    /// Preserves the user and group of the files, provided the receiver
+    /// runs with the privileges to change them
+    #[structopt(long)]
+    preserve_owner: bool,
+
+    /// Preserves the extended attributes of the files, including their
+    /// POSIX ACLs
+    #[structopt(long)]
+    preserve_xattrs: bool,
+
+    /// Compresses the data sent with zstd, lz4 or gzip, provided the
+    /// receiver supports it
+    #[structopt(long)]
+    compression: Option<Compression>,
+
+    /// Sends only the parts that changed of the files the receiver
+    /// already holds a copy of
+    #[structopt(long)]
+    delta: bool,
+
+    /// Sends only the parts of the files that are missing from the chunk
+    /// store of the receiver
+    #[structopt(long)]
+    dedup: bool,
+
+    /// Uploads large files over up to this number of connections in
+    /// parallel, each of them rate limited
+    #[structopt(long, default_value = "1")]
+    streams: u32,
+
+    /// Maximum number of bytes sent without them being acknowledged,
+    /// 8 MiB by default
+    #[structopt(long)]
+    window: Option<u32>,
+
+    /// Number of bytes after which the receiver is asked to acknowledge
+    /// the data, 1 MiB by default
+    #[structopt(long)]
+    ack_interval: Option<u32>,
+
+    /// Files and directories to upload, which are sent over a single
+    /// connection
+    #[structopt(parse(from_os_str), name = "FILE", required = true)]
+    file_names: Vec<PathBuf>,
 }
 
 fn main() {
     let args = Cli::from_args();
 
-    let uploader = FileUploader::new(args.host, args.port, args.rate_limit);
-    uploader.upload(args.file_name);
+    let tls = match tls_config(&args) {
+        Ok(tls) => tls,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+    };
+
+    let secret = match read_secret(args.secret_file.as_deref()) {
+        Ok(secret) => secret,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+    };
+
+    let mut uploader = FileUploader::new(args.host, args.port, args.rate_limit)
+        .with_preserve(Preserve {
+            mode: args.preserve_mode,
+            mtime: args.preserve_mtime,
+            owner: args.preserve_owner,
+            xattrs: args.preserve_xattrs,
+        })
+        .with_delta(args.delta)
+        .with_dedup(args.dedup)
+        .with_streams(args.streams);
+
+    if let Some(tls) = tls {
+        uploader = uploader.with_tls(tls);
+    }
+
+    if let Some(secret) = secret {
+        uploader = uploader.with_secret(secret);
+    }
+
+    if let Some(compression) = args.compression {
+        uploader = uploader.with_compression(compression);
+    }
+
+    if let Some(window) = args.window {
+        uploader = uploader.with_window(window);
+    }
+
+    if let Some(ack_interval) = args.ack_interval {
+        uploader = uploader.with_ack_interval(ack_interval);
+    }
+
+    if let Err(err) = uploader.upload_all(&args.file_names) {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+}
+
+fn tls_config(args: &Cli) -> Result<Option<TlsConfig>> {
+    let mut tls = match (&args.ca_file, &args.pinned_cert) {
+        (Some(ca_file), _) => TlsConfig::from_ca_file(ca_file)?,
+        (_, Some(pinned_cert)) => TlsConfig::from_pinned_cert(pinned_cert)?,
+        _ if args.cert.is_some() => {
+            return Err(Error::Tls(
+                "a client certificate requires --ca-file or --pinned-cert".to_string(),
+            ))
+        }
+        _ => return Ok(None),
+    };
+
+    if let Some(server_name) = &args.server_name {
+        tls = tls.with_server_name(server_name.clone());
+    }
+
+    if let (Some(cert), Some(key)) = (&args.cert, &args.key) {
+        tls = tls.with_client_cert(cert, key)?;
+    }
+
+    Ok(Some(tls))
 }
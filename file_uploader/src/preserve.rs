@@ -0,0 +1,86 @@
+//! Metadata of the uploaded files that the receiver applies to its
+//! copies.
+
+use std::fs::Metadata;
+
+use file_protocol::Attributes;
+
+/// Attributes of the files to preserve. None are preserved by default,
+/// so the received files get the permissions and modification time the
+/// receiver gives to new files.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Preserve {
+    /// Preserves the permission bits.
+    pub mode: bool,
+    /// Preserves the modification time.
+    pub mtime: bool,
+    /// Preserves the user and group, which only a privileged receiver
+    /// is able to do.
+    pub owner: bool,
+    /// Preserves the extended attributes, including the POSIX ACLs.
+    pub xattrs: bool,
+}
+
+impl Preserve {
+    /// Preserves all the attributes.
+    pub fn all() -> Preserve {
+        Preserve {
+            mode: true,
+            mtime: true,
+            owner: true,
+            xattrs: true,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Preserve::default()
+    }
+
+    /// Returns the attributes to send among those of a file.
+    pub(crate) fn select(&self, attributes: &Attributes) -> Attributes {
+        Attributes {
+            mode: attributes.mode.filter(|_| self.mode),
+            mtime: attributes.mtime.filter(|_| self.mtime),
+            owner: attributes.owner.filter(|_| self.owner),
+        }
+    }
+}
+
+/// Returns the attributes of a file given its `metadata`. Only the
+/// modification time is known outside of Unix.
+pub(crate) fn attributes(metadata: &Metadata) -> Attributes {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        Attributes {
+            mode: Some(metadata.mode() & 0o7777),
+            mtime: Some((metadata.mtime(), metadata.mtime_nsec() as u32)),
+            owner: Some((metadata.uid(), metadata.gid())),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        use std::time::UNIX_EPOCH;
+
+        let mtime = metadata
+            .modified()
+            .ok()
+            .map(|mtime| match mtime.duration_since(UNIX_EPOCH) {
+                Ok(elapsed) => (elapsed.as_secs() as i64, elapsed.subsec_nanos()),
+                Err(err) => {
+                    let before = err.duration();
+                    match before.subsec_nanos() {
+                        0 => (-(before.as_secs() as i64), 0),
+                        nanos => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+                    }
+                }
+            });
+
+        Attributes {
+            mtime,
+            ..Attributes::default()
+        }
+    }
+}
@@ -0,0 +1,17 @@
+//! Checks on the messages sent by the receiver that depend on the state
+//! of the upload.
+
+use crate::error::{Error, Result};
+
+/// Validates the offset sent by the receiver in reply to the header,
+/// which must be within the range of the file being uploaded.
+pub fn validate_offset(offset: u64, (start, end): (u64, u64)) -> Result<u64> {
+    if offset < start || offset > end {
+        return Err(Error::Protocol(format!(
+            "invalid offset: {} (range={}-{})",
+            offset, start, end
+        )));
+    }
+
+    Ok(offset)
+}
@@ -1,12 +1,11 @@
+use std::cmp;
 use std::io;
 use std::io::prelude::*;
 use std::time::{Duration, Instant};
 
 pub struct RateLimitedStream<T> {
     stream: T,
-    token_rate: Option<u32>,
-    available_tokens: f64,
-    last_updated: Instant,
+    bucket: Option<TokenBucket>,
 }
 
 impl<T: Read> Read for RateLimitedStream<T> {
@@ -18,45 +17,89 @@ impl<T: Read> Read for RateLimitedStream<T> {
 impl<T: Write> RateLimitedStream<T> {
     pub fn new(stream: T, token_rate: Option<u32>) -> RateLimitedStream<T> {
         RateLimitedStream {
-            token_rate,
-            available_tokens: 0.0,
-            last_updated: Instant::now(),
             stream,
+            bucket: token_rate.map(TokenBucket::new),
         }
     }
 
     pub fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if self.token_rate.is_some() {
-            self.reserve(buf.len());
-        }
-        self.stream.write(buf)
+        let bucket = match &mut self.bucket {
+            Some(bucket) => bucket,
+            None => return self.stream.write(buf),
+        };
+
+        // Writes larger than the capacity of the bucket are allowed to
+        // be partial, so they are shortened instead of rejected.
+        let buf = &buf[..cmp::min(buf.len(), bucket.capacity())];
+        bucket.wait(buf.len());
+
+        // Only the bytes actually written consume tokens.
+        let size = self.stream.write(buf)?;
+        bucket.consume(size);
+        Ok(size)
     }
 
     pub fn update_stream(&mut self, stream: T) {
         self.stream = stream;
     }
 
-    fn reserve(&mut self, required_tokens: usize) {
-        let required_tokens = required_tokens as f64;
+    /// Returns the underlying stream, which bypasses the rate limit.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.stream
+    }
+}
 
-        if required_tokens > self.token_rate() {
-            panic!("Requested number of tokens can not exceed capacity");
+/// Token bucket that refills at `token_rate` tokens per second, holding
+/// at most the tokens refilled during one second.
+pub struct TokenBucket {
+    token_rate: u32,
+    available_tokens: f64,
+    last_updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(token_rate: u32) -> TokenBucket {
+        TokenBucket {
+            token_rate,
+            available_tokens: 0.0,
+            last_updated: Instant::now(),
         }
+    }
+
+    /// Blocks the thread until the required tokens are available.
+    fn wait(&mut self, required_tokens: usize) {
+        if let Some(waiting_time) = self.waiting_time(required_tokens) {
+            std::thread::sleep(waiting_time);
+            self.sync();
+        }
+    }
+
+    /// Returns how long to wait until the required tokens are available,
+    /// or `None` if they are available already.
+    pub fn waiting_time(&mut self, required_tokens: usize) -> Option<Duration> {
+        let required_tokens = required_tokens as f64;
 
         self.sync();
 
-        if self.available_tokens < required_tokens {
-            let missing_tokens = required_tokens - self.available_tokens;
-            let waiting_time = missing_tokens.ceil() / self.token_rate();
-            std::thread::sleep(Duration::from_secs_f64(waiting_time));
-            self.sync();
+        if self.available_tokens >= required_tokens {
+            return None;
         }
 
-        debug_assert!(self.available_tokens >= required_tokens);
-        self.available_tokens -= required_tokens;
+        let missing_tokens = required_tokens - self.available_tokens;
+        Some(Duration::from_secs_f64(
+            missing_tokens.ceil() / self.token_rate(),
+        ))
+    }
+
+    pub fn consume(&mut self, tokens: usize) {
+        self.available_tokens -= tokens as f64;
     }
 
-    fn sync(&mut self) {
+    pub fn capacity(&self) -> usize {
+        self.token_rate as usize
+    }
+
+    pub fn sync(&mut self) {
         let current_time = Instant::now();
 
         let time_elapsed = current_time.duration_since(self.last_updated).as_nanos();
@@ -70,7 +113,7 @@ impl<T: Write> RateLimitedStream<T> {
     }
 
     fn token_rate(&self) -> f64 {
-        self.token_rate.unwrap() as f64
+        self.token_rate as f64
     }
 }
 
@@ -92,10 +135,13 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "Requested number of tokens can not exceed capacity")]
-    fn test_required_tokens_larger_than_capacity() {
-        let mut stream = RateLimitedStream::new(io::sink(), Some(1));
-        stream.write(&[0u8; 2]).unwrap();
+    fn test_write_larger_than_capacity_is_partial() {
+        let mut stream = RateLimitedStream::new(io::sink(), Some(2));
+
+        let now = Instant::now();
+
+        assert_eq!(stream.write(&[0u8; 3]).unwrap(), 2);
+        assert_eq!(now.elapsed().as_millis(), 1000);
     }
 
     #[test]
@@ -118,6 +164,6 @@ mod tests {
         let now = Instant::now();
         stream.write(&[0u8; 2]).unwrap();
         let elapsed_millis = now.elapsed().as_millis();
-        assert!(elapsed_millis >= 499 && elapsed_millis <= 501);
+        assert!((499..=501).contains(&elapsed_millis));
     }
 }
@@ -0,0 +1,257 @@
+//! TLS transport for the connections with the receiver.
+
+use std::convert::TryFrom;
+use std::io::{self, prelude::*};
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, ClientConnection, ConfigBuilder, DigitallySignedStruct,
+    RootCertStore, SignatureScheme, StreamOwned, WantsVerifier,
+};
+
+use crate::error::{Error, Result};
+
+/// Settings used to authenticate the receiver when uploading over TLS,
+/// and optionally the uploader itself.
+#[derive(Clone)]
+pub struct TlsConfig {
+    verifier: Arc<dyn ServerCertVerifier>,
+    config: Arc<ClientConfig>,
+    server_name: Option<String>,
+}
+
+impl TlsConfig {
+    /// Trusts the receivers whose certificate is issued by one of the
+    /// certificate authorities in the PEM bundle at `path`.
+    pub fn from_ca_file(path: impl AsRef<Path>) -> Result<TlsConfig> {
+        let mut roots = RootCertStore::empty();
+        let (added, _) = roots.add_parsable_certificates(read_certs(path.as_ref())?);
+
+        if added == 0 {
+            return Err(Error::Tls(format!(
+                "no valid certificates in: {}",
+                path.as_ref().display()
+            )));
+        }
+
+        let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider())
+            .build()
+            .map_err(|err| Error::Tls(err.to_string()))?;
+
+        TlsConfig::new(verifier)
+    }
+
+    /// Trusts only the receiver that presents the certificate at `path`,
+    /// regardless of who issued it and of the host it was issued for.
+    pub fn from_pinned_cert(path: impl AsRef<Path>) -> Result<TlsConfig> {
+        let cert = read_certs(path.as_ref())?
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::Tls(format!("no certificate in: {}", path.as_ref().display())))?;
+
+        TlsConfig::new(Arc::new(PinnedCertVerifier {
+            cert,
+            provider: provider(),
+        }))
+    }
+
+    /// Authenticates the uploader with the certificate chain and the
+    /// private key in the given PEM files, for receivers that require
+    /// client certificates.
+    pub fn with_client_cert(
+        self,
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Result<TlsConfig> {
+        let certs = read_certs(cert_path.as_ref())?;
+        let key = PrivateKeyDer::from_pem_file(key_path.as_ref())
+            .map_err(|err| Error::Tls(format!("{}: {}", key_path.as_ref().display(), err)))?;
+
+        let config = config_builder()?
+            .dangerous()
+            .with_custom_certificate_verifier(self.verifier.clone())
+            .with_client_auth_cert(certs, key)
+            .map_err(|err| Error::Tls(err.to_string()))?;
+
+        Ok(TlsConfig {
+            config: Arc::new(config),
+            ..self
+        })
+    }
+
+    /// Sets the name the certificate of the receiver must be valid for,
+    /// which is the host connected to by default.
+    pub fn with_server_name(mut self, server_name: String) -> TlsConfig {
+        self.server_name = Some(server_name);
+        self
+    }
+
+    fn new(verifier: Arc<dyn ServerCertVerifier>) -> Result<TlsConfig> {
+        let config = config_builder()?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier.clone())
+            .with_no_client_auth();
+
+        Ok(TlsConfig {
+            verifier,
+            config: Arc::new(config),
+            server_name: None,
+        })
+    }
+
+    pub(crate) fn client_config(&self) -> Arc<ClientConfig> {
+        self.config.clone()
+    }
+
+    pub(crate) fn server_name(&self, host: &str) -> Result<ServerName<'static>> {
+        let name = self.server_name.as_deref().unwrap_or(host);
+
+        ServerName::try_from(name.to_string())
+            .map_err(|_| Error::Tls(format!("invalid server name: {}", name)))
+    }
+
+    /// Starts a TLS session over `stream`. The handshake is performed
+    /// along with the first read or write.
+    pub(crate) fn connect<S: Read + Write>(&self, host: &str, stream: S) -> Result<Connection<S>> {
+        let conn = ClientConnection::new(self.client_config(), self.server_name(host)?)
+            .map_err(|err| Error::Tls(err.to_string()))?;
+
+        Ok(Connection::Tls(Box::new(StreamOwned::new(conn, stream))))
+    }
+}
+
+/// Connection with the receiver, which is encrypted when TLS is enabled.
+pub enum Connection<S: Read + Write> {
+    Plain(S),
+    Tls(Box<StreamOwned<ClientConnection, S>>),
+}
+
+impl<S: Read + Write> Connection<S> {
+    pub fn socket(&mut self) -> &mut S {
+        match self {
+            Connection::Plain(stream) => stream,
+            Connection::Tls(stream) => &mut stream.sock,
+        }
+    }
+
+    /// Replaces the underlying socket, keeping the TLS session.
+    pub fn map<T: Read + Write>(
+        self,
+        f: impl FnOnce(S) -> io::Result<T>,
+    ) -> io::Result<Connection<T>> {
+        Ok(match self {
+            Connection::Plain(stream) => Connection::Plain(f(stream)?),
+            Connection::Tls(stream) => {
+                let StreamOwned { conn, sock } = *stream;
+                Connection::Tls(Box::new(StreamOwned::new(conn, f(sock)?)))
+            }
+        })
+    }
+}
+
+impl<S: Read + Write> Read for Connection<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl<S: Read + Write> Write for Connection<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Accepts the certificate it was created with and nothing else.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    cert: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() != self.cert.as_ref() {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn config_builder() -> Result<ConfigBuilder<ClientConfig, WantsVerifier>> {
+    ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| Error::Tls(err.to_string()))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect())
+        .map_err(|err| Error::Tls(format!("{}: {}", path.display(), err)))
+}
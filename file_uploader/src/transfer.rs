@@ -0,0 +1,592 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, prelude::*, SeekFrom};
+
+use file_protocol::{BlockCopy, ChunkQuery, Response, Signatures, StoredChunk, MAX_CHUNK_SIZE};
+use sha2::{Digest, Sha256};
+
+use crate::dedup::Dedup;
+use crate::delta::Delta;
+use crate::error::{Error, Result};
+use crate::zero_copy;
+
+/// Data of a file to upload, which is read from memory for links.
+pub trait Source: Read + Seek {}
+
+impl<T: Read + Seek> Source for T {}
+
+/// Asynchronous counterpart of `Source`.
+#[cfg(feature = "tokio")]
+pub trait AsyncSource: tokio::io::AsyncRead + tokio::io::AsyncSeek + Send + Unpin {}
+
+#[cfg(feature = "tokio")]
+impl<T: tokio::io::AsyncRead + tokio::io::AsyncSeek + Send + Unpin> AsyncSource for T {}
+
+/// Chunk of the file to send next.
+#[derive(Debug, PartialEq)]
+pub enum Chunk {
+    /// Data of the file at the given offset and with the given length,
+    /// which was read into the buffer.
+    Data(u64, usize),
+    /// Copy of a block the receiver already holds, when the file is sent
+    /// as a delta.
+    Copy(BlockCopy),
+    /// Query of the chunks missing from the store of the receiver, when
+    /// the file is deduplicated.
+    Query(ChunkQuery),
+    /// Chunk the store of the receiver holds, when the file is
+    /// deduplicated.
+    Stored(StoredChunk),
+}
+
+/// What the transfer needs before it can return the next chunk. The
+/// transfer itself does no I/O on the file, which is left to the
+/// synchronous and asynchronous uploaders.
+enum Step {
+    /// The next chunk to send, or `None` if there is nothing left to
+    /// send for now.
+    Chunk(Option<Chunk>),
+    /// The data of the file at the given offset and with the given
+    /// length must be read into the buffer, after which it is sent as
+    /// `Chunk::Data`.
+    Reread(u64, usize),
+    /// The data of the file that follows the data read so far must be
+    /// read into the buffer, up to the given length, and the number of
+    /// bytes read passed to `Transfer::data_read`.
+    Read(usize),
+}
+
+/// Progress of the upload of a file, which is kept across reconnections.
+pub struct Transfer<F> {
+    file: F,
+    file_size: u64,
+    /// Offsets the range of the file to upload starts and ends at.
+    start: u64,
+    end: u64,
+    file_offset: u64,
+    /// Offset the data sent to the receiver ends at, which is behind the
+    /// offset of the file when the data read is turned into a delta or
+    /// deduplicated.
+    bytes_sent: u64,
+    bytes_acknowledged: u64,
+    bytes_hashed: u64,
+    /// Number of bytes that can be sent without them being acknowledged.
+    window: Option<u64>,
+    hasher: Sha256,
+    retransmissions: VecDeque<(u64, u32)>,
+    delta: Option<Delta>,
+    dedup: Option<Dedup>,
+    /// Handle of the open file that the data is sent from with
+    /// `sendfile`, if any, which the data is also read from at the
+    /// offset of each chunk.
+    sendfile: Option<File>,
+}
+
+impl<F> Transfer<F> {
+    pub fn new(file: F, file_size: u64) -> Transfer<F> {
+        Transfer {
+            file,
+            file_size,
+            start: 0,
+            end: file_size,
+            file_offset: 0,
+            bytes_sent: 0,
+            bytes_acknowledged: 0,
+            bytes_hashed: 0,
+            window: None,
+            hasher: Sha256::new(),
+            retransmissions: VecDeque::new(),
+            delta: None,
+            dedup: None,
+            sendfile: None,
+        }
+    }
+
+    /// Uploads only the range of the file between `start` and `end`,
+    /// whose digest is sent instead of the digest of the whole file.
+    pub fn with_range(mut self, start: u64, end: u64) -> Transfer<F> {
+        self.start = start;
+        self.end = end;
+        self.file_offset = start;
+        self.bytes_sent = start;
+        self.bytes_acknowledged = start;
+        self.bytes_hashed = start;
+        self
+    }
+
+    /// Sends the data of the chunks from `sendfile`, a handle of the open
+    /// file, over the connections that allow it.
+    pub fn with_sendfile(mut self, sendfile: Option<File>) -> Transfer<F> {
+        self.sendfile = sendfile;
+        self
+    }
+
+    pub fn sendfile(&self) -> Option<&File> {
+        self.sendfile.as_ref()
+    }
+
+    /// Bounds the data sent without being acknowledged to `window` bytes
+    /// for the rest of the session, or lifts the bound if `None`.
+    pub fn set_window(&mut self, window: Option<u32>) {
+        self.window = window.map(u64::from);
+    }
+
+    /// Sends the rest of the file as a delta against the copy of it the
+    /// receiver holds, described by its `signatures`.
+    pub fn send_as_delta(&mut self, signatures: Signatures) {
+        self.delta = Some(Delta::new(signatures, self.file_offset));
+    }
+
+    /// Sends the rest of the file deduplicated against the chunks the
+    /// receiver holds in its store.
+    pub fn deduplicate(&mut self) {
+        self.dedup = Some(Dedup::new(self.file_offset));
+    }
+
+    /// Returns whether the file is being sent as a delta or
+    /// deduplicated, in which case each chunk is preceded by its kind.
+    pub fn has_chunk_kinds(&self) -> bool {
+        self.delta.is_some() || self.dedup.is_some()
+    }
+
+    /// Returns whether the receiver acknowledged the whole range.
+    pub fn is_acknowledged(&self) -> bool {
+        self.bytes_acknowledged == self.end
+    }
+
+    /// Digest of the whole range, which is complete once all of the
+    /// range was sent.
+    pub fn digest(&self) -> Vec<u8> {
+        self.hasher.clone().finalize().to_vec()
+    }
+
+    /// Updates the transfer with a response received during the upload.
+    pub fn handle_response(&mut self, response: Response) -> Result<()> {
+        match response {
+            Response::Ack(offset) => {
+                // An acknowledgement past the end of the range would keep
+                // the upload from ever completing.
+                if offset < self.bytes_acknowledged || offset > self.end {
+                    return Err(Error::Protocol(format!(
+                        "invalid acknowledged offset: {}",
+                        offset
+                    )));
+                }
+                self.bytes_acknowledged = offset;
+
+                // The ranges of a striped file are uploaded in parallel,
+                // so only the progress of whole files is shown.
+                if self.start == 0 && self.end == self.file_size {
+                    update_progress_bar(offset, self.file_size);
+                }
+            }
+            Response::Nack(offset, length) => {
+                eprintln!(
+                    "WARNING: chunk corrupted in transit (offset={}, length={})",
+                    offset, length
+                );
+                self.retransmissions.push_back((offset, length));
+            }
+            Response::Missing(indexes) => match &mut self.dedup {
+                Some(dedup) => dedup.answer(indexes)?,
+                None => {
+                    return Err(Error::Protocol(
+                        "missing chunks sent for a file that is not deduplicated".to_string(),
+                    ))
+                }
+            },
+            Response::Verification(_) => {
+                return Err(Error::Protocol(
+                    "verification result sent before the end of the file".to_string(),
+                ))
+            }
+            Response::Error(message) => return Err(Error::Rejected(message)),
+            Response::Accept(_)
+            | Response::Challenge(_)
+            | Response::Hello(..)
+            | Response::Signatures(_)
+            | Response::AckInterval(_) => {
+                return Err(Error::Protocol(
+                    "handshake response sent during the transfer".to_string(),
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn restart(&mut self, offset: u64) {
+        self.file_offset = offset;
+        self.bytes_sent = offset;
+        self.bytes_acknowledged = offset;
+        self.retransmissions.clear();
+        self.delta = None;
+        self.dedup = None;
+    }
+
+    /// Returns the next chunk that must be retransmitted, validating
+    /// that it was sent before and that it fits in the buffer.
+    fn next_retransmission(&mut self, buf_size: usize) -> Result<Option<(u64, usize)>> {
+        match self.retransmissions.pop_front() {
+            Some((offset, length)) => {
+                let length = length as usize;
+                if length > buf_size || offset + length as u64 > self.file_offset {
+                    return Err(Error::Protocol(format!(
+                        "invalid chunk to retransmit (offset={}, length={})",
+                        offset, length
+                    )));
+                }
+                Ok(Some((offset, length)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns whether sending another chunk could exceed the window.
+    /// Chunks sent again are not limited by it, as the receiver waits for
+    /// them before acknowledging the data that follows.
+    fn is_window_full(&self) -> bool {
+        self.window.is_some_and(|window| {
+            let bytes_in_flight = self.bytes_sent.saturating_sub(self.bytes_acknowledged);
+            bytes_in_flight + MAX_CHUNK_SIZE as u64 > window
+        })
+    }
+
+    /// Records that `chunk` is about to be sent.
+    fn record_sent(&mut self, chunk: &Option<Chunk>) {
+        let end = match chunk {
+            Some(Chunk::Data(offset, length)) => offset + *length as u64,
+            Some(Chunk::Copy(copy)) => match &self.delta {
+                Some(delta) => copy.offset + delta.block_size() as u64,
+                None => copy.offset,
+            },
+            Some(Chunk::Stored(chunk)) => chunk.offset + chunk.length as u64,
+            Some(Chunk::Query(_)) | None => return,
+        };
+        self.bytes_sent = cmp::max(self.bytes_sent, end);
+    }
+
+    /// Returns how much of the data of the range that was not read yet
+    /// fits in a buffer of `buf_size` bytes.
+    fn unread(&self, buf_size: usize) -> usize {
+        cmp::min(buf_size as u64, self.end - self.file_offset) as usize
+    }
+
+    /// Records that `data` was read from the current offset of the file,
+    /// returning the offset it was read from.
+    fn advance(&mut self, data: &[u8]) -> u64 {
+        let offset = self.file_offset;
+        self.file_offset += data.len() as u64;
+
+        // Data that is sent again after a reconnection was already hashed.
+        if self.file_offset > self.bytes_hashed {
+            let start = (self.bytes_hashed - offset) as usize;
+            self.hasher.update(&data[start..]);
+            self.bytes_hashed = self.file_offset;
+        }
+
+        offset
+    }
+
+    /// Starts looking for the next chunk to send. Chunks sent again are
+    /// always sent as data, as are the chunks of a deduplicated file
+    /// that are missing from the store of the receiver, which are read
+    /// again once the receiver answered the query about them.
+    fn first_step(&mut self, buf: &mut [u8]) -> Result<Step> {
+        if let Some((offset, length)) = self.next_retransmission(buf.len())? {
+            return Ok(Step::Reread(offset, length));
+        }
+
+        if self.is_window_full() {
+            return Ok(Step::Chunk(None));
+        }
+
+        Ok(self.next_new_step(buf))
+    }
+
+    /// Looks for the next chunk that was not sent before, out of the
+    /// data read so far.
+    fn next_new_step(&mut self, buf: &mut [u8]) -> Step {
+        if let Some(chunk) = self.delta.as_mut().and_then(|delta| delta.next_chunk(buf)) {
+            return self.sent(Some(chunk));
+        }
+
+        if let Some(dedup) = &mut self.dedup {
+            match dedup.next_chunk(buf.len()) {
+                Some(Chunk::Data(offset, length)) => {
+                    self.record_sent(&Some(Chunk::Data(offset, length)));
+                    return Step::Reread(offset, length);
+                }
+                Some(chunk) => return self.sent(Some(chunk)),
+                None if !dedup.needs_data() => return Step::Chunk(None),
+                None => {}
+            }
+        }
+
+        Step::Read(self.unread(buf.len()))
+    }
+
+    /// Goes on looking for the next chunk once `bytes_read` bytes of the
+    /// file were read into `buf`, as asked by `Step::Read`.
+    fn data_read(&mut self, buf: &mut [u8], bytes_read: usize) -> Result<Step> {
+        if bytes_read == 0 {
+            return self.end_of_data(buf);
+        }
+
+        let chunk = self.feed(&buf[..bytes_read]);
+        Ok(self.fed(buf, chunk))
+    }
+
+    /// Goes on looking for the next chunk once there is no data left to
+    /// read, which must be at the end of the range.
+    fn end_of_data(&mut self, buf: &mut [u8]) -> Result<Step> {
+        if self.file_offset < self.end {
+            return Err(file_truncated());
+        }
+        if let Some(dedup) = &mut self.dedup {
+            dedup.finish();
+            return Ok(self.next_new_step(buf));
+        }
+        let chunk = self.delta.as_mut().and_then(|delta| delta.finish(buf));
+        Ok(self.sent(chunk))
+    }
+
+    /// Hashes the `data` read next and feeds it into the delta or the
+    /// deduplication of the file, returning it as a chunk otherwise.
+    fn feed(&mut self, data: &[u8]) -> Option<Chunk> {
+        let offset = self.advance(data);
+        match (&mut self.delta, &mut self.dedup) {
+            (Some(delta), _) => delta.feed(data),
+            (_, Some(dedup)) => dedup.feed(data),
+            (None, None) => return Some(Chunk::Data(offset, data.len())),
+        }
+        None
+    }
+
+    /// Returns the step that sends the `chunk` of data just fed, or that
+    /// looks for the next chunk out of the data fed so far.
+    fn fed(&mut self, buf: &mut [u8], chunk: Option<Chunk>) -> Step {
+        match chunk {
+            Some(chunk) => self.sent(Some(chunk)),
+            None => self.next_new_step(buf),
+        }
+    }
+
+    /// Returns the step that sends `chunk`, recording that it was sent.
+    fn sent(&mut self, chunk: Option<Chunk>) -> Step {
+        self.record_sent(&chunk);
+        Step::Chunk(chunk)
+    }
+}
+
+/// Returns the error for a file that got shorter than the range being
+/// uploaded from it.
+pub fn file_truncated() -> Error {
+    Error::Io(io::Error::other("file truncated during the upload"))
+}
+
+/// Reads the data of the chunk at `offset` from the `sendfile` handle of
+/// the file into `buf`, which must be filled up unless the file got
+/// shorter.
+fn read_at(sendfile: &File, buf: &mut [u8], offset: u64) -> Result<()> {
+    zero_copy::read_at(sendfile, buf, offset).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => file_truncated(),
+        _ => Error::Io(err),
+    })
+}
+
+impl<F: Read + Seek> Transfer<F> {
+    /// Restarts the transfer from the offset requested by the receiver.
+    ///
+    /// The file contents up to the offset that were not hashed yet are
+    /// fed into the hasher. This is needed when the receiver already
+    /// holds data that was never read by this uploader, e.g. after the
+    /// uploader restarted.
+    pub fn resume(&mut self, offset: u64) -> Result<()> {
+        if self.bytes_hashed < offset {
+            self.file.seek(SeekFrom::Start(self.bytes_hashed))?;
+            io::copy(
+                &mut (&mut self.file).take(offset - self.bytes_hashed),
+                &mut self.hasher,
+            )?;
+            self.bytes_hashed = offset;
+        }
+
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.restart(offset);
+
+        Ok(())
+    }
+
+    /// Returns the next chunk to send, reading its data into `buf`, or
+    /// `None` if there is nothing left to send for now, including when
+    /// the window is full. The data sent with `sendfile` is read at the
+    /// offset of the chunk from the same handle of the file.
+    pub fn next_chunk(&mut self, buf: &mut [u8]) -> Result<Option<Chunk>> {
+        let mut step = self.first_step(buf)?;
+
+        loop {
+            step = match step {
+                Step::Chunk(chunk) => return Ok(chunk),
+                Step::Reread(offset, length) => {
+                    match &self.sendfile {
+                        Some(sendfile) => read_at(sendfile, &mut buf[..length], offset)?,
+                        None => {
+                            self.file.seek(SeekFrom::Start(offset))?;
+                            self.file.read_exact(&mut buf[..length])?;
+                            self.file.seek(SeekFrom::Start(self.file_offset))?;
+                        }
+                    }
+
+                    return Ok(Some(Chunk::Data(offset, length)));
+                }
+                Step::Read(length) => {
+                    let bytes_read = match &self.sendfile {
+                        Some(sendfile) => {
+                            read_at(sendfile, &mut buf[..length], self.file_offset)?;
+                            length
+                        }
+                        None => self.file.read(&mut buf[..length])?,
+                    };
+                    self.data_read(buf, bytes_read)?
+                }
+            };
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Transfer<Box<dyn AsyncSource>> {
+    /// Asynchronous counterpart of `Transfer<F>::resume`.
+    pub async fn resume(&mut self, offset: u64) -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        if self.bytes_hashed < offset {
+            self.file.seek(SeekFrom::Start(self.bytes_hashed)).await?;
+
+            let mut reader = (&mut self.file).take(offset - self.bytes_hashed);
+            let mut buf = [0u8; 8192];
+            loop {
+                let bytes_read = reader.read(&mut buf).await?;
+                if bytes_read == 0 {
+                    break;
+                }
+                self.hasher.update(&buf[..bytes_read]);
+            }
+            self.bytes_hashed = offset;
+        }
+
+        self.file.seek(SeekFrom::Start(offset)).await?;
+        self.restart(offset);
+
+        Ok(())
+    }
+
+    /// Asynchronous counterpart of `Transfer<F>::next_chunk`.
+    pub async fn next_chunk(&mut self, buf: &mut [u8]) -> Result<Option<Chunk>> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let mut step = self.first_step(buf)?;
+
+        loop {
+            step = match step {
+                Step::Chunk(chunk) => return Ok(chunk),
+                Step::Reread(offset, length) => {
+                    self.file.seek(SeekFrom::Start(offset)).await?;
+                    self.file.read_exact(&mut buf[..length]).await?;
+                    self.file.seek(SeekFrom::Start(self.file_offset)).await?;
+
+                    return Ok(Some(Chunk::Data(offset, length)));
+                }
+                Step::Read(length) => {
+                    let bytes_read = self.file.read(&mut buf[..length]).await?;
+                    self.data_read(buf, bytes_read)?
+                }
+            };
+        }
+    }
+}
+
+fn update_progress_bar(bytes_acknowledged: u64, file_size: u64) {
+    let percentage = bytes_acknowledged as f64 / file_size as f64 * 100.0;
+    let progress = "=".repeat(percentage as usize / 2);
+    print!("\r[{:50}] {:.2}%", progress, percentage);
+    io::stdout().flush().unwrap();
+
+    if bytes_acknowledged == file_size {
+        print!("\x1B[2K\r"); // Clear current line
+        io::stdout().flush().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_reject_invalid_acks() {
+        let mut transfer = Transfer::new(Cursor::new(vec![0u8; 100]), 100).with_range(10, 60);
+
+        transfer.handle_response(Response::Ack(30)).unwrap();
+        transfer.handle_response(Response::Ack(30)).unwrap();
+        assert!(transfer.handle_response(Response::Ack(20)).is_err());
+        assert!(transfer.handle_response(Response::Ack(61)).is_err());
+
+        transfer.handle_response(Response::Ack(60)).unwrap();
+        assert!(transfer.is_acknowledged());
+    }
+
+    #[test]
+    fn test_reject_truncated_file() {
+        let mut transfer = Transfer::new(Cursor::new(vec![0u8; 50]), 100);
+        let mut buf = [0u8; 64];
+
+        assert!(matches!(
+            transfer.next_chunk(&mut buf),
+            Ok(Some(Chunk::Data(0, 50)))
+        ));
+        assert!(transfer.next_chunk(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_sendfile_data_read_at_offset() {
+        let path = std::env::temp_dir().join(format!("transfer-test-{}", std::process::id()));
+        let data: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        // The data is read from the handle it is sent from, not from the
+        // source of the transfer.
+        let sendfile = File::open(&path).unwrap();
+        let mut transfer =
+            Transfer::new(Cursor::new(Vec::new()), data.len() as u64).with_sendfile(Some(sendfile));
+        let mut buf = [0u8; 1000];
+
+        let mut sent = Vec::new();
+        while let Some(Chunk::Data(_, length)) = transfer.next_chunk(&mut buf).unwrap() {
+            sent.extend_from_slice(&buf[..length]);
+        }
+        let digest = transfer.digest();
+
+        // The file is truncated behind the back of an upload of it, and
+        // the chunk that can only be read in part is not sent.
+        let sendfile = File::open(&path).unwrap();
+        let mut transfer =
+            Transfer::new(Cursor::new(Vec::new()), data.len() as u64).with_sendfile(Some(sendfile));
+        let first_chunk = transfer.next_chunk(&mut buf).unwrap();
+        File::create(&path).unwrap().set_len(1500).unwrap();
+        let truncated_result = transfer.next_chunk(&mut buf);
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(sent, data);
+        assert_eq!(digest, Sha256::digest(&data).to_vec());
+        assert!(matches!(first_chunk, Some(Chunk::Data(0, 1000))));
+        assert_eq!(
+            truncated_result.unwrap_err().to_string(),
+            file_truncated().to_string()
+        );
+    }
+}
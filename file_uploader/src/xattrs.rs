@@ -0,0 +1,81 @@
+//! Extended attributes of the uploaded files.
+
+use std::io;
+use std::path::Path;
+
+use file_protocol::ExtendedAttributes;
+
+/// Reads the extended attributes of the file at `path`, which include
+/// its POSIX ACLs, or of the file it links to when `follow_links` is
+/// set. Files on filesystems without extended attributes have none.
+#[cfg(target_os = "linux")]
+pub fn read(path: &Path, follow_links: bool) -> io::Result<ExtendedAttributes> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+
+    let names = match read_buffer(|buf, size| unsafe {
+        let buf = buf as *mut libc::c_char;
+        if follow_links {
+            libc::listxattr(path.as_ptr(), buf, size)
+        } else {
+            libc::llistxattr(path.as_ptr(), buf, size)
+        }
+    }) {
+        Ok(names) => names,
+        Err(err) if err.raw_os_error() == Some(libc::ENOTSUP) => Vec::new(),
+        Err(err) => return Err(err),
+    };
+
+    let mut entries = Vec::new();
+    for name in names
+        .split(|&byte| byte == 0)
+        .filter(|name| !name.is_empty())
+    {
+        let c_name = CString::new(name)?;
+        match read_buffer(|buf, size| unsafe {
+            if follow_links {
+                libc::getxattr(path.as_ptr(), c_name.as_ptr(), buf, size)
+            } else {
+                libc::lgetxattr(path.as_ptr(), c_name.as_ptr(), buf, size)
+            }
+        }) {
+            Ok(value) => entries.push((name.to_vec(), value)),
+            // The attribute was removed since the names were listed.
+            Err(err) if err.raw_os_error() == Some(libc::ENODATA) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(ExtendedAttributes { entries })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn read(_path: &Path, _follow_links: bool) -> io::Result<ExtendedAttributes> {
+    Ok(ExtendedAttributes::default())
+}
+
+/// Calls `read` with a buffer large enough for the data it returns,
+/// growing the buffer while the data changes in between calls.
+#[cfg(target_os = "linux")]
+fn read_buffer(read: impl Fn(*mut libc::c_void, usize) -> libc::ssize_t) -> io::Result<Vec<u8>> {
+    loop {
+        let size = read(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let size = read(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        if size >= 0 {
+            buf.truncate(size as usize);
+            return Ok(buf);
+        }
+
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Zero-copy sending of the data of files on Linux, where `sendfile`
+//! moves it from the page cache to the socket instead of copying it
+//! from a buffer of the uploader. The data is still read, for the
+//! checksums of the chunks and the digest of the file.
+
+use std::fs::File;
+use std::io;
+
+use mio::net::TcpStream;
+
+/// Whether the data of files can be sent with `sendfile`.
+const SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// Returns whether the data of files can be sent with `sendfile` over
+/// a connection, which is only possible when it is sent as it is read:
+/// not throttled by a rate limit, nor compressed, nor encrypted.
+pub fn is_possible(rate_limited: bool, compressed: bool, encrypted: bool) -> bool {
+    SUPPORTED && !rate_limited && !compressed && !encrypted
+}
+
+/// Reads the data of `file` at `offset` into `buf`, for its checksum and
+/// the digest of the file, before it is sent with `send_file`. The data
+/// is read at `offset`, as the position of the file is left untouched by
+/// `send_file`, and reading less than `buf` fails as the file got
+/// shorter.
+#[cfg(target_os = "linux")]
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn read_at(_file: &File, _buf: &mut [u8], _offset: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Sends up to `length` bytes of `file` from `offset` to the non-blocking
+/// `socket`, returning the number of bytes sent.
+#[cfg(target_os = "linux")]
+pub fn send_file(socket: &TcpStream, file: &File, offset: u64, length: usize) -> io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut offset = offset as libc::off_t;
+    // SAFETY: both descriptors are open for as long as the borrows of
+    // `socket` and `file`, and `offset` is a valid `off_t` that outlives
+    // the call, which only updates it. The kernel copies the data
+    // itself, so no memory of the uploader is read or written otherwise.
+    let size = unsafe { libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut offset, length) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(size as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_file(
+    _socket: &TcpStream,
+    _file: &File,
+    _offset: u64,
+    _length: usize,
+) -> io::Result<usize> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sendfile_fallbacks() {
+        assert_eq!(is_possible(false, false, false), cfg!(target_os = "linux"));
+        assert!(!is_possible(true, false, false));
+        assert!(!is_possible(false, true, false));
+        assert!(!is_possible(false, false, true));
+    }
+}
@@ -1,19 +1,41 @@
 use std::cmp;
+use std::ffi::OsStr;
 use std::fs::{self, File};
 use std::io::{self, prelude::*, BufWriter};
-use std::path::Path;
-use std::sync::Arc;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use rand::prelude::*;
+use rcgen::{
+    BasicConstraints, CertificateParams, CertifiedIssuer, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
 use serial_test::serial;
 use sha2::{Digest, Sha256};
 
-use file_receiver::FileReceiver;
-use file_uploader::FileUploader;
+use file_protocol::{
+    Hello, Response, ResponseDecoder, MAX_CHUNK_SIZE, MAX_FILE_NAME_SIZE, MAX_VERSION,
+};
+use file_receiver::{AsyncFileReceiver, Collision, Durability, FileReceiver, NamingPolicy};
+use file_uploader::{AsyncFileUploader, Compression, FileUploader, Preserve};
 
 const SERVER_PORT: u16 = 8080;
+const PROXY_PORT: u16 = 8081;
+const WINDOW_PROXY_PORT: u16 = 8082;
+const PAUSING_PROXY_PORT: u16 = 8083;
+
+const CA_CERT_FILE: &str = "testca.pem";
+const CERT_FILE: &str = "testcert.pem";
+const KEY_FILE: &str = "testkey.pem";
+const CLIENT_CERT_FILE: &str = "testclientcert.pem";
+const CLIENT_KEY_FILE: &str = "testclientkey.pem";
+const UNKNOWN_CLIENT_CERT_FILE: &str = "testunknowncert.pem";
+const UNKNOWN_CLIENT_KEY_FILE: &str = "testunknownkey.pem";
 
 fn create_test_file(file_name: impl AsRef<Path>, size: usize) {
     let file = File::create(file_name).unwrap();
@@ -26,7 +48,28 @@ fn create_test_file(file_name: impl AsRef<Path>, size: usize) {
         let to_write = cmp::min(remaining, buffer.len());
         let buffer = &mut buffer[..to_write];
         rng.fill(buffer);
-        writer.write(buffer).unwrap();
+        writer.write_all(buffer).unwrap();
+        remaining -= to_write;
+    }
+}
+
+/// Creates a file that compresses well, like the logs of a service.
+fn create_compressible_test_file(file_name: impl AsRef<Path>, size: usize) {
+    let file = File::create(file_name).unwrap();
+    let mut writer = BufWriter::new(file);
+    let mut rng = rand::thread_rng();
+    let mut remaining = size;
+
+    while remaining > 0 {
+        let line = format!(
+            "2020-01-01T12:{:02}:{:02} INFO request {} served in {} ms\n",
+            rng.gen_range(0, 60),
+            rng.gen_range(0, 60),
+            rng.gen_range(0, 1000),
+            rng.gen_range(0, 100)
+        );
+        let to_write = cmp::min(remaining, line.len());
+        writer.write_all(&line.as_bytes()[..to_write]).unwrap();
         remaining -= to_write;
     }
 }
@@ -38,6 +81,172 @@ fn calculate_checksum(file_name: impl AsRef<Path>) -> String {
     format!("{:x}", hasher.finalize())
 }
 
+/// Forwards the connections made to `proxy_port` to the receiver,
+/// flipping the bits of the byte at `corrupted_byte` of the data sent
+/// by the uploader through the first connection. The proxy outlives the
+/// test, so each test uses a port of its own.
+///
+/// Returns the largest number of bytes the uploader sent through the
+/// first connection past the offset last acknowledged by the receiver.
+fn start_corrupting_proxy(proxy_port: u16, corrupted_byte: usize) -> Arc<AtomicU64> {
+    let listener = TcpListener::bind(("127.0.0.1", proxy_port)).unwrap();
+    let max_bytes_in_flight = Arc::new(AtomicU64::new(0));
+    let max_bytes_in_flight_clone = max_bytes_in_flight.clone();
+
+    thread::spawn(move || {
+        let mut corrupted_byte = Some(corrupted_byte);
+        let mut max_bytes_in_flight = Some(max_bytes_in_flight_clone);
+
+        for client in listener.incoming() {
+            let mut client = client.unwrap();
+            let mut server = TcpStream::connect(("127.0.0.1", SERVER_PORT)).unwrap();
+
+            let mut client_reader = client.try_clone().unwrap();
+            let mut server_writer = server.try_clone().unwrap();
+            let mut position = corrupted_byte.take();
+
+            let max_bytes_in_flight = max_bytes_in_flight.take();
+            let bytes_acknowledged = Arc::new(AtomicU64::new(0));
+            let bytes_acknowledged_clone = bytes_acknowledged.clone();
+
+            thread::spawn(move || {
+                let mut bytes_sent = 0;
+                let mut buf = [0u8; 4096];
+                loop {
+                    let size = match client_reader.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(size) => size,
+                    };
+                    if let Some(pos) = position {
+                        if pos < size {
+                            buf[pos] ^= 0xff;
+                            position = None;
+                        } else {
+                            position = Some(pos - size);
+                        }
+                    }
+                    if server_writer.write_all(&buf[..size]).is_err() {
+                        break;
+                    }
+                    bytes_sent += size as u64;
+                    if let Some(max_bytes_in_flight) = &max_bytes_in_flight {
+                        let acknowledged = bytes_acknowledged.load(Ordering::SeqCst);
+                        max_bytes_in_flight
+                            .fetch_max(bytes_sent.saturating_sub(acknowledged), Ordering::SeqCst);
+                    }
+                }
+                let _ = server_writer.shutdown(Shutdown::Both);
+            });
+
+            thread::spawn(move || {
+                let mut buf = [0u8; 4096];
+                let mut decoder = ResponseDecoder::new();
+                loop {
+                    let size = match server.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(size) => size,
+                    };
+                    decoder.feed(&buf[..size]);
+                    for response in decoder.decode().unwrap_or_default() {
+                        if let Response::Ack(offset) = response {
+                            bytes_acknowledged_clone.store(offset, Ordering::SeqCst);
+                        }
+                    }
+                    if client.write_all(&buf[..size]).is_err() {
+                        break;
+                    }
+                }
+                let _ = client.shutdown(Shutdown::Both);
+            });
+        }
+    });
+
+    max_bytes_in_flight
+}
+
+/// Forwards the first connection made to `proxy_port` to the receiver,
+/// holding back the data sent by the uploader past `paused_byte` until
+/// told to go on. Returns the channel that tells that the data is held
+/// back, and the one that tells the proxy to go on.
+fn start_pausing_proxy(
+    proxy_port: u16,
+    paused_byte: usize,
+) -> (mpsc::Receiver<()>, mpsc::Sender<()>) {
+    let listener = TcpListener::bind(("127.0.0.1", proxy_port)).unwrap();
+    let (paused_sender, paused_receiver) = mpsc::channel();
+    let (resume_sender, resume_receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let (mut client, _) = listener.accept().unwrap();
+        let mut server = TcpStream::connect(("127.0.0.1", SERVER_PORT)).unwrap();
+
+        let mut client_writer = client.try_clone().unwrap();
+        let mut server_reader = server.try_clone().unwrap();
+        thread::spawn(move || {
+            let _ = io::copy(&mut server_reader, &mut client_writer);
+            let _ = client_writer.shutdown(Shutdown::Both);
+        });
+
+        let _ = io::copy(&mut (&mut client).take(paused_byte as u64), &mut server);
+        paused_sender.send(()).unwrap();
+        resume_receiver.recv().unwrap();
+        let _ = io::copy(&mut client, &mut server);
+        let _ = server.shutdown(Shutdown::Both);
+    });
+
+    (paused_receiver, resume_sender)
+}
+
+/// Creates a certificate authority and the certificates issued by it
+/// for `localhost` and for the clients `alice` and `mallory`, along
+/// with their keys.
+fn create_test_certs() {
+    let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+
+    let issue = |params: CertificateParams, cert_file, key_file| {
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &ca).unwrap();
+        fs::write(cert_file, cert.pem()).unwrap();
+        fs::write(key_file, key.serialize_pem()).unwrap();
+    };
+
+    let client_params = |name| {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        params
+    };
+
+    fs::write(CA_CERT_FILE, ca.pem()).unwrap();
+    issue(
+        CertificateParams::new(vec!["localhost".to_string()]).unwrap(),
+        CERT_FILE,
+        KEY_FILE,
+    );
+    issue(client_params("alice"), CLIENT_CERT_FILE, CLIENT_KEY_FILE);
+    issue(
+        client_params("mallory"),
+        UNKNOWN_CLIENT_CERT_FILE,
+        UNKNOWN_CLIENT_KEY_FILE,
+    );
+}
+
+fn remove_test_certs() {
+    for file_name in &[
+        CA_CERT_FILE,
+        CERT_FILE,
+        KEY_FILE,
+        CLIENT_CERT_FILE,
+        CLIENT_KEY_FILE,
+        UNKNOWN_CLIENT_CERT_FILE,
+        UNKNOWN_CLIENT_KEY_FILE,
+    ] {
+        fs::remove_file(file_name).unwrap();
+    }
+}
+
 fn megabytes(n: usize) -> usize {
     n * 1024 * 1024
 }
@@ -54,12 +263,12 @@ fn test_streaming_basic() {
     let receiver_clone = receiver.clone();
 
     let receiver_thread = thread::spawn(move || {
-        receiver_clone.start();
+        receiver_clone.start().unwrap();
     });
 
     let uploader_thread = thread::spawn(move || {
         let uploader = FileUploader::new("localhost".to_string(), SERVER_PORT, None);
-        uploader.upload(src_file_name.to_string());
+        uploader.upload(src_file_name).unwrap();
     });
 
     uploader_thread.join().unwrap();
@@ -70,12 +279,53 @@ fn test_streaming_basic() {
     let checksum_original = calculate_checksum(src_file_name);
     let checksum_copied = calculate_checksum(dst_file_name);
 
-    fs::remove_file(&src_file_name).unwrap();
-    fs::remove_file(&dst_file_name).unwrap();
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
 
     assert_eq!(checksum_original, checksum_copied);
 }
 
+#[test]
+#[serial]
+fn test_streaming_durability_policies() {
+    let src_file_name = "testfile10Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    create_test_file(src_file_name, megabytes(10));
+
+    for &durability in &[
+        Durability::EveryBytes(megabytes(3) as u64),
+        Durability::None,
+    ] {
+        let receiver = Arc::new(FileReceiver::new(SERVER_PORT).with_durability(durability));
+        let receiver_clone = receiver.clone();
+
+        let receiver_thread = thread::spawn(move || {
+            receiver_clone.start().unwrap();
+        });
+
+        let uploader_thread = thread::spawn(move || {
+            let uploader = FileUploader::new("localhost".to_string(), SERVER_PORT, None);
+            uploader.upload(src_file_name).unwrap();
+        });
+
+        uploader_thread.join().unwrap();
+
+        receiver.stop();
+        receiver_thread.join().unwrap();
+
+        assert_eq!(
+            calculate_checksum(src_file_name),
+            calculate_checksum(dst_file_name),
+            "{:?}",
+            durability
+        );
+        fs::remove_file(dst_file_name).unwrap();
+    }
+
+    fs::remove_file(src_file_name).unwrap();
+}
+
 #[test]
 #[serial]
 fn test_streaming_restricted_upload_speed() {
@@ -88,7 +338,7 @@ fn test_streaming_restricted_upload_speed() {
     let receiver_clone = receiver.clone();
 
     let receiver_thread = thread::spawn(move || {
-        receiver_clone.start();
+        receiver_clone.start().unwrap();
     });
 
     let uploader_thread = thread::spawn(move || {
@@ -97,7 +347,7 @@ fn test_streaming_restricted_upload_speed() {
             SERVER_PORT,
             Some(megabytes(1) as u32),
         );
-        uploader.upload(src_file_name.to_string());
+        uploader.upload(src_file_name).unwrap();
     });
 
     let now = Instant::now();
@@ -109,8 +359,8 @@ fn test_streaming_restricted_upload_speed() {
 
     let checksum_original = calculate_checksum(src_file_name);
     let checksum_copied = calculate_checksum(dst_file_name);
-    fs::remove_file(&src_file_name).unwrap();
-    fs::remove_file(&dst_file_name).unwrap();
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
 
     // The transfer should take 10 seconds to complete. Allow some
     // margin of error necessary for the tests for pass in the CI.
@@ -132,7 +382,7 @@ fn test_streaming_resuming_upload() {
     let receiver_clone_b = receiver.clone();
 
     let receiver_thread = thread::spawn(move || {
-        receiver_clone_a.start();
+        receiver_clone_a.start().unwrap();
     });
 
     let uploader_thread = thread::spawn(move || {
@@ -141,7 +391,7 @@ fn test_streaming_resuming_upload() {
             SERVER_PORT,
             Some(megabytes(1) as u32),
         );
-        uploader.upload(src_file_name.to_string());
+        uploader.upload(src_file_name).unwrap();
     });
 
     let now = Instant::now();
@@ -151,7 +401,7 @@ fn test_streaming_resuming_upload() {
     receiver_thread.join().unwrap();
 
     let receiver_thread = thread::spawn(move || {
-        receiver_clone_b.start();
+        receiver_clone_b.start().unwrap();
     });
 
     uploader_thread.join().unwrap();
@@ -163,8 +413,8 @@ fn test_streaming_resuming_upload() {
     let checksum_original = calculate_checksum(src_file_name);
     let checksum_copied = calculate_checksum(dst_file_name);
 
-    fs::remove_file(&src_file_name).unwrap();
-    fs::remove_file(&dst_file_name).unwrap();
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
 
     // The transfer should take a bit more than 10 seconds to complete.
     // Allow some margin of error necessary for the tests for pass in
@@ -173,3 +423,1801 @@ fn test_streaming_resuming_upload() {
 
     assert_eq!(checksum_original, checksum_copied);
 }
+
+#[test]
+#[serial]
+fn test_streaming_resuming_upload_after_uploader_restart() {
+    let src_file_name = "testfile10Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+    let part_file_name = &format!(".{}.part", src_file_name);
+
+    create_test_file(src_file_name, megabytes(10));
+
+    // Simulate a previous upload that was interrupted half way through.
+    let mut partial_file = File::create(part_file_name).unwrap();
+    let src_file = File::open(src_file_name).unwrap();
+    io::copy(&mut src_file.take(megabytes(5) as u64), &mut partial_file).unwrap();
+    fs::write(
+        format!("{}.meta", part_file_name),
+        format!("{}\n{}\n", megabytes(10), megabytes(5)),
+    )
+    .unwrap();
+
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT));
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    let uploader_thread = thread::spawn(move || {
+        let uploader = FileUploader::new(
+            "localhost".to_string(),
+            SERVER_PORT,
+            Some(megabytes(1) as u32),
+        );
+        uploader.upload(src_file_name).unwrap();
+    });
+
+    let now = Instant::now();
+    uploader_thread.join().unwrap();
+    let elapsed_millis = now.elapsed().as_millis();
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+
+    // Only the second half of the file should have been uploaded.
+    assert!(elapsed_millis > 4500 && elapsed_millis < 6500);
+
+    assert_eq!(checksum_original, checksum_copied);
+    assert!(!Path::new(part_file_name).exists());
+    assert!(!Path::new(&format!("{}.meta", part_file_name)).exists());
+}
+
+#[test]
+#[serial]
+fn test_streaming_verification_failure() {
+    let src_file_name = "testfile10Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+    let part_file_name = &format!(".{}.part", src_file_name);
+
+    create_test_file(src_file_name, megabytes(10));
+
+    // A partial file whose contents do not match the original file.
+    create_test_file(part_file_name, megabytes(5));
+    fs::write(
+        format!("{}.meta", part_file_name),
+        format!("{}\n{}\n", megabytes(10), megabytes(5)),
+    )
+    .unwrap();
+
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT));
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    let uploader_thread = thread::spawn(move || {
+        let uploader = FileUploader::new("localhost".to_string(), SERVER_PORT, None);
+        uploader.upload(src_file_name)
+    });
+
+    let upload_result = uploader_thread.join().unwrap();
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    fs::remove_file(src_file_name).unwrap();
+
+    assert!(matches!(
+        upload_result,
+        Err(file_uploader::Error::Verification)
+    ));
+    assert!(!Path::new(dst_file_name).exists());
+    assert!(!Path::new(part_file_name).exists());
+}
+
+#[test]
+#[serial]
+fn test_streaming_corrupted_data() {
+    let src_file_name = "testfile10Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    create_test_file(src_file_name, megabytes(10));
+
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT));
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    start_corrupting_proxy(PROXY_PORT, megabytes(5));
+
+    let uploader_thread = thread::spawn(move || {
+        let uploader = FileUploader::new("localhost".to_string(), PROXY_PORT, None);
+        uploader.upload(src_file_name).unwrap();
+    });
+
+    uploader_thread.join().unwrap();
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[test]
+#[serial]
+fn test_streaming_file_truncated_during_zero_copy_upload() {
+    let src_file_name = "testfile20Mb";
+
+    create_test_file(src_file_name, megabytes(20));
+
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT));
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    let (paused, resume) = start_pausing_proxy(PAUSING_PROXY_PORT, megabytes(1));
+
+    // Neither rate limited, compressed nor encrypted, so the data is sent
+    // with `sendfile`.
+    let uploader_thread = thread::spawn(move || {
+        let uploader = FileUploader::new("localhost".to_string(), PAUSING_PROXY_PORT, None);
+        uploader.upload(src_file_name)
+    });
+
+    // The uploader can not get further than the window past the data
+    // held back by the proxy.
+    paused.recv().unwrap();
+    File::options()
+        .write(true)
+        .open(src_file_name)
+        .unwrap()
+        .set_len(megabytes(12) as u64 + 100)
+        .unwrap();
+    resume.send(()).unwrap();
+
+    let upload_result = uploader_thread.join().unwrap();
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(format!(".{}.part", src_file_name)).unwrap();
+    fs::remove_file(format!(".{}.part.meta", src_file_name)).unwrap();
+
+    let err = upload_result.unwrap_err();
+    assert!(err.to_string().contains("file truncated"), "{}", err);
+}
+
+#[test]
+#[serial]
+fn test_streaming_after_misbehaving_client() {
+    let src_file_name = "testfile10Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    create_test_file(src_file_name, megabytes(10));
+
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT));
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    // A header with a file name longer than allowed.
+    let mut client = loop {
+        match TcpStream::connect(("127.0.0.1", SERVER_PORT)) {
+            Ok(stream) => break stream,
+            Err(_) => thread::sleep(Duration::from_millis(100)),
+        }
+    };
+    client.write_all(&Hello::new(0).encode()).unwrap();
+    client
+        .write_all(&(MAX_FILE_NAME_SIZE as u16 + 1).to_be_bytes())
+        .unwrap();
+    drop(client);
+
+    let uploader_thread = thread::spawn(move || {
+        let uploader = FileUploader::new("localhost".to_string(), SERVER_PORT, None);
+        uploader.upload(src_file_name).unwrap();
+    });
+
+    uploader_thread.join().unwrap();
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[test]
+#[serial]
+fn test_streaming_after_idle_client() {
+    let src_file_name = "testfile1Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    create_test_file(src_file_name, megabytes(1));
+
+    let receiver = Arc::new(
+        FileReceiver::new(SERVER_PORT)
+            .with_max_connections(1)
+            .with_timeout(Duration::from_secs(1)),
+    );
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    // A client that never sends its hello, holding the only connection.
+    let client = loop {
+        match TcpStream::connect(("127.0.0.1", SERVER_PORT)) {
+            Ok(stream) => break stream,
+            Err(_) => thread::sleep(Duration::from_millis(100)),
+        }
+    };
+
+    let uploader_thread = thread::spawn(move || {
+        let uploader = FileUploader::new("localhost".to_string(), SERVER_PORT, None);
+        uploader.upload(src_file_name).unwrap();
+    });
+
+    uploader_thread.join().unwrap();
+    drop(client);
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[test]
+#[serial]
+fn test_streaming_concurrent_uploads() {
+    let src_file_names = ["testfile5MbA", "testfile5MbB"];
+
+    for src_file_name in &src_file_names {
+        create_test_file(src_file_name, megabytes(5));
+    }
+
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT));
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    let now = Instant::now();
+
+    let uploader_threads: Vec<_> = src_file_names
+        .iter()
+        .map(|&src_file_name| {
+            thread::spawn(move || {
+                let uploader = FileUploader::new(
+                    "localhost".to_string(),
+                    SERVER_PORT,
+                    Some(megabytes(1) as u32),
+                );
+                uploader.upload(src_file_name).unwrap();
+            })
+        })
+        .collect();
+
+    for uploader_thread in uploader_threads {
+        uploader_thread.join().unwrap();
+    }
+
+    let elapsed_millis = now.elapsed().as_millis();
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    for src_file_name in &src_file_names {
+        let dst_file_name = &format!("{}.received", src_file_name);
+
+        let checksum_original = calculate_checksum(src_file_name);
+        let checksum_copied = calculate_checksum(dst_file_name);
+
+        fs::remove_file(src_file_name).unwrap();
+        fs::remove_file(dst_file_name).unwrap();
+
+        assert_eq!(checksum_original, checksum_copied);
+    }
+
+    // Both uploads should run in parallel, taking 5 seconds to complete.
+    assert!(elapsed_millis > 4500 && elapsed_millis < 7000);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn test_streaming_async_concurrent_uploads() {
+    let src_file_names = ["testfile5MbA", "testfile5MbB"];
+
+    for src_file_name in &src_file_names {
+        create_test_file(src_file_name, megabytes(5));
+    }
+
+    let receiver = Arc::new(AsyncFileReceiver::new(SERVER_PORT));
+    let receiver_clone = receiver.clone();
+
+    let receiver_task = tokio::spawn(async move {
+        receiver_clone.start().await.unwrap();
+    });
+
+    let now = Instant::now();
+
+    let uploader_tasks: Vec<_> = src_file_names
+        .iter()
+        .map(|&src_file_name| {
+            tokio::spawn(async move {
+                let uploader = AsyncFileUploader::new(
+                    "localhost".to_string(),
+                    SERVER_PORT,
+                    Some(megabytes(1) as u32),
+                );
+                uploader.upload(src_file_name).await.unwrap();
+            })
+        })
+        .collect();
+
+    for uploader_task in uploader_tasks {
+        uploader_task.await.unwrap();
+    }
+
+    let elapsed_millis = now.elapsed().as_millis();
+
+    receiver.stop();
+    receiver_task.await.unwrap();
+
+    for src_file_name in &src_file_names {
+        let dst_file_name = &format!("{}.received", src_file_name);
+
+        let checksum_original = calculate_checksum(src_file_name);
+        let checksum_copied = calculate_checksum(dst_file_name);
+
+        fs::remove_file(src_file_name).unwrap();
+        fs::remove_file(dst_file_name).unwrap();
+
+        assert_eq!(checksum_original, checksum_copied);
+    }
+
+    // Both uploads should run in parallel, taking 5 seconds to complete.
+    assert!(elapsed_millis > 4500 && elapsed_millis < 7000);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn test_streaming_async_resuming_upload() {
+    let src_file_name = "testfile5Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    create_test_file(src_file_name, megabytes(5));
+
+    let receiver = Arc::new(AsyncFileReceiver::new(SERVER_PORT));
+    let receiver_clone_a = receiver.clone();
+    let receiver_clone_b = receiver.clone();
+
+    let receiver_task = tokio::spawn(async move {
+        receiver_clone_a.start().await.unwrap();
+    });
+
+    let uploader_task = tokio::spawn(async move {
+        let uploader = AsyncFileUploader::new(
+            "localhost".to_string(),
+            SERVER_PORT,
+            Some(megabytes(1) as u32),
+        );
+        uploader.upload(src_file_name).await.unwrap();
+    });
+
+    let now = Instant::now();
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    receiver.stop_now();
+    receiver_task.await.unwrap();
+
+    let receiver_task = tokio::spawn(async move {
+        receiver_clone_b.start().await.unwrap();
+    });
+
+    uploader_task.await.unwrap();
+    let elapsed_millis = now.elapsed().as_millis();
+
+    receiver.stop();
+    receiver_task.await.unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+
+    // The transfer should take a bit more than 5 seconds to complete.
+    assert!(elapsed_millis > 5000 && elapsed_millis < 7500);
+
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[test]
+#[serial]
+fn test_streaming_tls_resuming_upload() {
+    let src_file_name = "testfile5Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    create_test_file(src_file_name, megabytes(5));
+    create_test_certs();
+
+    let receiver_tls = file_receiver::TlsConfig::from_pem_files(CERT_FILE, KEY_FILE).unwrap();
+    let uploader_tls = file_uploader::TlsConfig::from_ca_file(CA_CERT_FILE).unwrap();
+
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT).with_tls(receiver_tls));
+    let receiver_clone_a = receiver.clone();
+    let receiver_clone_b = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone_a.start().unwrap();
+    });
+
+    let uploader_thread = thread::spawn(move || {
+        let uploader = FileUploader::new(
+            "localhost".to_string(),
+            SERVER_PORT,
+            Some(megabytes(1) as u32),
+        )
+        .with_tls(uploader_tls);
+        uploader.upload(src_file_name).unwrap();
+    });
+
+    let now = Instant::now();
+
+    thread::sleep(Duration::from_secs(2));
+    receiver.stop_now();
+    receiver_thread.join().unwrap();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone_b.start().unwrap();
+    });
+
+    uploader_thread.join().unwrap();
+    let elapsed_millis = now.elapsed().as_millis();
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+    remove_test_certs();
+
+    // The data received before the interruption is not sent again.
+    assert!(elapsed_millis > 5000 && elapsed_millis < 7500);
+
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[test]
+#[serial]
+fn test_streaming_tls_pinned_cert() {
+    let src_file_name = "testfile1Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    create_test_file(src_file_name, megabytes(1));
+    create_test_certs();
+
+    let receiver_tls = file_receiver::TlsConfig::from_pem_files(CERT_FILE, KEY_FILE).unwrap();
+
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT).with_tls(receiver_tls));
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    let upload = |pinned_cert| {
+        let uploader_tls = file_uploader::TlsConfig::from_pinned_cert(pinned_cert).unwrap();
+        FileUploader::new("localhost".to_string(), SERVER_PORT, None)
+            .with_tls(uploader_tls)
+            .upload(src_file_name)
+    };
+
+    // The certificate authority is not trusted when a certificate is
+    // pinned, only the certificate itself.
+    let untrusted_result = upload(CA_CERT_FILE);
+    let trusted_result = upload(CERT_FILE);
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+    remove_test_certs();
+
+    assert!(untrusted_result.is_err());
+    assert!(trusted_result.is_ok());
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn test_streaming_async_tls_basic() {
+    let src_file_name = "testfile5Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    create_test_file(src_file_name, megabytes(5));
+    create_test_certs();
+
+    let receiver_tls = file_receiver::TlsConfig::from_pem_files(CERT_FILE, KEY_FILE).unwrap();
+    let uploader_tls = file_uploader::TlsConfig::from_ca_file(CA_CERT_FILE).unwrap();
+
+    let receiver = Arc::new(AsyncFileReceiver::new(SERVER_PORT).with_tls(receiver_tls));
+    let receiver_clone = receiver.clone();
+
+    let receiver_task = tokio::spawn(async move {
+        receiver_clone.start().await.unwrap();
+    });
+
+    let uploader =
+        AsyncFileUploader::new("localhost".to_string(), SERVER_PORT, None).with_tls(uploader_tls);
+    uploader.upload(src_file_name).await.unwrap();
+
+    receiver.stop();
+    receiver_task.await.unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+    remove_test_certs();
+
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[test]
+#[serial]
+fn test_streaming_tls_client_auth() {
+    let src_file_name = "testfile1Mb";
+    let client_dir = "testdir_alice";
+    let dst_file_name = &format!("{}/{}.received", client_dir, src_file_name);
+
+    create_test_file(src_file_name, megabytes(1));
+    create_test_certs();
+
+    // The name of the anonymous uploaders is not available to clients.
+    let reserved_clients = vec![file_receiver::Client::new(
+        "anonymous".to_string(),
+        client_dir,
+    )];
+    assert!(
+        file_receiver::TlsConfig::from_pem_files(CERT_FILE, KEY_FILE)
+            .unwrap()
+            .with_client_auth(CA_CERT_FILE, reserved_clients)
+            .is_err()
+    );
+
+    let clients = vec![file_receiver::Client::new("alice".to_string(), client_dir)];
+    let receiver_tls = file_receiver::TlsConfig::from_pem_files(CERT_FILE, KEY_FILE)
+        .unwrap()
+        .with_client_auth(CA_CERT_FILE, clients)
+        .unwrap();
+
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT).with_tls(receiver_tls));
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    let upload = |client_cert: Option<(&str, &str)>| {
+        let mut uploader_tls = file_uploader::TlsConfig::from_ca_file(CA_CERT_FILE).unwrap();
+        if let Some((cert_file, key_file)) = client_cert {
+            uploader_tls = uploader_tls.with_client_cert(cert_file, key_file).unwrap();
+        }
+        FileUploader::new("localhost".to_string(), SERVER_PORT, None)
+            .with_tls(uploader_tls)
+            .upload(src_file_name)
+    };
+
+    let anonymous_result = upload(None);
+    let unknown_client_result = upload(Some((UNKNOWN_CLIENT_CERT_FILE, UNKNOWN_CLIENT_KEY_FILE)));
+    let client_result = upload(Some((CLIENT_CERT_FILE, CLIENT_KEY_FILE)));
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_dir_all(client_dir).unwrap();
+    remove_test_certs();
+
+    assert!(anonymous_result.is_err());
+    assert!(unknown_client_result.is_err());
+    assert!(client_result.is_ok());
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[test]
+#[serial]
+fn test_streaming_shared_secret() {
+    let src_file_name = "testfile1Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    create_test_file(src_file_name, megabytes(1));
+
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT).with_secret(b"secret".to_vec()));
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    let upload = |secret: Option<&[u8]>| {
+        let mut uploader = FileUploader::new("localhost".to_string(), SERVER_PORT, None);
+        if let Some(secret) = secret {
+            uploader = uploader.with_secret(secret.to_vec());
+        }
+        uploader.upload(src_file_name)
+    };
+
+    let missing_secret_result = upload(None);
+    let wrong_secret_result = upload(Some(b"wrong"));
+    let secret_result = upload(Some(b"secret"));
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+
+    assert!(matches!(
+        missing_secret_result,
+        Err(file_uploader::Error::Rejected(_))
+    ));
+    assert!(matches!(
+        wrong_secret_result,
+        Err(file_uploader::Error::Rejected(_))
+    ));
+    assert!(secret_result.is_ok());
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn test_streaming_async_shared_secret() {
+    let src_file_name = "testfile1Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    create_test_file(src_file_name, megabytes(1));
+
+    let receiver = Arc::new(AsyncFileReceiver::new(SERVER_PORT).with_secret(b"secret".to_vec()));
+    let receiver_clone = receiver.clone();
+
+    let receiver_task = tokio::spawn(async move {
+        receiver_clone.start().await.unwrap();
+    });
+
+    let wrong_secret_result = AsyncFileUploader::new("localhost".to_string(), SERVER_PORT, None)
+        .with_secret(b"wrong".to_vec())
+        .upload(src_file_name)
+        .await;
+    let secret_result = AsyncFileUploader::new("localhost".to_string(), SERVER_PORT, None)
+        .with_secret(b"secret".to_vec())
+        .upload(src_file_name)
+        .await;
+
+    receiver.stop();
+    receiver_task.await.unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+
+    assert!(matches!(
+        wrong_secret_result,
+        Err(file_uploader::Error::Rejected(_))
+    ));
+    assert!(secret_result.is_ok());
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[test]
+#[serial]
+fn test_streaming_incompatible_hello() {
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT).with_secret(b"secret".to_vec()));
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    let exchange_hello = |hello: &[u8]| {
+        let mut client = loop {
+            match TcpStream::connect(("127.0.0.1", SERVER_PORT)) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(100)),
+            }
+        };
+        client.write_all(hello).unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        response
+    };
+
+    let invalid_magic_response = exchange_hello(b"GET / HTTP/1");
+    let future_version_response = exchange_hello(
+        &Hello {
+            min_version: MAX_VERSION + 1,
+            max_version: MAX_VERSION + 2,
+            capabilities: 0,
+        }
+        .encode(),
+    );
+    let missing_auth_response = exchange_hello(&Hello::new(0).encode());
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    for (response, reason) in [
+        (invalid_magic_response, "magic"),
+        (future_version_response, "version"),
+        (missing_auth_response, "auth"),
+    ] {
+        match Response::decode(&response).unwrap() {
+            Some((Response::Error(message), _)) => {
+                assert!(message.contains(reason), "{}", message)
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn test_streaming_raw_file_names() {
+    let non_utf8_file_name = Path::new(OsStr::from_bytes(b"testfile\xff\xfe")).to_path_buf();
+    let long_dir_name = format!("testdir_{}", "d".repeat(200));
+    let long_file_name = PathBuf::from(&long_dir_name).join(format!("testfile{}", "f".repeat(200)));
+
+    // Longer than what fits in the length of the names of the
+    // previous versions of the protocol.
+    assert!(long_file_name.as_os_str().len() > 255);
+
+    fs::create_dir(&long_dir_name).unwrap();
+    create_test_file(&non_utf8_file_name, megabytes(1));
+    create_test_file(&long_file_name, megabytes(1));
+
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT));
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    let uploader = FileUploader::new("localhost".to_string(), SERVER_PORT, None);
+    uploader.upload(&non_utf8_file_name).unwrap();
+    uploader.upload(&long_file_name).unwrap();
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let received_file_name = |file_name: &Path| {
+        let mut received = file_name.file_name().unwrap().to_os_string();
+        received.push(".received");
+        PathBuf::from(received)
+    };
+
+    for file_name in &[non_utf8_file_name, long_file_name] {
+        let checksum_original = calculate_checksum(file_name);
+        let checksum_copied = calculate_checksum(received_file_name(file_name));
+
+        fs::remove_file(file_name).unwrap();
+        fs::remove_file(received_file_name(file_name)).unwrap();
+
+        assert_eq!(checksum_original, checksum_copied);
+    }
+
+    fs::remove_dir(&long_dir_name).unwrap();
+}
+
+#[test]
+#[serial]
+fn test_streaming_long_file_name_restricted_upload_speed() {
+    let src_file_name = &format!("testfile{}", "f".repeat(200));
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    create_test_file(src_file_name, 256);
+
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT));
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    // The header holding the name is larger than what can be sent in
+    // one second, so it has to be sent over several writes.
+    let uploader = FileUploader::new("localhost".to_string(), SERVER_PORT, Some(200));
+    uploader.upload(src_file_name).unwrap();
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[test]
+#[serial]
+fn test_streaming_naming_policy() {
+    let src_file_name = "testfile1Mb";
+    let output_dir = "testdir_output";
+    let dst_file_name = &format!("{}/anonymous/{}", output_dir, src_file_name);
+    let renamed_file_name = &format!("{}.1", dst_file_name);
+
+    create_test_file(src_file_name, megabytes(1));
+
+    let start_receiver = |collision| {
+        let naming = NamingPolicy::new("{client}/{name}")
+            .unwrap()
+            .with_collision(collision);
+        let receiver = Arc::new(
+            FileReceiver::new(SERVER_PORT)
+                .with_output_dir(output_dir)
+                .with_naming(naming),
+        );
+        let receiver_clone = receiver.clone();
+        let receiver_thread = thread::spawn(move || {
+            receiver_clone.start().unwrap();
+        });
+        (receiver, receiver_thread)
+    };
+
+    let uploader = FileUploader::new("localhost".to_string(), SERVER_PORT, None);
+
+    let (receiver, receiver_thread) = start_receiver(Collision::Rename);
+    uploader.upload(src_file_name).unwrap();
+    uploader.upload(src_file_name).unwrap();
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let (receiver, receiver_thread) = start_receiver(Collision::Reject);
+    let rejected_result = uploader.upload(src_file_name);
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+    let checksum_renamed = calculate_checksum(renamed_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_dir_all(output_dir).unwrap();
+
+    assert_eq!(checksum_original, checksum_copied);
+    assert_eq!(checksum_original, checksum_renamed);
+    assert!(matches!(
+        rejected_result,
+        Err(file_uploader::Error::Rejected(_))
+    ));
+}
+
+#[test]
+#[serial]
+fn test_streaming_directory_upload() {
+    let src_dir = "testdir_batch";
+    let src_file_name = "testfile1Mb";
+    let output_dir = "testdir_output";
+    let relative_paths = ["a", "sub/b", "sub/deeper/c"];
+
+    fs::create_dir_all(format!("{}/sub/deeper", src_dir)).unwrap();
+    for relative_path in &relative_paths {
+        create_test_file(format!("{}/{}", src_dir, relative_path), megabytes(1));
+    }
+    create_test_file(src_file_name, megabytes(1));
+
+    let receiver = Arc::new(
+        FileReceiver::new(SERVER_PORT)
+            .with_output_dir(output_dir)
+            .with_naming(NamingPolicy::original_name())
+            .with_secret(b"secret".to_vec()),
+    );
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    let uploader = FileUploader::new("localhost".to_string(), SERVER_PORT, None)
+        .with_secret(b"secret".to_vec());
+    let upload_result = uploader.upload_all(&[src_dir, src_file_name]);
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let mut checksums = Vec::new();
+    for relative_path in &relative_paths {
+        checksums.push((
+            calculate_checksum(format!("{}/{}", src_dir, relative_path)),
+            calculate_checksum(format!("{}/{}/{}", output_dir, src_dir, relative_path)),
+        ));
+    }
+    checksums.push((
+        calculate_checksum(src_file_name),
+        calculate_checksum(format!("{}/{}", output_dir, src_file_name)),
+    ));
+
+    fs::remove_dir_all(src_dir).unwrap();
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_dir_all(output_dir).unwrap();
+
+    assert!(upload_result.is_ok());
+    for (checksum_original, checksum_copied) in checksums {
+        assert_eq!(checksum_original, checksum_copied);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn test_streaming_async_resuming_batch_upload() {
+    let src_dir = "testdir_batch";
+    let output_dir = "testdir_output";
+    let file_sizes = [("a", megabytes(2)), ("b", megabytes(3))];
+
+    fs::create_dir_all(src_dir).unwrap();
+    for (file_name, size) in &file_sizes {
+        create_test_file(format!("{}/{}", src_dir, file_name), *size);
+    }
+
+    let receiver = Arc::new(
+        AsyncFileReceiver::new(SERVER_PORT)
+            .with_output_dir(output_dir)
+            .with_naming(NamingPolicy::original_name()),
+    );
+    let receiver_clone_a = receiver.clone();
+    let receiver_clone_b = receiver.clone();
+
+    let receiver_task = tokio::spawn(async move {
+        receiver_clone_a.start().await.unwrap();
+    });
+
+    let uploader_task = tokio::spawn(async move {
+        let uploader = AsyncFileUploader::new(
+            "localhost".to_string(),
+            SERVER_PORT,
+            Some(megabytes(1) as u32),
+        );
+        uploader.upload(src_dir).await.unwrap();
+    });
+
+    let now = Instant::now();
+
+    // Interrupt the upload of the second file.
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    receiver.stop_now();
+    receiver_task.await.unwrap();
+
+    let receiver_task = tokio::spawn(async move {
+        receiver_clone_b.start().await.unwrap();
+    });
+
+    uploader_task.await.unwrap();
+    let elapsed_millis = now.elapsed().as_millis();
+
+    receiver.stop();
+    receiver_task.await.unwrap();
+
+    let mut checksums = Vec::new();
+    for (file_name, _) in &file_sizes {
+        checksums.push((
+            calculate_checksum(format!("{}/{}", src_dir, file_name)),
+            calculate_checksum(format!("{}/{}/{}", output_dir, src_dir, file_name)),
+        ));
+    }
+
+    fs::remove_dir_all(src_dir).unwrap();
+    fs::remove_dir_all(output_dir).unwrap();
+
+    // Neither the first file nor the data of the second file received
+    // before the interruption are sent again.
+    assert!(elapsed_millis > 5000 && elapsed_millis < 7500);
+
+    for (checksum_original, checksum_copied) in checksums {
+        assert_eq!(checksum_original, checksum_copied);
+    }
+}
+
+#[test]
+#[serial]
+fn test_streaming_preserve_attributes() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::time::UNIX_EPOCH;
+
+    let src_file_name = "testfile1Mb";
+    let output_dir = "testdir_output";
+    let mtime = UNIX_EPOCH + Duration::new(1_000_000_000, 123_456_789);
+
+    create_test_file(src_file_name, megabytes(1));
+    let src_file = File::options().write(true).open(src_file_name).unwrap();
+    src_file
+        .set_permissions(fs::Permissions::from_mode(0o4750))
+        .unwrap();
+    src_file.set_modified(mtime).unwrap();
+
+    let receiver = Arc::new(
+        FileReceiver::new(SERVER_PORT)
+            .with_output_dir(output_dir)
+            .with_naming(NamingPolicy::original_name()),
+    );
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    let uploader = FileUploader::new("localhost".to_string(), SERVER_PORT, None)
+        .with_preserve(Preserve::all());
+    let upload_result = uploader.upload(src_file_name);
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let src_metadata = fs::metadata(src_file_name).unwrap();
+    let dst_metadata = fs::metadata(format!("{}/{}", output_dir, src_file_name)).unwrap();
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_dir_all(output_dir).unwrap();
+
+    assert!(upload_result.is_ok());
+    // The setuid bit is not applied by the receiver.
+    assert_eq!(dst_metadata.mode() & 0o7777, 0o750);
+    assert_eq!(dst_metadata.modified().unwrap(), mtime);
+    assert_eq!(dst_metadata.uid(), src_metadata.uid());
+    assert_eq!(dst_metadata.gid(), src_metadata.gid());
+}
+
+fn get_xattr(path: impl AsRef<Path>, name: &str) -> Option<Vec<u8>> {
+    let path = std::ffi::CString::new(path.as_ref().as_os_str().as_bytes()).unwrap();
+    let name = std::ffi::CString::new(name).unwrap();
+    let mut buf = [0u8; 256];
+
+    let size = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+        )
+    };
+
+    if size < 0 {
+        None
+    } else {
+        Some(buf[..size as usize].to_vec())
+    }
+}
+
+fn set_xattr(path: impl AsRef<Path>, name: &str, value: &[u8]) {
+    let path = std::ffi::CString::new(path.as_ref().as_os_str().as_bytes()).unwrap();
+    let name = std::ffi::CString::new(name).unwrap();
+
+    let result = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    assert_eq!(result, 0, "{}", io::Error::last_os_error());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn test_streaming_links_and_xattrs() {
+    use std::os::unix::fs::symlink;
+
+    let src_dir = "testdir_links";
+    let output_dir = "testdir_output";
+
+    fs::create_dir_all(format!("{}/sub", src_dir)).unwrap();
+    create_test_file(format!("{}/a", src_dir), megabytes(1));
+    set_xattr(format!("{}/a", src_dir), "user.comment", b"hello");
+    symlink("a", format!("{}/link", src_dir)).unwrap();
+    symlink("missing/target", format!("{}/dangling", src_dir)).unwrap();
+    symlink("../sub", format!("{}/sub/parent", src_dir)).unwrap();
+
+    // A link given as an argument, which is followed.
+    let top_link = "testlink_top";
+    symlink(format!("{}/a", src_dir), top_link).unwrap();
+
+    let receiver = Arc::new(
+        AsyncFileReceiver::new(SERVER_PORT)
+            .with_output_dir(output_dir)
+            .with_naming(NamingPolicy::original_name()),
+    );
+    let receiver_clone = receiver.clone();
+
+    let receiver_task = tokio::spawn(async move {
+        receiver_clone.start().await.unwrap();
+    });
+
+    let uploader = AsyncFileUploader::new("localhost".to_string(), SERVER_PORT, None)
+        .with_preserve(Preserve {
+            xattrs: true,
+            ..Preserve::default()
+        });
+    let upload_result = uploader.upload_all(&[src_dir, top_link]).await;
+
+    receiver.stop();
+    receiver_task.await.unwrap();
+
+    let dst_dir = format!("{}/{}", output_dir, src_dir);
+    let checksum_original = calculate_checksum(format!("{}/a", src_dir));
+    let checksum_copied = calculate_checksum(format!("{}/a", dst_dir));
+    let xattr = get_xattr(format!("{}/a", dst_dir), "user.comment");
+    let top_link_xattr = get_xattr(format!("{}/{}", output_dir, top_link), "user.comment");
+    let targets: Vec<_> = ["link", "dangling", "sub/parent"]
+        .iter()
+        .map(|link| fs::read_link(format!("{}/{}", dst_dir, link)).ok())
+        .collect();
+
+    fs::remove_dir_all(src_dir).unwrap();
+    fs::remove_dir_all(output_dir).unwrap();
+    fs::remove_file(top_link).unwrap();
+
+    assert!(upload_result.is_ok());
+    assert_eq!(checksum_original, checksum_copied);
+    assert_eq!(xattr.as_deref(), Some(&b"hello"[..]));
+    assert_eq!(top_link_xattr.as_deref(), Some(&b"hello"[..]));
+    assert_eq!(
+        targets,
+        vec![
+            Some(PathBuf::from("a")),
+            Some(PathBuf::from("missing/target")),
+            Some(PathBuf::from("../sub")),
+        ]
+    );
+}
+
+#[test]
+#[serial]
+fn test_streaming_file_through_received_link() {
+    use std::os::unix::fs::symlink;
+
+    let src_dir = "testdir_links";
+    let other_src_dir = "testdir_other/testdir_links";
+    let outside_dir = "testdir_outside";
+    let output_dir = "testdir_output";
+
+    // The first upload creates a link to a directory outside of the
+    // output directory, which the second one tries to write through.
+    fs::create_dir_all(src_dir).unwrap();
+    fs::create_dir_all(outside_dir).unwrap();
+    symlink("../../testdir_outside", format!("{}/sub", src_dir)).unwrap();
+    fs::create_dir_all(format!("{}/sub", other_src_dir)).unwrap();
+    create_test_file(format!("{}/sub/a", other_src_dir), 1024);
+
+    let receiver = Arc::new(
+        FileReceiver::new(SERVER_PORT)
+            .with_output_dir(output_dir)
+            .with_naming(NamingPolicy::original_name()),
+    );
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    let uploader = FileUploader::new("localhost".to_string(), SERVER_PORT, None);
+    let link_result = uploader.upload(src_dir);
+    let file_result = uploader.upload(other_src_dir);
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let outside_files = fs::read_dir(outside_dir).unwrap().count();
+
+    fs::remove_dir_all(src_dir).unwrap();
+    fs::remove_dir_all("testdir_other").unwrap();
+    fs::remove_dir_all(outside_dir).unwrap();
+    fs::remove_dir_all(output_dir).unwrap();
+
+    assert!(link_result.is_ok());
+    assert!(matches!(
+        file_result,
+        Err(file_uploader::Error::Rejected(_))
+    ));
+    assert_eq!(outside_files, 0);
+}
+
+#[test]
+#[serial]
+fn test_streaming_compression() {
+    let src_file_name = "testfile10Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    create_compressible_test_file(src_file_name, megabytes(10));
+
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT));
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    for compression in &Compression::ALL {
+        let uploader = FileUploader::new(
+            "localhost".to_string(),
+            SERVER_PORT,
+            Some(megabytes(1) as u32),
+        )
+        .with_compression(*compression);
+
+        let now = Instant::now();
+        uploader.upload(src_file_name).unwrap();
+        let elapsed_millis = now.elapsed().as_millis();
+
+        // Sending the 10 MB uncompressed would take 10 seconds.
+        assert!(
+            elapsed_millis < 5000,
+            "{} took {} ms",
+            compression,
+            elapsed_millis
+        );
+        assert_eq!(
+            calculate_checksum(src_file_name),
+            calculate_checksum(dst_file_name)
+        );
+        fs::remove_file(dst_file_name).unwrap();
+    }
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    fs::remove_file(src_file_name).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn test_streaming_async_compressed_resuming_upload() {
+    let src_file_name = "testfile6Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    // Data that does not compress between data that does, so that the
+    // uploader stops and starts compressing again.
+    let compressible_file_name = "testfile_compressible";
+    let random_file_name = "testfile_random";
+    create_compressible_test_file(compressible_file_name, megabytes(2));
+    create_test_file(random_file_name, megabytes(2));
+    let mut data = fs::read(compressible_file_name).unwrap();
+    data.extend(fs::read(random_file_name).unwrap());
+    data.extend(fs::read(compressible_file_name).unwrap());
+    fs::write(src_file_name, data).unwrap();
+    fs::remove_file(compressible_file_name).unwrap();
+    fs::remove_file(random_file_name).unwrap();
+
+    let receiver = Arc::new(AsyncFileReceiver::new(SERVER_PORT));
+    let receiver_clone_a = receiver.clone();
+    let receiver_clone_b = receiver.clone();
+
+    let receiver_task = tokio::spawn(async move {
+        receiver_clone_a.start().await.unwrap();
+    });
+
+    let uploader_task = tokio::spawn(async move {
+        let uploader = AsyncFileUploader::new(
+            "localhost".to_string(),
+            SERVER_PORT,
+            Some(megabytes(1) as u32),
+        )
+        .with_compression(Compression::Zstd);
+        uploader.upload(src_file_name).await.unwrap();
+    });
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    receiver.stop_now();
+    receiver_task.await.unwrap();
+
+    let receiver_task = tokio::spawn(async move {
+        receiver_clone_b.start().await.unwrap();
+    });
+
+    uploader_task.await.unwrap();
+
+    receiver.stop();
+    receiver_task.await.unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[test]
+#[serial]
+fn test_streaming_delta_upload() {
+    let src_file_name = "testfile10Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    create_test_file(src_file_name, megabytes(10));
+
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT));
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    let uploader = FileUploader::new("localhost".to_string(), SERVER_PORT, None);
+    uploader.upload(src_file_name).unwrap();
+
+    // Data inserted, removed and changed shifts the blocks that follow.
+    let mut data = fs::read(src_file_name).unwrap();
+    data.splice(megabytes(3)..megabytes(3), vec![b'x'; 1000]);
+    data.drain(megabytes(5)..megabytes(5) + 777);
+    data[megabytes(7)] ^= 0xff;
+    data.extend_from_slice(b"appended");
+    fs::write(src_file_name, data).unwrap();
+
+    let uploader = FileUploader::new(
+        "localhost".to_string(),
+        SERVER_PORT,
+        Some(megabytes(1) as u32),
+    )
+    .with_delta(true);
+
+    let now = Instant::now();
+    uploader.upload(src_file_name).unwrap();
+    let elapsed_millis = now.elapsed().as_millis();
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+
+    // Sending the whole file would take 10 seconds.
+    assert!(elapsed_millis < 5000, "took {} ms", elapsed_millis);
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn test_streaming_async_delta_resuming_upload() {
+    let src_file_name = "testfile6Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+    let random_file_name = "testfile_random";
+
+    create_test_file(src_file_name, megabytes(6));
+    create_test_file(random_file_name, megabytes(3));
+
+    let receiver = Arc::new(AsyncFileReceiver::new(SERVER_PORT));
+    let receiver_clone_a = receiver.clone();
+    let receiver_clone_b = receiver.clone();
+
+    let receiver_task = tokio::spawn(async move {
+        receiver_clone_a.start().await.unwrap();
+    });
+
+    let uploader = AsyncFileUploader::new("localhost".to_string(), SERVER_PORT, None);
+    uploader.upload(src_file_name).await.unwrap();
+
+    // The first half of the file is kept, shifted by a few bytes, and
+    // the second half is replaced by data the receiver lacks.
+    let mut data = b"prefix".to_vec();
+    data.extend_from_slice(&fs::read(src_file_name).unwrap()[..megabytes(3)]);
+    data.extend(fs::read(random_file_name).unwrap());
+    fs::write(src_file_name, data).unwrap();
+    fs::remove_file(random_file_name).unwrap();
+
+    let uploader_task = tokio::spawn(async move {
+        let uploader = AsyncFileUploader::new(
+            "localhost".to_string(),
+            SERVER_PORT,
+            Some(megabytes(1) as u32),
+        )
+        .with_delta(true);
+        uploader.upload(src_file_name).await.unwrap();
+    });
+
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    receiver.stop_now();
+    receiver_task.await.unwrap();
+
+    let receiver_task = tokio::spawn(async move {
+        receiver_clone_b.start().await.unwrap();
+    });
+
+    uploader_task.await.unwrap();
+
+    receiver.stop();
+    receiver_task.await.unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[test]
+#[serial]
+fn test_streaming_dedup_upload() {
+    let src_file_name = "testfile10Mb";
+    let copy_file_name = "testfile10Mb_copy";
+    let store_dir = "testdir_store";
+
+    create_test_file(src_file_name, megabytes(10));
+
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT).with_chunk_store(store_dir));
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    let uploader = FileUploader::new("localhost".to_string(), SERVER_PORT, None);
+    uploader.upload(src_file_name).unwrap();
+
+    // A file under another name that shares most of its data.
+    let mut data = fs::read(src_file_name).unwrap();
+    data.splice(megabytes(3)..megabytes(3), vec![b'x'; 1000]);
+    data[megabytes(7)] ^= 0xff;
+    fs::write(copy_file_name, data).unwrap();
+
+    let uploader = FileUploader::new(
+        "localhost".to_string(),
+        SERVER_PORT,
+        Some(megabytes(1) as u32),
+    )
+    .with_dedup(true);
+
+    let now = Instant::now();
+    uploader.upload(copy_file_name).unwrap();
+    let elapsed_millis = now.elapsed().as_millis();
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let checksum_original = calculate_checksum(copy_file_name);
+    let checksum_copied = calculate_checksum(format!("{}.received", copy_file_name));
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(format!("{}.received", src_file_name)).unwrap();
+    fs::remove_file(copy_file_name).unwrap();
+    fs::remove_file(format!("{}.received", copy_file_name)).unwrap();
+    fs::remove_dir_all(store_dir).unwrap();
+
+    // Sending the whole file would take 10 seconds.
+    assert!(elapsed_millis < 5000, "took {} ms", elapsed_millis);
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn test_streaming_async_dedup_upload() {
+    let dir_name = "testdir_dedup";
+    let store_dir = "testdir_store";
+    let output_dir = "testdir_dedup_output";
+
+    fs::create_dir_all(dir_name).unwrap();
+    create_test_file(format!("{}/a", dir_name), megabytes(3));
+    let mut data = fs::read(format!("{}/a", dir_name)).unwrap();
+    data.splice(..0, b"prefix".iter().copied());
+    fs::write(format!("{}/b", dir_name), data).unwrap();
+
+    let receiver = Arc::new(
+        AsyncFileReceiver::new(SERVER_PORT)
+            .with_chunk_store(store_dir)
+            .with_output_dir(output_dir)
+            .with_naming(NamingPolicy::original_name()),
+    );
+    let receiver_clone = receiver.clone();
+
+    let receiver_task = tokio::spawn(async move {
+        receiver_clone.start().await.unwrap();
+    });
+
+    // The second file is deduplicated against the chunks of the first.
+    let uploader = AsyncFileUploader::new(
+        "localhost".to_string(),
+        SERVER_PORT,
+        Some(megabytes(1) as u32),
+    )
+    .with_dedup(true);
+
+    let now = Instant::now();
+    uploader.upload(dir_name).await.unwrap();
+    let elapsed_millis = now.elapsed().as_millis();
+
+    receiver.stop();
+    receiver_task.await.unwrap();
+
+    for file_name in &["a", "b"] {
+        assert_eq!(
+            calculate_checksum(format!("{}/{}", dir_name, file_name)),
+            calculate_checksum(format!("{}/{}/{}", output_dir, dir_name, file_name))
+        );
+    }
+
+    fs::remove_dir_all(dir_name).unwrap();
+    fs::remove_dir_all(output_dir).unwrap();
+    fs::remove_dir_all(store_dir).unwrap();
+
+    // Sending both files whole would take 6 seconds.
+    assert!(elapsed_millis < 5500, "took {} ms", elapsed_millis);
+}
+
+#[test]
+#[serial]
+fn test_streaming_striped_resuming_upload() {
+    let src_file_name = "testfile12Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+    let part_file_name = &format!(".{}.part", src_file_name);
+
+    create_test_file(src_file_name, megabytes(12));
+
+    let receiver = Arc::new(FileReceiver::new(SERVER_PORT));
+    let receiver_clone_a = receiver.clone();
+    let receiver_clone_b = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone_a.start().unwrap();
+    });
+
+    // Each of the four connections is limited to 1 MB/s.
+    let uploader_thread = thread::spawn(move || {
+        let uploader = FileUploader::new(
+            "localhost".to_string(),
+            SERVER_PORT,
+            Some(megabytes(1) as u32),
+        )
+        .with_streams(4);
+        uploader.upload(src_file_name).unwrap();
+    });
+
+    let now = Instant::now();
+
+    thread::sleep(Duration::from_millis(1500));
+    receiver.stop_now();
+    receiver_thread.join().unwrap();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone_b.start().unwrap();
+    });
+
+    uploader_thread.join().unwrap();
+    let elapsed_millis = now.elapsed().as_millis();
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+
+    // Each range resumes from where it stopped, so the transfer should
+    // take a bit more than 3 seconds to complete.
+    assert!(
+        elapsed_millis > 3000 && elapsed_millis < 6000,
+        "took {} ms",
+        elapsed_millis
+    );
+
+    assert_eq!(checksum_original, checksum_copied);
+    assert!(!Path::new(part_file_name).exists());
+    assert!(!Path::new(&format!("{}.meta", part_file_name)).exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn test_streaming_async_striped_upload() {
+    let src_file_name = "testfile10Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    create_test_file(src_file_name, megabytes(10));
+
+    let receiver = Arc::new(AsyncFileReceiver::new(SERVER_PORT));
+    let receiver_clone = receiver.clone();
+
+    let receiver_task = tokio::spawn(async move {
+        receiver_clone.start().await.unwrap();
+    });
+
+    let uploader = AsyncFileUploader::new(
+        "localhost".to_string(),
+        SERVER_PORT,
+        Some(megabytes(1) as u32),
+    )
+    .with_streams(4);
+
+    let now = Instant::now();
+    uploader.upload(src_file_name).await.unwrap();
+    let elapsed_millis = now.elapsed().as_millis();
+
+    receiver.stop();
+    receiver_task.await.unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+
+    // Sending the file over a single connection would take 10 seconds.
+    assert!(elapsed_millis < 5000, "took {} ms", elapsed_millis);
+
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[test]
+#[serial]
+fn test_streaming_small_window() {
+    let src_file_name = "testfile10Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    create_test_file(src_file_name, megabytes(10));
+
+    // Syncing only every 16 MB would hold back the acknowledgements the
+    // uploader waits for, unless synced within the window.
+    let receiver = Arc::new(
+        FileReceiver::new(SERVER_PORT)
+            .with_durability(Durability::EveryBytes(megabytes(16) as u64)),
+    );
+    let receiver_clone = receiver.clone();
+
+    let receiver_thread = thread::spawn(move || {
+        receiver_clone.start().unwrap();
+    });
+
+    // The corrupted chunk is sent again even though the window is full.
+    let max_bytes_in_flight = start_corrupting_proxy(WINDOW_PROXY_PORT, megabytes(5));
+
+    let window = megabytes(1) as u64 / 2;
+    let uploader_thread = thread::spawn(move || {
+        let uploader = FileUploader::new("localhost".to_string(), WINDOW_PROXY_PORT, None)
+            .with_window(window as u32)
+            .with_ack_interval(window as u32 / 4);
+        uploader.upload(src_file_name).unwrap();
+    });
+
+    uploader_thread.join().unwrap();
+
+    receiver.stop();
+    receiver_thread.join().unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+
+    // Besides the data within the window, the bytes in flight include
+    // the framing of the chunks and the corrupted chunk sent again.
+    let max_bytes_in_flight = max_bytes_in_flight.load(Ordering::SeqCst);
+    assert!(
+        max_bytes_in_flight > window / 2
+            && max_bytes_in_flight <= window + window / 32 + MAX_CHUNK_SIZE as u64,
+        "{} bytes in flight",
+        max_bytes_in_flight
+    );
+
+    assert_eq!(checksum_original, checksum_copied);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn test_streaming_async_small_window_resuming_upload() {
+    let src_file_name = "testfile5Mb";
+    let dst_file_name = &format!("{}.received", src_file_name);
+
+    create_test_file(src_file_name, megabytes(5));
+
+    let receiver = Arc::new(AsyncFileReceiver::new(SERVER_PORT));
+    let receiver_clone_a = receiver.clone();
+    let receiver_clone_b = receiver.clone();
+
+    let receiver_task = tokio::spawn(async move {
+        receiver_clone_a.start().await.unwrap();
+    });
+
+    let uploader_task = tokio::spawn(async move {
+        let uploader = AsyncFileUploader::new(
+            "localhost".to_string(),
+            SERVER_PORT,
+            Some(megabytes(1) as u32),
+        )
+        .with_window(megabytes(1) as u32 / 4)
+        .with_ack_interval(megabytes(1) as u32 / 8);
+        uploader.upload(src_file_name).await.unwrap();
+    });
+
+    let now = Instant::now();
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    receiver.stop_now();
+    receiver_task.await.unwrap();
+
+    let receiver_task = tokio::spawn(async move {
+        receiver_clone_b.start().await.unwrap();
+    });
+
+    uploader_task.await.unwrap();
+    let elapsed_millis = now.elapsed().as_millis();
+
+    receiver.stop();
+    receiver_task.await.unwrap();
+
+    let checksum_original = calculate_checksum(src_file_name);
+    let checksum_copied = calculate_checksum(dst_file_name);
+
+    fs::remove_file(src_file_name).unwrap();
+    fs::remove_file(dst_file_name).unwrap();
+
+    // Little more than the window is sent again after the reconnection.
+    assert!(
+        elapsed_millis > 5000 && elapsed_millis < 7500,
+        "took {} ms",
+        elapsed_millis
+    );
+
+    assert_eq!(checksum_original, checksum_copied);
+}